@@ -0,0 +1,413 @@
+#!/usr/bin/env python3
+"""Writes the FFV1 samples that FFmpeg was not at hand to make.
+
+    python3 data/make_samples.py
+
+The samples are coded from data/ffv1_v3_yuv420p.ref, a 640x360 yuv420p
+frame, by the small FFV1 encoder below. It follows RFC 9043, and FFmpeg
+for what the RFC leaves open, without sharing any code with the crate:
+
+* data/ffv1_v0_yuv420p.mkv and data/ffv1_v1_yuv420p.mkv are the whole
+  frame, coded as versions 0 and 1: one slice, no configuration record and
+  the parameters in the keyframe header.
+* data/ffv1_v3_yuv420p_odd.mkv is the frame cropped to 639x357, coded as
+  version 3 with 3x2 slices. Slices starting on odd columns code the
+  chroma column they share with the previous slice again, as FFmpeg does:
+  their chroma planes start at half their position rounded down, and are
+  half their width rounded up.
+"""
+
+import os
+import struct
+
+DATA = os.path.dirname(os.path.abspath(__file__))
+WIDTH, HEIGHT = 640, 360
+CONTEXT_SIZE = 32
+
+
+def build_states(factor, max_p):
+    """Returns the state transition table of the range coder, built the way
+    FFmpeg builds it. Only the states after a 1 bit are returned, the
+    states after a 0 bit being mirrored from them."""
+    one = 1 << 32
+    one_state = [0] * 256
+
+    last_p8 = 0
+    p = one // 2
+    for _ in range(128):
+        p8 = (256 * p + one // 2) >> 32
+        if p8 <= last_p8:
+            p8 = last_p8 + 1
+        if last_p8 and last_p8 < 256 and p8 <= max_p:
+            one_state[last_p8] = p8
+        p += ((one - p) * factor + one // 2) >> 32
+        last_p8 = p8
+
+    for i in range(256 - max_p, max_p + 1):
+        if one_state[i]:
+            continue
+        p = (i * one + 128) >> 8
+        p += ((one - p) * factor + one // 2) >> 32
+        p8 = (256 * p + one // 2) >> 32
+        if p8 <= i:
+            p8 = i + 1
+        if p8 > max_p:
+            p8 = max_p
+        one_state[i] = p8
+
+    return one_state
+
+
+# 3.8.1.4. State Transition Table
+ONE_STATE = build_states(int(0.05 * (1 << 32)), 256 - 8)
+ZERO_STATE = [0] + [256 - ONE_STATE[256 - i] for i in range(1, 256)]
+
+
+class RangeEncoder:
+    """Binary range coder, see 3.8.1. Range Coding Mode."""
+
+    def __init__(self):
+        self.out = bytearray()
+        self.low = 0
+        self.range = 0xFF00
+        self.outstanding_count = 0
+        self.outstanding_byte = -1
+
+    def renorm(self):
+        while self.range < 0x100:
+            if self.outstanding_byte < 0:
+                self.outstanding_byte = self.low >> 8
+            elif self.low <= 0xFF00:
+                self.out.append(self.outstanding_byte)
+                self.out.extend(b"\xff" * self.outstanding_count)
+                self.outstanding_count = 0
+                self.outstanding_byte = self.low >> 8
+            elif self.low >= 0x10000:
+                self.out.append(self.outstanding_byte + 1)
+                self.out.extend(b"\x00" * self.outstanding_count)
+                self.outstanding_count = 0
+                self.outstanding_byte = (self.low >> 8) - 0x100
+            else:
+                self.outstanding_count += 1
+            self.low = (self.low & 0xFF) << 8
+            self.range <<= 8
+
+    def put_bit(self, states, i, bit):
+        state = states[i]
+        range1 = (self.range * state) >> 8
+        if bit:
+            self.low += self.range - range1
+            self.range = range1
+            states[i] = ONE_STATE[state]
+        else:
+            self.range -= range1
+            states[i] = ZERO_STATE[state]
+        self.renorm()
+
+    def put_symbol(self, states, value, signed=False):
+        """See 3.8.1.2. Range Non Binary Values."""
+        if value == 0:
+            self.put_bit(states, 0, 1)
+            return
+        a = abs(value)
+        e = a.bit_length() - 1
+        self.put_bit(states, 0, 0)
+        for i in range(e):
+            self.put_bit(states, 1 + min(i, 9), 1)
+        self.put_bit(states, 1 + min(e, 9), 0)
+        for i in range(e - 1, -1, -1):
+            self.put_bit(states, 22 + min(i, 9), (a >> i) & 1)
+        if signed:
+            self.put_bit(states, 11 + min(e, 10), int(value < 0))
+
+    def finish(self):
+        self.range = 0xFF
+        self.low += 0xFF
+        self.renorm()
+        self.range = 0xFF
+        self.renorm()
+        return bytes(self.out)
+
+
+def new_states():
+    return [128] * CONTEXT_SIZE
+
+
+def crc32(buf):
+    """CRC-32 with polynomial 0x04C11DB7, most significant bit first and
+    starting from 0, see 4.8.3. slice_crc_parity."""
+    crc = 0
+    for byte in buf:
+        crc ^= byte << 24
+        for _ in range(8):
+            crc = (crc << 1) ^ 0x04C11DB7 if crc & 0x80000000 else crc << 1
+            crc &= 0xFFFFFFFF
+    return crc
+
+
+def with_parity(buf):
+    return buf + struct.pack(">I", crc32(buf))
+
+
+# Quantization of the differences between neighbours: 0, 1 to 2, and 3 or
+# more, as runs of each value over the first 128 differences. The first
+# three neighbour differences are used, the last two are not.
+QUANT_RUNS = [[1, 2, 125]] * 3 + [[128]] * 2
+
+
+def quant_tables():
+    """Returns the quantization tables coded by QUANT_RUNS, and the number
+    of contexts they make. See 4.1.11. QuantizationTable."""
+    tables = []
+    scale = 1
+    for runs in QUANT_RUNS:
+        table = [0] * 256
+        k = 0
+        for v, run in enumerate(runs):
+            for _ in range(run):
+                table[k] = scale * v
+                k += 1
+        for k in range(1, 128):
+            table[256 - k] = -table[k]
+        table[128] = -table[127]
+        tables.append(table)
+        scale *= 2 * len(runs) - 1
+    return tables, (scale + 1) // 2
+
+
+QUANT_TABLES, CONTEXT_COUNT = quant_tables()
+
+
+def put_quant_table_set(coder):
+    for runs in QUANT_RUNS:
+        states = new_states()
+        for run in runs:
+            coder.put_symbol(states, run - 1)
+
+
+def put_parameters(coder, version, num_slices=(1, 1)):
+    """Codes the parameters of an 8 bit yuv420p stream, see 4.1.
+    Parameters."""
+    states = new_states()
+    coder.put_symbol(states, version)
+    if version >= 3:
+        coder.put_symbol(states, 4)  # micro_version
+    coder.put_symbol(states, 1)  # coder_type
+    coder.put_symbol(states, 0)  # colorspace_type
+    if version >= 1:
+        coder.put_symbol(states, 8)  # bits_per_raw_sample
+    coder.put_bit(states, 0, 1)  # chroma_planes
+    coder.put_symbol(states, 1)  # log2_h_chroma_subsample
+    coder.put_symbol(states, 1)  # log2_v_chroma_subsample
+    coder.put_bit(states, 0, 0)  # extra_plane
+    if version >= 2:
+        coder.put_symbol(states, num_slices[0] - 1)
+        coder.put_symbol(states, num_slices[1] - 1)
+        coder.put_symbol(states, 1)  # quant_table_set_count
+    put_quant_table_set(coder)
+    if version >= 2:
+        coder.put_bit(states, 0, 0)  # states_coded
+    if version >= 3:
+        coder.put_symbol(states, 1)  # ec
+        coder.put_symbol(states, 1)  # intra
+
+
+def median(a, b, c):
+    return sorted((a, b, c))[1]
+
+
+def put_plane(coder, states, plane, x0, y0, width, height):
+    """Codes the 'width'x'height' samples of 'plane' from 'x0', 'y0', see
+    4.7. Line."""
+    rows = [plane[y0 + y][x0:x0 + width] for y in range(height)]
+
+    # 3.1. Border: samples above the slice are 0, samples on the left are
+    # those of the first column of the line above, and samples on the
+    # right are those of the last column.
+    def sample(y, x):
+        if y < 0 or x < -1:
+            return 0
+        if x == -1:
+            return sample(y - 1, 0)
+        return rows[y][min(x, width - 1)]
+
+    for y in range(height):
+        for x in range(width):
+            T, L = sample(y - 2, x), sample(y, x - 2)
+            t, l = sample(y - 1, x), sample(y, x - 1)
+            tl, tr = sample(y - 1, x - 1), sample(y - 1, x + 1)
+
+            # 3.4. Context
+            context = (
+                QUANT_TABLES[0][(l - tl) & 0xFF]
+                + QUANT_TABLES[1][(tl - t) & 0xFF]
+                + QUANT_TABLES[2][(t - tr) & 0xFF]
+                + QUANT_TABLES[3][(L - l) & 0xFF]
+                + QUANT_TABLES[4][(T - t) & 0xFF]
+            )
+
+            # 3.3. Median Predictor, and 3.8. Coding of the Sample
+            # Difference, folded into 8 bits.
+            diff = rows[y][x] - median(l, t, l + t - tl)
+            diff = ((diff + 128) & 0xFF) - 128
+            if context < 0:
+                context, diff = -context, -diff
+            coder.put_symbol(states[context], diff, signed=True)
+
+
+def put_slice_content(coder, planes, x, y, width, height):
+    """Codes a slice of a yuv420p frame, see 4.6. Slice Content. Chroma
+    planes of the slice start at half its position rounded down, and are
+    half its size rounded up."""
+    contexts = [
+        [new_states() for _ in range(CONTEXT_COUNT)] for _ in range(2)
+    ]
+    put_plane(coder, contexts[0], planes[0], x, y, width, height)
+    for plane in planes[1:]:
+        put_plane(
+            coder,
+            contexts[1],
+            plane,
+            x >> 1,
+            y >> 1,
+            (width + 1) >> 1,
+            (height + 1) >> 1,
+        )
+
+
+def v0_v1_packet(planes, version):
+    """Codes a version 0 or 1 keyframe: its header and its only slice
+    share the range coder, and there is no slice footer."""
+    coder = RangeEncoder()
+    coder.put_bit(new_states(), 0, 1)  # keyframe
+    put_parameters(coder, version)
+    put_slice_content(coder, planes, 0, 0, len(planes[0][0]), len(planes[0]))
+    return coder.finish()
+
+
+def v3_record(num_slices):
+    coder = RangeEncoder()
+    put_parameters(coder, 3, num_slices)
+    return with_parity(coder.finish())
+
+
+def v3_packet(planes, num_slices):
+    """Codes a version 3 keyframe, with error correction."""
+    width, height = len(planes[0][0]), len(planes[0])
+    num_h, num_v = num_slices
+    packet = b""
+    for sy in range(num_v):
+        for sx in range(num_h):
+            coder = RangeEncoder()
+            if not packet:
+                coder.put_bit(new_states(), 0, 1)  # keyframe
+
+            # 4.5. Slice Header
+            states = new_states()
+            for value in (sx, sy, 0, 0, 0, 0, 3, 0, 0):
+                coder.put_symbol(states, value)
+
+            x, y = width * sx // num_h, height * sy // num_v
+            end_x = width * (sx + 1) // num_h
+            end_y = height * (sy + 1) // num_v
+            put_slice_content(coder, planes, x, y, end_x - x, end_y - y)
+
+            # 4.8. Slice Footer
+            slice = coder.finish()
+            slice += struct.pack(">I", len(slice))[1:] + b"\x00"
+            packet += with_parity(slice)
+    return packet
+
+
+def ebml_size(size):
+    return struct.pack(">Q", size | 1 << 56)
+
+
+def element(id, data):
+    return id + ebml_size(len(data)) + data
+
+
+def uint(id, value):
+    return element(id, struct.pack(">I", value))
+
+
+def mkv(width, height, record, packet):
+    """Wraps a single frame into a minimal Matroska file."""
+    header = element(
+        b"\x1a\x45\xdf\xa3",
+        uint(b"\x42\x86", 1)
+        + uint(b"\x42\xf7", 1)
+        + uint(b"\x42\xf2", 4)
+        + uint(b"\x42\xf3", 8)
+        + element(b"\x42\x82", b"matroska")
+        + uint(b"\x42\x87", 4)
+        + uint(b"\x42\x85", 2),
+    )
+    info = element(
+        b"\x15\x49\xa9\x66",
+        uint(b"\x2a\xd7\xb1", 1000000)
+        + element(b"\x4d\x80", b"make_samples.py")
+        + element(b"\x57\x41", b"make_samples.py"),
+    )
+    track = (
+        uint(b"\xd7", 1)
+        + uint(b"\x73\xc5", 1)
+        + uint(b"\x83", 1)
+        + element(b"\x86", b"V_FFV1")
+        + (element(b"\x63\xa2", record) if record else b"")
+        + element(
+            b"\xe0", uint(b"\xb0", width) + uint(b"\xba", height)
+        )
+    )
+    tracks = element(b"\x16\x54\xae\x6b", element(b"\xae", track))
+    cluster = element(
+        b"\x1f\x43\xb6\x75",
+        uint(b"\xe7", 0) + element(b"\xa3", b"\x81\x00\x00\x80" + packet),
+    )
+    return header + element(b"\x18\x53\x80\x67", info + tracks + cluster)
+
+
+def read_planes():
+    with open(os.path.join(DATA, "ffv1_v3_yuv420p.ref"), "rb") as f:
+        ref = f.read()
+    luma, chroma = ref[: WIDTH * HEIGHT], ref[WIDTH * HEIGHT :]
+    cw, ch = WIDTH // 2, HEIGHT // 2
+    return [
+        [luma[y * WIDTH : (y + 1) * WIDTH] for y in range(HEIGHT)],
+        [chroma[y * cw : (y + 1) * cw] for y in range(ch)],
+        [chroma[(ch + y) * cw : (ch + y + 1) * cw] for y in range(ch)],
+    ]
+
+
+def crop(planes, width, height):
+    """Crops from the top left corner, which keeps the chroma planes of an
+    even sized frame whole."""
+    cw, ch = (width + 1) >> 1, (height + 1) >> 1
+    return [[row[:width] for row in planes[0][:height]]] + [
+        [row[:cw] for row in plane[:ch]] for plane in planes[1:]
+    ]
+
+
+def write(name, data):
+    with open(os.path.join(DATA, name), "wb") as f:
+        f.write(data)
+
+
+def main():
+    planes = read_planes()
+    for version in (0, 1):
+        write(
+            "ffv1_v%d_yuv420p.mkv" % version,
+            mkv(WIDTH, HEIGHT, b"", v0_v1_packet(planes, version)),
+        )
+
+    num_slices = (3, 2)
+    odd = crop(planes, 639, 357)
+    write(
+        "ffv1_v3_yuv420p_odd.mkv",
+        mkv(639, 357, v3_record(num_slices), v3_packet(odd, num_slices)),
+    )
+
+
+if __name__ == "__main__":
+    main()
@@ -3,13 +3,6 @@
 // ffv1 crate
 extern crate ffv1;
 
-// rust-av crates
-extern crate av_data as data;
-extern crate av_format as format;
-
-// Matroska demuxer
-extern crate matroska;
-
 // CLI crates
 extern crate clap;
 
@@ -17,28 +10,18 @@ extern crate clap;
 extern crate byteorder;
 
 use std::fs::File;
-use std::io::{BufWriter, Write};
+use std::io::{BufReader, BufWriter, Write};
 use std::path::Path;
 
-use data::params::MediaKind;
-use format::buffer::AccReader;
-use format::demuxer::{Context, Event};
-
-use matroska::demuxer::MkvDemuxer;
-
 use ffv1::decoder::{Decoder, Frame};
+use ffv1::frame::Samples;
+use ffv1::mkv::MkvReader;
+use ffv1::record::ConfigRecord;
+use ffv1::trace::JsonLines;
 
 use byteorder::{LittleEndian, WriteBytesExt};
 use clap::{App, Arg};
 
-// ffv1 decoder parameters
-#[derive(Default)]
-struct DecParams {
-    width: u32,
-    height: u32,
-    extradata: Vec<u8>,
-}
-
 // Writes a u16 buffer as little endian on a file.
 #[inline(always)]
 fn write_u16_le<W: Write>(
@@ -53,45 +36,40 @@ fn write_u16_le<W: Write>(
 
 // Decodes a single ffv1 frame
 fn decode_single_frame(
-    demuxer: &mut Context,
+    reader: &mut MkvReader<BufReader<File>>,
     decoder: &mut Decoder,
     extradata: &[u8],
 ) -> Result<Frame, String> {
-    // The demuxer reads which event has occurred
-    match demuxer.read_event() {
+    // The reader returns the next packet of the ffv1 track
+    match reader.next_packet() {
         // If a new packet has been found, decode it
-        Ok(event) => match event {
-            Event::NewPacket(pkt) => {
-                println!(
-                    "extradata = {} packet = {} track = {}\n",
-                    extradata.len(),
-                    pkt.data.len(),
-                    pkt.pos.unwrap_or(0)
-                );
-                // Reads a ffv1 frame
-                let frame = decoder.decode_frame(&pkt.data).unwrap();
-                println!(
-                    "Frame decoded at {}x{}\n",
-                    frame.width, frame.height
-                );
-                Ok(frame)
-            }
-            // When the EOF is reached, the decoding process is stopped
-            Event::Eof => {
-                println!("EOF reached.");
-                Err("EOF reached".to_owned())
-            }
-            _ => {
-                // If an unsupported event occurs,
-                // the decoding process is stopped
-                println!("Unsupported event {:?}", event);
-                Err("Unsupported event".to_owned())
-            }
-        },
+        Ok(Some(pkt)) => {
+            println!(
+                "extradata = {} packet = {} timestamp = {}ns\n",
+                extradata.len(),
+                pkt.data.len(),
+                pkt.timestamp
+            );
+            // Reads a ffv1 frame
+            let frame = decoder.decode_frame(&pkt.data).unwrap();
+            println!(
+                "Frame decoded at {}x{} in {} ({:?})\n",
+                frame.width(),
+                frame.height(),
+                frame.pixel_format(),
+                frame.picture_structure()
+            );
+            Ok(frame)
+        }
+        // When the EOF is reached, the decoding process is stopped
+        Ok(None) => {
+            println!("EOF reached.");
+            Err("EOF reached".to_owned())
+        }
         Err(err) => {
-            // If there are no more events, the decoding process is stopped
-            println!("No more events {:?}", err);
-            Err("No more events".to_owned())
+            // If the file cannot be read, the decoding process is stopped
+            println!("Cannot read a packet {:?}", err);
+            Err("Cannot read a packet".to_owned())
         }
     }
 }
@@ -116,6 +94,22 @@ fn main() -> std::io::Result<()> {
                 .takes_value(true)
                 .required(true),
         )
+        .arg(
+            Arg::with_name("threads")
+                .help("Number of slice decoding threads (0 for one per CPU)")
+                .short("t")
+                .long("threads")
+                .takes_value(true)
+                .default_value("0"),
+        )
+        .arg(
+            Arg::with_name("trace-path")
+                .help(
+                    "File to trace the syntax elements read to, as JSON lines",
+                )
+                .long("trace")
+                .takes_value(true),
+        )
         .get_matches();
 
     // Get the path to the matroska file
@@ -130,66 +124,60 @@ fn main() -> std::io::Result<()> {
         .map(|s| Path::new(s))
         .unwrap();
 
-    // Open the matroska file
-    let reader = File::open(input_path).unwrap();
-
-    // Create a buffer of size 4096MB to contain matroska data
-    let ar = AccReader::with_capacity(4 * 1024, reader);
-
-    // Set the type of demuxer, in this case, a matroska demuxer
-    let mut demuxer = Context::new(Box::new(MkvDemuxer::new()), Box::new(ar));
-
-    // Read matroska headers
-    demuxer
-        .read_headers()
-        .expect("Cannot parse the format headers");
-
-    // Save decoder params for ffv1 decoder
-    let mut decoder_params: DecParams = Default::default();
-
-    // Iterate over the streams contained in a matroska file
-    for stream in &demuxer.info.streams {
-        // Considers only video streams and analyze the type of codec inside.
-        if let Some(MediaKind::Video(info)) = &stream.params.kind {
-            let extradata =
-                stream.params.extradata.as_ref().unwrap_or_else(|| {
-                    eprintln!("No extradata detected. Aborting");
-                    std::process::exit(1);
-                });
-            if String::from_utf8_lossy(&extradata).contains("FFV1") {
-                decoder_params.width = info.width as u32;
-                decoder_params.height = info.height as u32;
-                // As per Matroska spec for VFW CodecPrivate
-                decoder_params.extradata = extradata[40..].to_owned();
-            }
-        }
-    }
+    // Get the number of slice decoding threads
+    let threads = matches
+        .value_of("threads")
+        .map(|s| s.parse::<usize>().expect("Invalid number of threads"))
+        .unwrap();
+
+    // Open the matroska file and read its headers
+    let reader = BufReader::new(File::open(input_path).unwrap());
+    let mut reader =
+        MkvReader::new(reader).expect("Cannot parse the format headers");
+
+    // The first ffv1 track is decoded
+    let track = reader.tracks()[0].clone();
 
     // Create a new ffv1 decoder
-    let mut ffv1_decoder = Decoder::new(
-        &decoder_params.extradata,
-        decoder_params.width,
-        decoder_params.height,
-    )
-    .unwrap();
+    let mut ffv1_decoder =
+        Decoder::new(&track.config_record, track.width, track.height).unwrap();
+    ffv1_decoder.set_threads(threads).unwrap();
+
+    // Trace the configuration record, then the frames
+    if let Some(trace_path) = matches.value_of("trace-path") {
+        let mut trace =
+            JsonLines::new(BufWriter::new(File::create(trace_path)?));
+        if !track.config_record.is_empty() {
+            ConfigRecord::parse_config_record_with_trace(
+                &track.config_record,
+                &mut trace,
+            )
+            .unwrap();
+        }
+        let writer = trace.finish()?;
+        ffv1_decoder.set_trace(Some(Box::new(JsonLines::new(writer))));
+    }
 
     // Open raw file
     let mut output_file = BufWriter::new(File::create(output_path).unwrap());
 
     // Iterate over the decoded frames
     while let Ok(frame) = decode_single_frame(
-        &mut demuxer,
+        &mut reader,
         &mut ffv1_decoder,
-        &decoder_params.extradata,
+        &track.config_record,
     ) {
-        if frame.bit_depth == 8 {
-            output_file.write_all(&frame.buf[0])?;
-            output_file.write_all(&frame.buf[1])?;
-            output_file.write_all(&frame.buf[2])?;
-        } else {
-            write_u16_le(&mut output_file, &frame.buf16[0])?;
-            write_u16_le(&mut output_file, &frame.buf16[1])?;
-            write_u16_le(&mut output_file, &frame.buf16[2])?;
+        match frame.samples() {
+            Samples::U8(planes) => {
+                for plane in planes {
+                    output_file.write_all(plane)?;
+                }
+            }
+            Samples::U16(planes) => {
+                for plane in planes {
+                    write_u16_le(&mut output_file, plane)?;
+                }
+            }
         }
     }
     println!("Done.");
@@ -0,0 +1,8 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use ffv1::record::ConfigRecord;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = ConfigRecord::parse_config_record(data);
+});
@@ -0,0 +1,58 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use ffv1::decoder::Decoder;
+
+// Frames larger than this only make the fuzzer slower.
+const MAX_PIXELS: u32 = 1 << 20;
+
+// The input is laid out as:
+//
+//   * width, 2 bytes little endian
+//   * height, 2 bytes little endian
+//   * configuration record length, 2 bytes little endian
+//   * configuration record, empty for FFV1 versions 0 and 1
+//   * packets, each preceded by its length as 4 bytes little endian,
+//     with the last packet taking whatever is left
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 6 {
+        return;
+    }
+
+    let width = u16::from_le_bytes([data[0], data[1]]) as u32;
+    let height = u16::from_le_bytes([data[2], data[3]]) as u32;
+    if width * height > MAX_PIXELS {
+        return;
+    }
+
+    let record_len = u16::from_le_bytes([data[4], data[5]]) as usize;
+    let data = &data[6..];
+    if data.len() < record_len {
+        return;
+    }
+    let (record, mut packets) = data.split_at(record_len);
+
+    let mut decoder = match Decoder::new(record, width, height) {
+        Ok(decoder) => decoder,
+        Err(_) => return,
+    };
+
+    while !packets.is_empty() {
+        let packet = if packets.len() > 4 {
+            let len = u32::from_le_bytes([
+                packets[0], packets[1], packets[2], packets[3],
+            ]) as usize;
+            if len <= packets.len() - 4 {
+                let packet = &packets[4..4 + len];
+                packets = &packets[4 + len..];
+                packet
+            } else {
+                std::mem::take(&mut packets)
+            }
+        } else {
+            std::mem::take(&mut packets)
+        };
+
+        let _ = decoder.decode_frame(packet);
+    }
+});
@@ -0,0 +1,32 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use ffv1::golombcoder::golomb::{Coder, State};
+
+// The first byte picks the bit depth, the second the line width, the
+// rest is Golomb-Rice coded data.
+fuzz_target!(|data: &[u8]| {
+    if data.len() < 2 {
+        return;
+    }
+
+    let bits = 8 + (data[0] % 10) as usize;
+    let width = data[1] as u32 + 1;
+    let mut coder = Coder::new(&data[2..]);
+    let mut states = vec![State::new(); 3];
+
+    coder.new_plane(width);
+    loop {
+        coder.new_line();
+        for x in 0..width {
+            // Context 0 switches the coder to run mode.
+            let context = (x % 3) as usize;
+            if coder
+                .sg(context as i32, &mut states[context], bits)
+                .is_err()
+            {
+                return;
+            }
+        }
+    }
+});
@@ -0,0 +1,42 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use ffv1::rangecoder::range::RangeCoder;
+
+// The first byte picks the sequence of symbols to read, the rest is
+// range coded data.
+fuzz_target!(|data: &[u8]| {
+    if data.is_empty() {
+        return;
+    }
+
+    let ops = data[0];
+    let mut coder = match RangeCoder::new(&data[1..]) {
+        Ok(coder) => coder,
+        Err(_) => return,
+    };
+
+    let mut state = [128u8; 32];
+    // Reading past the end of the data only yields zeros, so stop after
+    // about as many symbols as there are bits.
+    for i in 0..data.len() * 8 {
+        let result = match (ops >> (2 * (i % 4))) & 3 {
+            0 => coder.ur(&mut state).map(|_| ()),
+            1 => coder.sr(&mut state).map(|_| ()),
+            2 => {
+                coder.br(&mut state);
+                Ok(())
+            }
+            _ => {
+                let mut bit_state = [128u8];
+                coder.br(&mut bit_state);
+                Ok(())
+            }
+        };
+        if result.is_err() {
+            return;
+        }
+    }
+
+    coder.sentinal_end();
+});
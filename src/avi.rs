@@ -0,0 +1,496 @@
+//! Reads FFV1 video streams from AVI files, including OpenDML ones larger
+//! than 1 GiB.
+//!
+//! Frames are located through the OpenDML index of the stream if there is
+//! one, through the 'idx1' index otherwise, and by walking the 'movi' list
+//! as a last resort.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::error::{Error, Result};
+use crate::vfw::BitmapInfoHeader;
+
+// AVIIF_KEYFRAME, in the flags of 'idx1' entries
+const IDX1_KEYFRAME: u32 = 0x10;
+
+// Set in the size of the entries of OpenDML standard indexes for frames
+// which are not keyframes
+const IX_NOT_KEYFRAME: u32 = 0x8000_0000;
+
+// bIndexType of OpenDML indexes
+const AVI_INDEX_OF_INDEXES: u8 = 0x00;
+const AVI_INDEX_OF_CHUNKS: u8 = 0x01;
+
+/// Stream is the FFV1 video stream of an AVI file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stream {
+    /// Number of the stream, which its chunk IDs start with.
+    pub number: u32,
+    /// Frame rate of the stream, as 'rate' frames every 'scale' seconds.
+    pub rate: u32,
+    /// See rate.
+    pub scale: u32,
+    /// The 'strf' chunk of the stream: a BITMAPINFOHEADER followed by the
+    /// configuration record, to create the Decoder with Decoder::from_vfw.
+    pub codec_private: Vec<u8>,
+    /// The parsed 'strf' chunk.
+    pub header: BitmapInfoHeader,
+}
+
+/// Packet holds a frame of the FFV1 stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    /// Number of the frame, from the start of the stream.
+    pub frame: u64,
+    /// Presentation timestamp of the frame, in nanoseconds.
+    pub timestamp: i64,
+    /// Whether the index marks the frame as a keyframe. Frames found
+    /// without an index all are.
+    pub keyframe: bool,
+    /// The coded frame.
+    pub data: Vec<u8>,
+}
+
+// Location of a frame in the file
+#[derive(Clone, Copy, Debug)]
+struct IndexEntry {
+    offset: u64,
+    size: u32,
+    keyframe: bool,
+}
+
+/// AviReader reads the FFV1 stream of an AVI file, and the packets of its
+/// frames.
+///
+/// Only the first FFV1 stream of a file is read. Frames of no size, which
+/// repeat the previous frame, are skipped.
+pub struct AviReader<R: Read + Seek> {
+    reader: R,
+    stream: Stream,
+    index: Vec<IndexEntry>,
+    next_frame: usize,
+}
+
+impl<R: Read + Seek> AviReader<R> {
+    /// Reads the headers and the index of a file.
+    ///
+    /// Fails if the file has no FFV1 stream.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let (id, _) = match read_chunk_header(&mut reader)? {
+            Some(header) => header,
+            None => {
+                return Err(Error::InvalidInputData("empty file".to_owned()))
+            }
+        };
+        if &id != b"RIFF" || &read_fourcc(&mut reader)? != b"AVI " {
+            return Err(Error::InvalidInputData("not an AVI file".to_owned()));
+        }
+        let mut stream = None;
+        let mut super_index = Vec::new();
+        let mut movi = None;
+        let mut idx1 = None;
+
+        // Writers do not always get the size of the RIFF chunk right, so
+        // chunks are read up to the end of the file.
+        while let Some((id, size)) = read_chunk_header(&mut reader)? {
+            let start = reader.stream_position().map_err(read_error)?;
+            match &id {
+                b"LIST" => match &read_fourcc(&mut reader)? {
+                    b"hdrl" => {
+                        let hdrl =
+                            read_payload(&mut reader, size.saturating_sub(4))?;
+                        let (found, indx) = parse_hdrl(&hdrl)?;
+                        stream = found;
+                        super_index = indx;
+                    }
+                    b"movi" if movi.is_none() => {
+                        movi = Some((start, start + size as u64))
+                    }
+                    _ => {}
+                },
+                b"idx1" if idx1.is_none() => {
+                    idx1 = Some(read_payload(&mut reader, size)?)
+                }
+                // The first RIFF chunk may be followed by AVIX ones, whose
+                // frames only OpenDML indexes point to.
+                b"RIFF" => break,
+                _ => {}
+            }
+            seek(&mut reader, start + pad(size))?;
+        }
+
+        let stream = match stream {
+            Some(stream) => stream,
+            None => {
+                return Err(Error::InvalidInputData(
+                    "no FFV1 stream".to_owned(),
+                ))
+            }
+        };
+
+        let mut avi = AviReader {
+            reader,
+            stream,
+            index: Vec::new(),
+            next_frame: 0,
+        };
+        if !super_index.is_empty() {
+            avi.read_odml_index(&super_index)?;
+        } else if let (Some(idx1), Some(movi)) = (&idx1, movi) {
+            avi.read_idx1(idx1, movi.0)?;
+        } else if let Some(movi) = movi {
+            avi.scan_movi(movi)?;
+        }
+
+        Ok(avi)
+    }
+
+    /// Returns the FFV1 stream of the file.
+    pub fn stream(&self) -> &Stream {
+        &self.stream
+    }
+
+    /// Returns the number of frames of the stream, including the frames
+    /// of no size.
+    pub fn frame_count(&self) -> usize {
+        self.index.len()
+    }
+
+    /// Reads the next packet of the FFV1 stream, or None after the last
+    /// one.
+    pub fn next_packet(&mut self) -> Result<Option<Packet>> {
+        while let Some(&entry) = self.index.get(self.next_frame) {
+            let frame = self.next_frame as u64;
+            self.next_frame += 1;
+            if entry.size == 0 {
+                continue;
+            }
+
+            seek(&mut self.reader, entry.offset)?;
+            let data = read_payload(&mut self.reader, entry.size)?;
+
+            return Ok(Some(Packet {
+                frame,
+                timestamp: self.timestamp(frame),
+                keyframe: entry.keyframe,
+                data,
+            }));
+        }
+
+        Ok(None)
+    }
+
+    // Converts a frame number to nanoseconds
+    fn timestamp(&self, frame: u64) -> i64 {
+        if self.stream.rate == 0 {
+            return 0;
+        }
+        let ns = frame as u128 * self.stream.scale as u128 * 1_000_000_000
+            / self.stream.rate as u128;
+        ns.min(i64::MAX as u128) as i64
+    }
+
+    // Whether a chunk ID is the one of a frame of the FFV1 stream, that is
+    // "##dc", or "##db" as some writers use
+    fn is_frame_chunk(&self, id: &[u8; 4]) -> bool {
+        let number = format!("{:02}", self.stream.number);
+        &id[..2] == number.as_bytes()
+            && (&id[2..] == b"dc" || &id[2..] == b"db")
+    }
+
+    /// Reads the OpenDML standard indexes the super index of the stream
+    /// points to.
+    ///
+    /// See: OpenDML AVI File Format Extensions, AVI Standard Index Chunk
+    fn read_odml_index(&mut self, super_index: &[u64]) -> Result<()> {
+        for &offset in super_index {
+            seek(&mut self.reader, offset)?;
+            let (_, chunk_size) = match read_chunk_header(&mut self.reader)? {
+                Some(header) => header,
+                None => {
+                    return Err(Error::InvalidInputData(
+                        "truncated OpenDML index".to_owned(),
+                    ))
+                }
+            };
+            let ix = read_payload(&mut self.reader, chunk_size)?;
+            if ix.len() < 24 {
+                return Err(Error::InvalidInputData(
+                    "truncated OpenDML index".to_owned(),
+                ));
+            }
+
+            let longs_per_entry = read_u16(&ix, 0) as usize;
+            let index_type = ix[3];
+            let entries = read_u32(&ix, 4) as usize;
+            let base_offset = read_u64(&ix, 12);
+            if index_type != AVI_INDEX_OF_CHUNKS || longs_per_entry < 2 {
+                return Err(Error::InvalidInputData(format!(
+                    "unsupported OpenDML index type {}",
+                    index_type
+                )));
+            }
+
+            for entry in
+                ix[24..].chunks_exact(longs_per_entry * 4).take(entries)
+            {
+                let offset = read_u32(entry, 0);
+                let size = read_u32(entry, 4);
+                self.index.push(IndexEntry {
+                    offset: base_offset.saturating_add(offset as u64),
+                    size: size & !IX_NOT_KEYFRAME,
+                    keyframe: size & IX_NOT_KEYFRAME == 0,
+                });
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reads the 'idx1' index, whose offsets are from the 'movi' fourcc
+    /// in most files, and from the start of the file in some.
+    fn read_idx1(&mut self, idx1: &[u8], movi_start: u64) -> Result<()> {
+        let entries: Vec<_> = idx1
+            .chunks_exact(16)
+            .filter(|entry| self.is_frame_chunk(&fourcc(entry)))
+            .collect();
+
+        // The chunks the first and last entries point to tell which
+        // offsets these are: a single one may match by chance.
+        let mut base = movi_start;
+        for entry in [entries.first(), entries.last()].iter().flatten() {
+            let offset = read_u32(entry, 8) as u64;
+            seek(&mut self.reader, movi_start + offset)?;
+            let header = read_chunk_header(&mut self.reader).ok().flatten();
+            if header.map(|(id, _)| id) != Some(fourcc(entry)) {
+                base = 0;
+            }
+        }
+
+        for entry in entries {
+            let flags = read_u32(entry, 4);
+            let offset = read_u32(entry, 8) as u64;
+            self.index.push(IndexEntry {
+                // Past the chunk header
+                offset: base + offset + 8,
+                size: read_u32(entry, 12),
+                keyframe: flags & IDX1_KEYFRAME != 0,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Walks the 'movi' list for frame chunks, for files without index.
+    fn scan_movi(&mut self, (start, end): (u64, u64)) -> Result<()> {
+        // Past the 'movi' fourcc
+        let mut pos = start + 4;
+        while pos + 8 <= end {
+            seek(&mut self.reader, pos)?;
+            let (id, size) = match read_chunk_header(&mut self.reader)? {
+                Some(header) => header,
+                None => break,
+            };
+            if &id == b"LIST" {
+                // Frames may be grouped in 'rec ' lists.
+                pos += 12;
+                continue;
+            }
+            if self.is_frame_chunk(&id) {
+                self.index.push(IndexEntry {
+                    offset: pos + 8,
+                    size,
+                    keyframe: true,
+                });
+            }
+            pos += 8 + pad(size);
+        }
+
+        Ok(())
+    }
+}
+
+impl<R: Read + Seek> Iterator for AviReader<R> {
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
+
+/// Parses the 'hdrl' list, and returns the first FFV1 stream and the
+/// entries of its OpenDML super index, as offsets of standard indexes.
+fn parse_hdrl(hdrl: &[u8]) -> Result<(Option<Stream>, Vec<u64>)> {
+    let mut number = 0;
+    for (id, strl) in Chunks(hdrl) {
+        if &id != b"LIST" || strl.len() < 4 || &strl[..4] != b"strl" {
+            continue;
+        }
+
+        let mut strh = None;
+        let mut strf = None;
+        let mut indx = None;
+        for (id, payload) in Chunks(&strl[4..]) {
+            match &id {
+                b"strh" => strh = Some(payload),
+                b"strf" => strf = Some(payload),
+                b"indx" => indx = Some(payload),
+                _ => {}
+            }
+        }
+
+        // fccType, then fccHandler, dwFlags, wPriority, wLanguage,
+        // dwInitialFrames, dwScale and dwRate
+        let (strh, strf) = match (strh, strf) {
+            (Some(strh), Some(strf))
+                if strh.len() >= 28
+                    && &strh[..4] == b"vids"
+                    && strf.get(16..20) == Some(b"FFV1") =>
+            {
+                (strh, strf)
+            }
+            _ => {
+                number += 1;
+                continue;
+            }
+        };
+
+        let stream = Stream {
+            number,
+            scale: read_u32(strh, 20),
+            rate: read_u32(strh, 24),
+            codec_private: strf.to_vec(),
+            header: BitmapInfoHeader::parse_bitmap_info_header(strf)?,
+        };
+        let super_index = match indx {
+            Some(indx) => parse_super_index(indx)?,
+            None => Vec::new(),
+        };
+
+        return Ok((Some(stream), super_index));
+    }
+
+    Ok((None, Vec::new()))
+}
+
+/// Parses an OpenDML super index, an 'indx' chunk.
+///
+/// See: OpenDML AVI File Format Extensions, AVI Super Index Chunk
+fn parse_super_index(indx: &[u8]) -> Result<Vec<u64>> {
+    if indx.len() < 24 {
+        return Err(Error::InvalidInputData(
+            "truncated OpenDML super index".to_owned(),
+        ));
+    }
+
+    let longs_per_entry = read_u16(indx, 0) as usize;
+    let index_type = indx[3];
+    let entries = read_u32(indx, 4) as usize;
+    if index_type != AVI_INDEX_OF_INDEXES || longs_per_entry < 4 {
+        return Err(Error::InvalidInputData(format!(
+            "unsupported OpenDML super index type {}",
+            index_type
+        )));
+    }
+
+    Ok(indx[24..]
+        .chunks_exact(longs_per_entry * 4)
+        .take(entries)
+        .map(|entry| read_u64(entry, 0))
+        .collect())
+}
+
+/// Iterates over the chunks of the payload of a list, as their IDs and
+/// payloads. Truncated chunks end the iteration.
+struct Chunks<'a>(&'a [u8]);
+
+impl<'a> Iterator for Chunks<'a> {
+    type Item = ([u8; 4], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.len() < 8 {
+            return None;
+        }
+        let id = fourcc(self.0);
+        let size = read_u32(self.0, 4) as usize;
+        let payload = self.0[8..].get(..size)?;
+        self.0 = self.0.get(8 + pad(size as u32) as usize..).unwrap_or(&[]);
+
+        Some((id, payload))
+    }
+}
+
+// Chunks are padded to an even size
+fn pad(size: u32) -> u64 {
+    size as u64 + (size as u64 & 1)
+}
+
+fn fourcc(buf: &[u8]) -> [u8; 4] {
+    [buf[0], buf[1], buf[2], buf[3]]
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([buf[pos], buf[pos + 1]])
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes(fourcc(&buf[pos..]))
+}
+
+fn read_u64(buf: &[u8], pos: usize) -> u64 {
+    read_u32(buf, pos) as u64 | (read_u32(buf, pos + 4) as u64) << 32
+}
+
+/// Reads the ID and the size of a chunk, or None at the end of the file.
+fn read_chunk_header<R: Read>(
+    reader: &mut R,
+) -> Result<Option<([u8; 4], u32)>> {
+    let mut header = [0; 8];
+    let mut read = 0;
+    while read < header.len() {
+        match reader.read(&mut header[read..]) {
+            Ok(0) if read == 0 => return Ok(None),
+            Ok(0) => {
+                return Err(Error::InvalidInputData(
+                    "truncated chunk header".to_owned(),
+                ))
+            }
+            Ok(n) => read += n,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(read_error(err)),
+        }
+    }
+
+    Ok(Some((fourcc(&header), read_u32(&header, 4))))
+}
+
+fn read_fourcc<R: Read>(reader: &mut R) -> Result<[u8; 4]> {
+    let mut fourcc = [0; 4];
+    reader.read_exact(&mut fourcc).map_err(read_error)?;
+    Ok(fourcc)
+}
+
+/// Reads the payload of a chunk.
+fn read_payload<R: Read>(reader: &mut R, size: u32) -> Result<Vec<u8>> {
+    // The payload is not allocated all at once, since its size is not to
+    // be trusted.
+    let mut payload = Vec::new();
+    let read = reader
+        .take(size as u64)
+        .read_to_end(&mut payload)
+        .map_err(read_error)?;
+    if read < size as usize {
+        return Err(Error::InvalidInputData("truncated chunk".to_owned()));
+    }
+
+    Ok(payload)
+}
+
+fn seek<R: Seek>(reader: &mut R, pos: u64) -> Result<()> {
+    reader.seek(SeekFrom::Start(pos)).map_err(read_error)?;
+    Ok(())
+}
+
+fn read_error(err: io::Error) -> Error {
+    Error::InvalidInputData(format!("cannot read AVI data: {}", err))
+}
@@ -0,0 +1,531 @@
+use std::fmt;
+
+use crate::constants::CONTEXT_SIZE;
+use crate::crc32mpeg2::crc32_mpeg2;
+use crate::error::Result;
+use crate::range::RangeCoder;
+use crate::rangecoder::tables::DEFAULT_STATE_TRANSITION;
+use crate::record::ConfigRecord;
+use crate::slice::{
+    count_slices, locate_slices, InternalFrame, InvalidElement, SliceHeader,
+};
+pub use crate::trace::Location;
+use crate::trace::Tracer;
+
+/// Violation is a departure from the FFV1 specification.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Violation {
+    /// Section of the specification which is violated, e.g.
+    /// "4.5.6. quant_table_set_index".
+    pub section: &'static str,
+    /// Where the violation was found.
+    pub location: Location,
+    /// What is wrong.
+    pub message: String,
+}
+
+impl From<InvalidElement> for Violation {
+    fn from(invalid: InvalidElement) -> Self {
+        Violation {
+            section: invalid.section,
+            location: invalid.location,
+            message: invalid.message,
+        }
+    }
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.location, self.section, self.message)
+    }
+}
+
+/// Checker checks a stream against the FFV1 specification, and reports
+/// every violation it finds, rather than stopping at the first one like
+/// the Decoder does.
+///
+/// Only the configuration record, the frame headers, the slice headers
+/// and the slice footers are checked: slice contents are not decoded.
+pub struct Checker {
+    record: Option<ConfigRecord>,
+    // Whether the parameters come from a configuration record, rather
+    // than from the header of each keyframe as in versions 0 and 1.
+    record_present: bool,
+    state_transition: [u8; 256],
+    width: u32,
+    height: u32,
+    frame_count: usize,
+    keyframe_seen: bool,
+    // Slice layout of the last keyframe, for versions older than 3: the
+    // number of slices, and the headers of the ones within the grid, with
+    // their index.
+    slice_count: usize,
+    slice_headers: Vec<(usize, SliceHeader)>,
+    current_frame: InternalFrame,
+    violations: Vec<Violation>,
+}
+
+impl Checker {
+    /// Creates a checker for a stream, from its configuration record and
+    /// frame dimensions, and checks the record.
+    ///
+    /// If the record cannot be parsed, this is reported, and frames are
+    /// not checked any further.
+    ///
+    /// FFV1 versions 0 and 1 have no configuration record, and code their
+    /// parameters in each keyframe instead, which are checked with the
+    /// keyframes. For those, 'record' must be empty.
+    pub fn new(record: &[u8], width: u32, height: u32) -> Self {
+        let mut checker = Checker {
+            record: None,
+            record_present: !record.is_empty(),
+            state_transition: [0; 256],
+            width,
+            height,
+            frame_count: 0,
+            keyframe_seen: false,
+            slice_count: 0,
+            slice_headers: Vec::new(),
+            current_frame: InternalFrame::default(),
+            violations: Vec::new(),
+        };
+
+        if !checker.record_present {
+            return checker;
+        }
+        match ConfigRecord::parse_config_record(record) {
+            Ok(record) => {
+                checker.check_record(&record, Location::ConfigRecord);
+                checker.record = Some(record);
+            }
+            Err(err) => checker.report(
+                "4.2. Configuration Record",
+                Location::ConfigRecord,
+                err.to_string(),
+            ),
+        }
+
+        checker
+    }
+
+    /// Returns every violation found so far.
+    pub fn violations(&self) -> &[Violation] {
+        &self.violations
+    }
+
+    /// Returns whether or not no violation was found so far.
+    pub fn is_conforming(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    /// Checks the next frame of the stream.
+    ///
+    /// Returns the violations found in this frame.
+    pub fn check_frame(&mut self, frame_input: &[u8]) -> &[Violation] {
+        let first_violation = self.violations.len();
+        let frame = self.frame_count;
+        self.frame_count += 1;
+
+        if self.record.is_some() || !self.record_present {
+            self.check_frame_header(frame_input, frame);
+        }
+
+        &self.violations[first_violation..]
+    }
+
+    fn report(
+        &mut self,
+        section: &'static str,
+        location: Location,
+        message: String,
+    ) {
+        self.violations.push(Violation {
+            section,
+            location,
+            message,
+        });
+    }
+
+    /// Checks the parameters the parser accepts without looking at them,
+    /// which were read at 'location'.
+    ///
+    /// See: 4.1. Parameters
+    fn check_record(&mut self, record: &ConfigRecord, location: Location) {
+        // 4.1.4. state_transition_delta
+        for (i, default_state_transition) in
+            DEFAULT_STATE_TRANSITION.iter().enumerate().skip(1)
+        {
+            let state = *default_state_transition as i32
+                + record.state_transition_delta[i] as i32;
+            if !(0..=255).contains(&state) {
+                self.report(
+                    "4.1.4. state_transition_delta",
+                    location,
+                    format!("state {} transitions to {}", i, state),
+                );
+            }
+            self.state_transition[i] = state as u8;
+        }
+
+        // An empty column or row of slices cannot be coded.
+        //
+        // See: * 4.1.11. num_h_slices
+        //      * 4.1.12. num_v_slices
+        let num_h_slices = record.num_h_slices_minus1 as u32 + 1;
+        if num_h_slices > self.width {
+            self.report(
+                "4.1.11. num_h_slices",
+                location,
+                format!(
+                    "{} slice columns for a width of {}",
+                    num_h_slices, self.width
+                ),
+            );
+        }
+        let num_v_slices = record.num_v_slices_minus1 as u32 + 1;
+        if num_v_slices > self.height {
+            self.report(
+                "4.1.12. num_v_slices",
+                location,
+                format!(
+                    "{} slice rows for a height of {}",
+                    num_v_slices, self.height
+                ),
+            );
+        }
+
+        // 4.1.16. ec
+        if record.ec > 1 {
+            self.report(
+                "4.1.16. ec",
+                location,
+                format!("reserved ec value: {}", record.ec),
+            );
+        }
+
+        // 4.1.17. intra
+        if record.intra > 1 {
+            self.report(
+                "4.1.17. intra",
+                location,
+                format!("reserved intra value: {}", record.intra),
+            );
+        }
+    }
+
+    /// Checks the header of a frame, then its slices.
+    ///
+    /// See: 4.3. Frame
+    fn check_frame_header(&mut self, frame_input: &[u8], frame: usize) {
+        let location = Location::Frame(frame);
+        let mut header_coder = match RangeCoder::new(frame_input) {
+            Ok(coder) => coder,
+            Err(err) => {
+                return self.report("4.3. Frame", location, err.to_string())
+            }
+        };
+
+        let mut state: [u8; CONTEXT_SIZE as usize] =
+            [128; CONTEXT_SIZE as usize];
+        let keyframe = header_coder.br(&mut state);
+        if !keyframe && !self.keyframe_seen {
+            self.report(
+                "4.3. Frame",
+                location,
+                "the first frame is not a keyframe".to_owned(),
+            );
+        }
+        self.keyframe_seen |= keyframe;
+
+        // 4.1. Parameters
+        if keyframe && !self.record_present {
+            match ConfigRecord::parse_keyframe_header(&mut header_coder) {
+                Ok(record) => {
+                    self.check_record(&record, location);
+                    self.record = Some(record);
+                }
+                Err(err) => {
+                    // Frames up to the next keyframe cannot be checked.
+                    self.record = None;
+                    return self.report(
+                        "4.1. Parameters",
+                        location,
+                        err.to_string(),
+                    );
+                }
+            }
+        }
+
+        let (version, intra) = match self.record {
+            Some(ref record) => (record.version, record.intra),
+            None => return,
+        };
+        if !keyframe && intra == 1 {
+            self.report(
+                "4.1.17. intra",
+                location,
+                "inter frame in an intra-only stream".to_owned(),
+            );
+        }
+        // Versions 0 and 1 have a single slice, and neither a slice layout
+        // nor slice footers.
+        if version < 2 {
+            return;
+        }
+        if version < 3 {
+            self.check_slice_layout(frame_input, header_coder, keyframe, frame)
+        } else {
+            self.check_slices(frame_input, header_coder, frame)
+        }
+    }
+
+    /// Checks the slice layout coded in the header of keyframes by
+    /// version 2, and the footers of its slices.
+    fn check_slice_layout(
+        &mut self,
+        frame_input: &[u8],
+        mut header_coder: RangeCoder,
+        keyframe: bool,
+        frame: usize,
+    ) {
+        let location = Location::Frame(frame);
+
+        if keyframe {
+            self.slice_count = 0;
+            self.slice_headers.clear();
+            if let Err(err) = self.read_slice_layout(&mut header_coder, frame)
+            {
+                self.report("4.3. Frame", location, err.to_string());
+            }
+            let headers = std::mem::take(&mut self.slice_headers);
+            self.check_coverage(&headers, frame);
+            self.slice_headers = headers;
+        }
+
+        let slice_count = self.slice_count;
+        if slice_count == 0 {
+            return;
+        }
+        if let Err(err) =
+            locate_slices(frame_input, &mut self.current_frame, slice_count)
+        {
+            self.report("4.8. Slice Footer", location, err.to_string());
+        }
+    }
+
+    /// Reads the slice layout of a version 2 keyframe into 'slice_count'
+    /// and 'slice_headers', reporting what is wrong with each slice.
+    fn read_slice_layout(
+        &mut self,
+        coder: &mut RangeCoder,
+        frame: usize,
+    ) -> Result<()> {
+        let mut state: [u8; CONTEXT_SIZE as usize] =
+            [128; CONTEXT_SIZE as usize];
+        let (num_h_slices, num_v_slices) = self.num_slices();
+
+        let slice_count = coder.ur(&mut state)?;
+        if slice_count == 0
+            || slice_count as u64 > num_h_slices as u64 * num_v_slices as u64
+        {
+            self.report(
+                "4.3. Frame",
+                Location::Frame(frame),
+                format!("invalid slice count: {}", slice_count),
+            );
+            return Ok(());
+        }
+
+        let record = match self.record {
+            Some(ref record) => record,
+            None => return Ok(()),
+        };
+        let violations = &mut self.violations;
+        self.slice_count = slice_count as usize;
+        for slice in 0..slice_count as usize {
+            let mut header = SliceHeader::default();
+            let mut tracer = Tracer::new(None, Location::Slice(frame, slice));
+            if header.read_position(
+                coder,
+                &mut state,
+                record,
+                &mut tracer,
+                &mut |element| violations.push(element.into()),
+            )? {
+                self.slice_headers.push((slice, header));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Checks the footers and the headers of the slices of a frame, for
+    /// version 3 and later.
+    fn check_slices(
+        &mut self,
+        frame_input: &[u8],
+        header_coder: RangeCoder,
+        frame: usize,
+    ) {
+        let (ec, coder_type) = match self.record {
+            Some(ref record) => (record.ec, record.coder_type),
+            None => return,
+        };
+
+        if let Err(err) =
+            count_slices(frame_input, &mut self.current_frame, ec != 0)
+        {
+            return self.report(
+                "4.8. Slice Footer",
+                Location::Frame(frame),
+                err.to_string(),
+            );
+        }
+
+        let mut headers = Vec::new();
+        let slice_info = std::mem::take(&mut self.current_frame.slice_info);
+        for (slice, info) in slice_info.iter().enumerate() {
+            let location = Location::Slice(frame, slice);
+            let pos = info.pos as usize;
+
+            if ec == 1 {
+                // 8 bytes for the footer, parity included.
+                let end = pos + info.size as usize + 8;
+                if info.error_status != 0 {
+                    self.report(
+                        "4.8.2. error_status",
+                        location,
+                        format!(
+                            "non-zero error_status: {}",
+                            info.error_status
+                        ),
+                    );
+                }
+                if crc32_mpeg2(&frame_input[pos..end]) != 0 {
+                    self.report(
+                        "4.8.3. slice_crc_parity",
+                        location,
+                        "CRC mismatch".to_owned(),
+                    );
+                }
+            }
+
+            // The first slice carries on from the frame header.
+            let coder = if slice == 0 {
+                Ok(header_coder.clone())
+            } else {
+                RangeCoder::new(&frame_input[pos..])
+            };
+            let result = coder.and_then(|mut coder| {
+                if coder_type == 2 {
+                    coder.set_table(&self.state_transition);
+                }
+                self.read_slice_header(&mut coder, location)
+            });
+            match result {
+                Ok(Some(header)) => headers.push((slice, header)),
+                Ok(None) => {}
+                Err(err) => {
+                    self.report("4.5. Slice Header", location, err.to_string())
+                }
+            }
+        }
+        self.current_frame.slice_info = slice_info;
+
+        self.check_coverage(&headers, frame);
+    }
+
+    /// Reads the header of a slice, reporting what is wrong with it.
+    ///
+    /// Returns the header, if the slice lies within the grid of slices.
+    ///
+    /// See: 4.5. Slice Header
+    fn read_slice_header(
+        &mut self,
+        coder: &mut RangeCoder,
+        location: Location,
+    ) -> Result<Option<SliceHeader>> {
+        let record = match self.record {
+            Some(ref record) => record,
+            None => return Ok(None),
+        };
+        let violations = &mut self.violations;
+        let mut header = SliceHeader::default();
+        let within_grid = header.read(
+            coder,
+            record,
+            &mut Tracer::new(None, location),
+            &mut |element| violations.push(element.into()),
+        )?;
+
+        // The decoder takes reserved values as unknown.
+        //
+        // See: 4.5.7. picture_structure
+        if header.picture_structure > 3 {
+            self.report(
+                "4.5.7. picture_structure",
+                location,
+                format!("reserved value: {}", header.picture_structure),
+            );
+        }
+
+        Ok(if within_grid { Some(header) } else { None })
+    }
+
+    /// Checks that the slices of a frame cover the grid of slices, each
+    /// cell of it once. Each header comes with the index of its slice in
+    /// the frame.
+    ///
+    /// See: 4.5. Slice Header
+    fn check_coverage(
+        &mut self,
+        headers: &[(usize, SliceHeader)],
+        frame: usize,
+    ) {
+        let (num_h_slices, num_v_slices) = self.num_slices();
+        let mut covered = vec![false; (num_h_slices * num_v_slices) as usize];
+
+        for &(slice, ref header) in headers {
+            let mut overlaps = false;
+            let (x, y) = (header.slice_x, header.slice_y);
+            for y in y..=y + header.slice_height_minus1 {
+                for x in x..=x + header.slice_width_minus1 {
+                    let cell = &mut covered[(y * num_h_slices + x) as usize];
+                    overlaps |= *cell;
+                    *cell = true;
+                }
+            }
+            if overlaps {
+                self.report(
+                    "4.5. Slice Header",
+                    Location::Slice(frame, slice),
+                    "the slice overlaps another one".to_owned(),
+                );
+            }
+        }
+
+        let missing = covered.iter().filter(|&&covered| !covered).count();
+        if missing > 0 {
+            self.report(
+                "4.5. Slice Header",
+                Location::Frame(frame),
+                format!(
+                    "{} of the {} cells of the grid of slices are not covered",
+                    missing,
+                    covered.len()
+                ),
+            );
+        }
+    }
+
+    /// Returns the number of columns and rows of the grid of slices.
+    fn num_slices(&self) -> (u32, u32) {
+        match self.record {
+            Some(ref record) => (
+                record.num_h_slices_minus1 as u32 + 1,
+                record.num_v_slices_minus1 as u32 + 1,
+            ),
+            None => (1, 1),
+        }
+    }
+}
@@ -51,3 +51,14 @@ const CRC32_TABLE: [u32; 256] = [
 pub fn crc32_mpeg2(buf: &[u8]) -> u32 {
     !update(!0, &CRC32_TABLE, buf)
 }
+
+/// Returns the parity bytes to append to 'buf', so that the CRC of the
+/// whole is 0.
+///
+/// See: * 4.2.2. configuration_record_crc_parity
+///      * 4.8.3. slice_crc_parity
+pub fn crc32_mpeg2_parity(buf: &[u8]) -> [u8; 4] {
+    // crc32_mpeg2 works on reflected values, so the parity comes out
+    // with its bytes in reverse order.
+    crc32_mpeg2(buf).to_le_bytes()
+}
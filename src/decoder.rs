@@ -1,83 +1,57 @@
+use std::sync::{Mutex, PoisonError};
+
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
 use crate::constants::CONTEXT_SIZE;
 use crate::crc32mpeg2::crc32_mpeg2;
 use crate::error::{Error, Result};
-use crate::golomb::{Coder, State};
-use crate::jpeg2000rct::{rct16, rct8, rct_mid};
-use crate::pred::{
-    derive_borders, derive_borders16, derive_borders32, get_context,
-    get_median,
+pub use crate::frame::Frame;
+use crate::frame::{
+    Concealed, Concealment, FrameLayout, PictureStructure, PixelFormat,
+    PlaneMut, PlanesMut, SliceDamage, SliceMetadata,
 };
+use crate::golomb::{Coder, State};
+use crate::jpeg2000rct::rct;
+use crate::pred::{derive_borders32, get_context, predict};
 use crate::range::RangeCoder;
 use crate::rangecoder::tables::DEFAULT_STATE_TRANSITION;
 use crate::record::ConfigRecord;
-use crate::slice::{count_slices, is_keyframe, InternalFrame, Slice};
-
-/// Frame contains a decoded FFV1 frame and relevant
-/// data about the frame.
-///
-/// If BitDepth is 8, image data is in Buf. If it is anything else,
-/// image data is in Buf16.
-///
-/// Image data consists of up to four contiguous planes, as follows:
-///   - If ColorSpace is YCbCr:
-///     - Plane 0 is Luma (always present)
-///     - If HasChroma is true, the next two planes are Cr and Cr, subsampled by
-///       ChromaSubsampleV and ChromaSubsampleH.
-///     - If HasAlpha is true, the next plane is alpha.
-///  - If ColorSpace is RGB:
-///    - Plane 0 is Green
-///    - Plane 1 is Blue
-///    - Plane 2 is Red
-///    - If HasAlpha is true, plane 4 is alpha.
-pub struct Frame {
-    /// Image data. Valid only when BitDepth is 8.
-    pub buf: Vec<Vec<u8>>,
-    /// Image data. Valid only when BitDepth is greater than 8.
-    pub buf16: Vec<Vec<u16>>,
-    /// Unexported 32-bit scratch buffer for 16-bit JPEG2000-RCT RGB
-    pub buf32: Vec<Vec<u32>>,
-    /// Width of the frame, in pixels.
-    #[allow(dead_code)]
-    pub width: u32,
-    /// Height of the frame, in pixels.
-    #[allow(dead_code)]
-    pub height: u32,
-    /// Bitdepth of the frame (8-16).
-    #[allow(dead_code)]
-    pub bit_depth: u8,
-    /// Colorspace of the frame. See the colorspace constants.
-    #[allow(dead_code)]
-    pub color_space: isize,
-    /// Whether or not chroma planes are present.
-    #[allow(dead_code)]
-    pub has_chroma: bool,
-    /// Whether or not an alpha plane is present.
-    #[allow(dead_code)]
-    pub has_alpha: bool,
-    /// The log2 vertical chroma subampling value.
-    #[allow(dead_code)]
-    pub chroma_subsample_v: u8,
-    /// The log2 horizontal chroma subsampling value.
-    #[allow(dead_code)]
-    pub chroma_subsample_h: u8,
-}
+use crate::slice::{
+    count_slices, locate_slices, FrameIntegrity, InternalFrame, Slice,
+    SliceInfo, SliceIntegrity,
+};
+use crate::trace::{Location, Trace, Tracer};
+use crate::vfw::BitmapInfoHeader;
 
 /// Decoder is a FFV1 decoder instance.
 pub struct Decoder {
     width: u32,
     height: u32,
     record: ConfigRecord,
+    record_present: bool,
     state_transition: [u8; 256],
     initial_states: Vec<Vec<Vec<u8>>>, // FIXME: This is horrible
     current_frame: InternalFrame,
+    pool: Option<ThreadPool>,
+    single_threaded: bool,
+    frame_layout: FrameLayout,
+    concealment: Concealment,
+    trace: Option<Mutex<Box<dyn Trace>>>,
+    frame_count: usize,
 }
 
 impl Decoder {
     /// NewDecoder creates a new FFV1 decoder instance.
     ///
     /// 'record' is the codec private data provided by the container. For
-    /// Matroska, this is what is in CodecPrivate (adjusted for e.g. VFW
-    /// data that may be before it). For ISOBMFF, this is the 'glbl' box.
+    /// Matroska, this is what is in CodecPrivate (for VFW data, see
+    /// from_vfw). For ISOBMFF, this is the payload of the 'glbl' box,
+    /// which the mp4 module gets.
+    ///
+    /// FFV1 versions 0 and 1 have no configuration record, and code their
+    /// parameters in each keyframe instead. For those, 'record' must be
+    /// empty.
     ///
     /// 'width' and 'height' are the frame width and height provided by
     /// the container.
@@ -89,33 +63,44 @@ impl Decoder {
             )));
         }
 
-        if record.is_empty() {
-            return Err(Error::InvalidInputData(
-                "invalid record with length zero".to_owned(),
-            ));
-        }
-
-        let record = match ConfigRecord::parse_config_record(&record) {
-            Ok(record) => record,
-            Err(err) => {
-                return Err(Error::InvalidInputData(format!(
-                    "invalid v3 configuration record: {}",
-                    err
-                )))
+        // The parameters of FFV1 versions 0 and 1 are only known once the
+        // first keyframe is parsed.
+        //
+        // See: 4.3. Frame
+        let record_present = !record.is_empty();
+        let record = if record_present {
+            match ConfigRecord::parse_config_record(record) {
+                Ok(record) => record,
+                Err(err) => {
+                    return Err(Error::InvalidInputData(format!(
+                        "invalid configuration record: {}",
+                        err
+                    )))
+                }
             }
+        } else {
+            Default::default()
         };
 
         let mut decoder = Decoder {
             width,
             height,
             record,
+            record_present,
             state_transition: [0; 256],
             initial_states: Vec::new(),
             current_frame: InternalFrame {
                 keyframe: false,
                 slice_info: Vec::new(),
                 slices: Vec::new(),
+                slice_headers: Vec::new(),
             },
+            pool: None,
+            single_threaded: false,
+            frame_layout: FrameLayout::default(),
+            concealment: Concealment::Disabled,
+            trace: None,
+            frame_count: 0,
         };
 
         decoder.initialize_states();
@@ -123,103 +108,347 @@ impl Decoder {
         Ok(decoder)
     }
 
+    /// Creates a new FFV1 decoder instance from Video for Windows codec
+    /// private data, that is a BITMAPINFOHEADER followed by the
+    /// configuration record, as found in AVI 'strf' chunks and in the
+    /// CodecPrivate of V_MS/VFW/FOURCC Matroska tracks.
+    ///
+    /// The frame width and height are taken from the header.
+    pub fn from_vfw(codec_private: &[u8]) -> Result<Self> {
+        let header =
+            BitmapInfoHeader::parse_bitmap_info_header(codec_private)?;
+        let (width, height) = header.dimensions();
+
+        Self::new(&header.config_record, width, height)
+    }
+
+    /// Sets the number of threads used to decode the slices of a frame.
+    ///
+    /// By default, and when 'threads' is 0, slices are decoded on the
+    /// global rayon thread pool, which has one thread per logical CPU.
+    /// With 1 thread, slices are decoded one after the other on the
+    /// calling thread, without going through rayon at all.
+    pub fn set_threads(&mut self, threads: usize) -> Result<()> {
+        self.single_threaded = threads == 1;
+        if threads <= 1 {
+            self.pool = None;
+            return Ok(());
+        }
+
+        let pool = match ThreadPoolBuilder::new().num_threads(threads).build()
+        {
+            Ok(pool) => pool,
+            Err(err) => {
+                return Err(Error::InvalidConfiguration(format!(
+                    "cannot create a pool of {} threads: {}",
+                    threads, err
+                )))
+            }
+        };
+        self.pool = Some(pool);
+
+        Ok(())
+    }
+
+    /// Sets how the planes of the frames returned by decode_frame are laid
+    /// out in memory, e.g. to align rows for SIMD or GPU uploads.
+    ///
+    /// Strides smaller than the width of their plane make decode_frame
+    /// fail.
+    pub fn set_frame_layout(&mut self, layout: FrameLayout) -> Result<()> {
+        layout.validate()?;
+        self.frame_layout = layout;
+        Ok(())
+    }
+
+    /// Sets what to do with damaged slices. By default, they make the
+    /// whole frame fail.
+    ///
+    /// Concealed slices are reported by the metadata of the frame, see
+    /// Frame::slices.
+    pub fn set_concealment(&mut self, concealment: Concealment) {
+        self.concealment = concealment;
+    }
+
+    /// Sets a trace to pass every syntax element read from the frame
+    /// headers, the slice headers and the slice footers on to, in coding
+    /// order. Slice contents are not traced.
+    ///
+    /// Slices decoded in parallel are passed on once all of them are
+    /// decoded, in the order of the packet.
+    ///
+    /// Frames are numbered from the first one decoded after the trace is
+    /// set. To trace the configuration record, see
+    /// ConfigRecord::parse_config_record_with_trace.
+    pub fn set_trace(&mut self, trace: Option<Box<dyn Trace>>) {
+        self.trace = trace.map(Mutex::new);
+        self.frame_count = 0;
+    }
+
+    /// Returns the pixel format of the frames, to size the planes given to
+    /// decode_frame_into_u8 or decode_frame_into_u16.
+    ///
+    /// FFV1 versions 0 and 1 code it in each keyframe, so it is unknown
+    /// until the first one is decoded.
+    pub fn pixel_format(&self) -> Option<PixelFormat> {
+        if self.record_present || !self.current_frame.slices.is_empty() {
+            Some(PixelFormat::from_record(&self.record))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the picture structure of the last decoded frame, as coded
+    /// by its first slice. It is unknown for FFV1 versions 0 to 2, which
+    /// do not code it.
+    ///
+    /// See: 4.5.7. picture_structure
+    pub fn picture_structure(&self) -> PictureStructure {
+        match self.current_frame.slices.first() {
+            Some(slice) => {
+                PictureStructure::from_coded(slice.header.picture_structure)
+            }
+            None => PictureStructure::Unknown,
+        }
+    }
+
+    /// Returns the slices of the last decoded frame, in the order they are
+    /// coded.
+    pub fn slices(&self) -> Vec<SliceMetadata> {
+        self.current_frame
+            .slices
+            .iter()
+            .zip(&self.current_frame.slice_info)
+            .map(|(slice, info)| SliceMetadata {
+                x: slice.start_x,
+                y: slice.start_y,
+                width: slice.width,
+                height: slice.height,
+                quant_table_set_index: slice
+                    .header
+                    .quant_table_set_index
+                    .clone(),
+                picture_structure: PictureStructure::from_coded(
+                    slice.header.picture_structure,
+                ),
+                sar_num: slice.header.sar_num,
+                sar_den: slice.header.sar_den,
+                error_status: info.error_status,
+                concealed: slice.concealed.clone(),
+            })
+            .collect()
+    }
+
     /// DecodeFrame takes a packet and decodes it to a ffv1.Frame.
     ///
-    /// Slice threading is used by default, with one goroutine per
-    /// slice.
+    /// Slice threading is used by default, with one rayon task per
+    /// slice. See `set_threads` to control the number of threads.
     pub fn decode_frame(&mut self, frame_input: &[u8]) -> Result<Frame> {
-        let mut frame = Frame {
-            buf: Vec::new(),
-            buf16: Vec::new(),
-            buf32: Vec::new(),
-            width: self.width,
-            height: self.height,
-            bit_depth: self.record.bits_per_raw_sample,
-            color_space: self.record.colorspace_type as isize,
-            has_chroma: self.record.chroma_planes,
-            has_alpha: self.record.extra_plane,
-            chroma_subsample_v: if self.record.chroma_planes {
-                self.record.log2_v_chroma_subsample
-            } else {
-                0
-            },
-            chroma_subsample_h: if self.record.chroma_planes {
-                self.record.log2_h_chroma_subsample
-            } else {
-                0
-            },
-        };
+        let header_coder = self.decode_frame_header(frame_input)?;
 
-        let mut num_planes = 1;
-        if self.record.chroma_planes {
-            num_planes += 2;
+        let pixel_format = PixelFormat::from_record(&self.record);
+        let mut frame = Frame::new(
+            self.width,
+            self.height,
+            pixel_format,
+            &self.frame_layout,
+        )?;
+        frame.with_planes_mut(|planes| {
+            self.decode_slices(frame_input, header_coder, planes)
+        })?;
+        frame.set_picture_structure(self.picture_structure());
+        frame.set_slices(self.slices());
+
+        Ok(frame)
+    }
+
+    /// Decodes a packet of an 8 bit stream into the planes given by the
+    /// caller, in the order of the pixel format.
+    ///
+    /// Each plane must hold the rows of its pixel_format plane_dimensions,
+    /// 'stride' samples apart. Samples outside of them are left alone.
+    ///
+    /// The memory used to decode slices is kept from frame to frame, so
+    /// once a stream is going this makes no allocations at all, except
+    /// for the keyframes of FFV1 versions 0 and 1, whose parameters are
+    /// parsed anew each time.
+    pub fn decode_frame_into_u8(
+        &mut self,
+        frame_input: &[u8],
+        planes: &mut [PlaneMut<'_, u8>],
+    ) -> Result<()> {
+        let header_coder = self.decode_frame_header(frame_input)?;
+        self.decode_slices(frame_input, header_coder, PlanesMut::U8(planes))
+    }
+
+    /// Decodes a packet of a 9 to 16 bit stream into the planes given by
+    /// the caller. See decode_frame_into_u8.
+    pub fn decode_frame_into_u16(
+        &mut self,
+        frame_input: &[u8],
+        planes: &mut [PlaneMut<'_, u16>],
+    ) -> Result<()> {
+        let header_coder = self.decode_frame_header(frame_input)?;
+        self.decode_slices(frame_input, header_coder, PlanesMut::U16(planes))
+    }
+
+    /// Checks the integrity of a packet without decoding it: the slice
+    /// footers must tile the packet, and each slice is checked against its
+    /// CRC.
+    ///
+    /// The state of the decoder is left alone, so packets can be verified
+    /// in any order, and this works for streams whose frames cannot be
+    /// decoded by this crate too. Only streams with slice CRCs, that is
+    /// version 3 and later with 'ec' set, can be verified.
+    ///
+    /// See: * 4.8. Slice Footer
+    ///      * 9.1.1. Multi-threading Support and Independence of Slices
+    pub fn verify_frame(&self, frame_input: &[u8]) -> Result<FrameIntegrity> {
+        if self.record.version < 3 || self.record.ec != 1 {
+            return Err(Error::InvalidConfiguration(
+                "the stream has no slice CRCs".to_owned(),
+            ));
         }
-        if self.record.extra_plane {
-            num_planes += 1;
-        }
-
-        // Hideous and temporary.
-        if self.record.bits_per_raw_sample == 8 {
-            frame.buf = vec![Vec::new(); num_planes];
-            frame.buf[0] = vec![0; (self.width * self.height) as usize];
-            if self.record.chroma_planes {
-                let chroma_width =
-                    self.width >> self.record.log2_h_chroma_subsample;
-                let chroma_height =
-                    self.height >> self.record.log2_v_chroma_subsample;
-                frame.buf[1] =
-                    vec![0; (chroma_width * chroma_height) as usize];
-                frame.buf[2] =
-                    vec![0; (chroma_width * chroma_height) as usize];
-            }
-            if self.record.extra_plane {
-                frame.buf[3] = vec![0; (self.width * self.height) as usize];
-            }
-        }
-
-        // We allocate *both* if it's 8bit RGB since I'm a terrible person and
-        // I wanted to use it as a scratch space, since JPEG2000-RCT is very
-        // annoyingly coded as n+1 bits, and I wanted the implementation
-        // to be straightforward... RIP.
-        if self.record.bits_per_raw_sample > 8
-            || self.record.colorspace_type == 1
-        {
-            frame.buf16 = vec![Vec::new(); num_planes];
-            frame.buf16[0] = vec![0; (self.width * self.height) as usize];
-            if self.record.chroma_planes {
-                let chroma_width =
-                    self.width >> self.record.log2_h_chroma_subsample;
-                let chroma_height =
-                    self.height >> self.record.log2_v_chroma_subsample;
-                frame.buf16[1] =
-                    vec![0; (chroma_width * chroma_height) as usize];
-                frame.buf16[2] =
-                    vec![0; (chroma_width * chroma_height) as usize];
-            }
-            if self.record.extra_plane {
-                frame.buf16[3] = vec![0; (self.width * self.height) as usize];
-            }
-        }
-
-        // For 16-bit RGB we need a 32-bit scratch space beause we need to predict
-        // based on 17-bit values in the JPEG2000-RCT space, so just allocate a
-        // whole frame, because I am lazy. Is it slow? Yes.
-        if self.record.bits_per_raw_sample == 16
-            && self.record.colorspace_type == 1
-        {
-            frame.buf32 = vec![Vec::new(); num_planes];
-            frame.buf32[0] = vec![0; (self.width * self.height) as usize];
-            frame.buf32[1] = vec![0; (self.width * self.height) as usize];
-            frame.buf32[2] = vec![0; (self.width * self.height) as usize];
-            if self.record.extra_plane {
-                frame.buf32[3] = vec![0; (self.width * self.height) as usize];
+        if frame_input.is_empty() {
+            return Err(Error::FrameError("empty frame".to_owned()));
+        }
+
+        let mut state: [u8; CONTEXT_SIZE as usize] =
+            [128; CONTEXT_SIZE as usize];
+        let keyframe = match RangeCoder::new(frame_input) {
+            Ok(mut coder) => coder.br(&mut state),
+            Err(err) => {
+                return Err(Error::FrameError(format!(
+                    "invalid frame header: {}",
+                    err
+                )))
             }
+        };
+
+        let mut frame = InternalFrame::default();
+        if let Err(err) = count_slices(frame_input, &mut frame, true) {
+            return Err(Error::FrameError(format!(
+                "invalid frame footer: {}",
+                err
+            )));
+        }
+
+        let slices = frame
+            .slice_info
+            .iter()
+            .map(|info| {
+                // 8 bytes for the footer, parity included.
+                let pos = info.pos as usize;
+                let end = pos + info.size as usize + 8;
+                SliceIntegrity {
+                    pos,
+                    size: info.size as usize,
+                    error_status: info.error_status,
+                    crc_valid: crc32_mpeg2(&frame_input[pos..end]) == 0,
+                }
+            })
+            .collect();
+
+        Ok(FrameIntegrity { keyframe, slices })
+    }
+
+    /// Parses the frame header, and locates the slices of the frame.
+    ///
+    /// Returns the coder the first slice carries on with.
+    fn decode_frame_header<'a>(
+        &mut self,
+        frame_input: &'a [u8],
+    ) -> Result<RangeCoder<'a>> {
+        let frame = self.frame_count;
+        self.frame_count += 1;
+
+        // The trace is taken out while parsing, which needs the decoder.
+        let mut trace = self.trace.take();
+        let mut tracer = Tracer::new(
+            trace.as_mut().map(|trace| {
+                &mut **trace.get_mut().unwrap_or_else(PoisonError::into_inner)
+                    as &mut dyn Trace
+            }),
+            Location::Frame(frame),
+        );
+        let result = self.parse_frame_header(frame_input, &mut tracer);
+        self.trace = trace;
+
+        result
+    }
+
+    /// Parses the frame header, passing the syntax elements read on to
+    /// 'tracer'.
+    ///
+    /// See: 4.3. Frame
+    fn parse_frame_header<'a>(
+        &mut self,
+        frame_input: &'a [u8],
+        tracer: &mut Tracer,
+    ) -> Result<RangeCoder<'a>> {
+        if frame_input.is_empty() {
+            return Err(Error::FrameError("empty frame".to_owned()));
         }
 
+        // 4. Bitstream
+        let mut state: [u8; CONTEXT_SIZE as usize] =
+            [128; CONTEXT_SIZE as usize];
+
         // We parse the frame's keyframe info outside the slice decoding
         // loop so we know ahead of time if each slice has to refresh its
         // states or not. This allows easy slice threading.
-        self.current_frame.keyframe = is_keyframe(frame_input);
+        //
+        // The coder is kept, since the first slice carries on from the end
+        // of the frame header.
+        //
+        // See: 4.3. Frame
+        let mut header_coder = match RangeCoder::new(frame_input) {
+            Ok(coder) => coder,
+            Err(err) => {
+                return Err(Error::FrameError(format!(
+                    "invalid frame header: {}",
+                    err
+                )))
+            }
+        };
+        self.current_frame.keyframe =
+            tracer.br(&mut header_coder, &mut state, "4.3. keyframe");
+        if !self.current_frame.keyframe && self.current_frame.slices.is_empty()
+        {
+            return Err(Error::FrameError(
+                "cannot decode an inter frame before any keyframe".to_owned(),
+            ));
+        }
+
+        // 4.1. Parameters
+        if self.current_frame.keyframe && !self.record_present {
+            self.record = match ConfigRecord::parse_keyframe_header_traced(
+                &mut header_coder,
+                tracer,
+            ) {
+                Ok(record) => record,
+                Err(err) => {
+                    return Err(Error::FrameError(format!(
+                        "invalid keyframe header: {}",
+                        err
+                    )))
+                }
+            };
+            self.initialize_states();
+        }
+
+        // Versions older than 3 code the slice layout in the keyframe
+        // header too.
+        if self.current_frame.keyframe && self.record.version < 3 {
+            if let Err(err) =
+                self.parse_slice_layout(&mut header_coder, tracer)
+            {
+                return Err(Error::FrameError(format!(
+                    "invalid slice layout: {}",
+                    err
+                )));
+            }
+        }
 
         // We parse all the footers ahead of time too, for the same reason.
         // It allows us to know all the slice positions and sizes.
@@ -233,29 +462,550 @@ impl Decoder {
             )));
         }
 
-        // Slice threading lazymode (not using sync for now, only sequential code,
-        // FIXME there could be errors here)
-        for i in 0..self.current_frame.slices.len() {
-            let err = self.decode_slice(frame_input, i as isize, &mut frame);
-            if let Err(err) = err {
-                return Err(Error::SliceError(format!(
-                    "slice {} failed: {}",
-                    i, err
+        Ok(header_coder)
+    }
+
+    /// Checks that the planes given by the caller fit the frame.
+    fn check_planes<T>(&self, planes: &[PlaneMut<'_, T>]) -> Result<()> {
+        let pixel_format = PixelFormat::from_record(&self.record);
+        if planes.len() != pixel_format.num_planes() {
+            return Err(Error::InvalidInputData(format!(
+                "{} planes given for {}, which has {}",
+                planes.len(),
+                pixel_format,
+                pixel_format.num_planes()
+            )));
+        }
+
+        for (p, plane) in planes.iter().enumerate() {
+            let (width, height) =
+                pixel_format.plane_dimensions(p, self.width, self.height);
+            let (width, height) = (width as usize, height as usize);
+            if height > 0
+                && (plane.stride < width
+                    || plane.data.len() < (height - 1) * plane.stride + width)
+            {
+                return Err(Error::InvalidInputData(format!(
+                    "plane {} cannot hold {}x{} samples with a stride of {}",
+                    p, width, height, plane.stride
                 )));
             }
         }
 
-        // Delete the scratch buffer, if needed, as per above.
-        if self.record.bits_per_raw_sample == 8
-            && self.record.colorspace_type == 1
+        Ok(())
+    }
+
+    /// Decodes the slices of a frame, whose header was parsed by
+    /// decode_frame_header, into 'planes'.
+    fn decode_slices(
+        &mut self,
+        frame_input: &[u8],
+        header_coder: RangeCoder,
+        planes: PlanesMut,
+    ) -> Result<()> {
+        let bit_depth = self.record.bits_per_raw_sample;
+        match planes {
+            PlanesMut::U8(planes) if bit_depth == 8 => {
+                self.decode_slices_into(frame_input, header_coder, planes)
+            }
+            PlanesMut::U16(planes) if bit_depth > 8 => {
+                self.decode_slices_into(frame_input, header_coder, planes)
+            }
+            _ => Err(Error::InvalidInputData(format!(
+                "wrong sample type for a {} bit stream",
+                bit_depth
+            ))),
+        }
+    }
+
+    /// Decodes the slices of a frame into 'planes', see decode_slices.
+    fn decode_slices_into<T: Sample>(
+        &mut self,
+        frame_input: &[u8],
+        header_coder: RangeCoder,
+        planes: &mut [PlaneMut<'_, T>],
+    ) -> Result<()> {
+        self.check_planes(planes)?;
+
+        // Slices never predict from each other, so each one is decoded
+        // straight into its own region of the frame. To decode them in
+        // parallel, their headers are read first, and the rows of each
+        // region are then split off the planes.
+        let mut slices = std::mem::take(&mut self.current_frame.slices);
+        let decoder = &*self;
+        let slice_info = &self.current_frame.slice_info;
+        let start_slice =
+            |(i, (slice, info)): (usize, (&mut Slice, &SliceInfo))| {
+                let coder = if i == 0 {
+                    Ok(header_coder.clone())
+                } else {
+                    RangeCoder::new(&frame_input[info.pos as usize..])
+                };
+                slice.concealed = None;
+                if decoder.trace.is_some() {
+                    decoder.trace_slice_footer(frame_input, i, info, slice);
+                }
+                coder
+                    .and_then(|coder| {
+                        decoder.start_slice(frame_input, coder, info, i, slice)
+                    })
+                    .map_err(|err| slice_failed(i, err))
+            };
+        let decode = |slices: &mut Vec<Slice>,
+                      planes: &mut [PlaneMut<'_, T>]| {
+            let coders = slices
+                .par_iter_mut()
+                .zip(slice_info.par_iter())
+                .enumerate()
+                .map(start_slice)
+                .collect::<Result<Vec<_>>>()?;
+            for (i, slice) in slices.iter().enumerate() {
+                decoder
+                    .check_slice_region(slice, &slices[..i])
+                    .map_err(|err| slice_failed(i, err))?;
+            }
+            let rows = decoder.split_planes(planes, slices);
+            slices
+                .par_iter_mut()
+                .zip(coders)
+                .zip(rows)
+                .enumerate()
+                .try_for_each(|(i, ((slice, coders), mut rows))| {
+                    decoder
+                        .finish_slice(coders, slice, &mut rows)
+                        .map_err(|err| slice_failed(i, err))
+                })
+        };
+        let result = if self.single_threaded {
+            (0..slices.len()).try_for_each(|i| {
+                let (done, rest) = slices.split_at_mut(i);
+                let slice = &mut rest[0];
+                let coders = start_slice((i, (&mut *slice, &slice_info[i])))?;
+                decoder
+                    .check_slice_region(slice, done)
+                    .and_then(|()| {
+                        let regions = decoder.plane_regions(slice);
+                        let mut rows =
+                            SliceRows::Planes(&mut *planes, regions);
+                        decoder.finish_slice(coders, slice, &mut rows)
+                    })
+                    .map_err(|err| slice_failed(i, err))
+            })
+        } else {
+            match self.pool {
+                Some(ref pool) => {
+                    pool.install(|| decode(&mut slices, &mut *planes))
+                }
+                None => decode(&mut slices, &mut *planes),
+            }
+        };
+        self.current_frame.slices = slices;
+
+        // Traced even if a slice failed, since this is when traces are
+        // needed the most.
+        if let Some(ref trace) = self.trace {
+            let mut trace =
+                trace.lock().unwrap_or_else(PoisonError::into_inner);
+            for (slice, info) in self
+                .current_frame
+                .slices
+                .iter_mut()
+                .zip(&self.current_frame.slice_info)
+            {
+                for mut element in slice.trace.drain(..) {
+                    element.pos += info.pos as usize;
+                    trace.element(&element);
+                }
+            }
+        }
+        result?;
+
+        if self
+            .current_frame
+            .slices
+            .iter()
+            .any(|slice| slice.concealed.is_some())
         {
-            frame.buf16 = Vec::new();
+            self.conceal_slices(planes);
         }
 
-        // We'll never need this again.
-        frame.buf32 = Vec::new();
+        Ok(())
+    }
 
-        Ok(frame)
+    /// Checks that a slice fits in the frame without overlapping any of
+    /// 'others', so that it can be written to its own region of the
+    /// planes.
+    fn check_slice_region(
+        &self,
+        slice: &Slice,
+        others: &[Slice],
+    ) -> Result<()> {
+        let is_empty = |slice: &Slice| slice.width == 0 || slice.height == 0;
+        if is_empty(slice) {
+            return Ok(());
+        }
+
+        if slice.start_x as u64 + slice.width as u64 > self.width as u64
+            || slice.start_y as u64 + slice.height as u64 > self.height as u64
+        {
+            return Err(Error::SliceError(format!(
+                "{}x{} slice at {},{} does not fit in the frame",
+                slice.width, slice.height, slice.start_x, slice.start_y
+            )));
+        }
+
+        // Chroma planes round the edges of slices down the same way, so
+        // slices which do not overlap in the first plane overlap in none.
+        let overlaps = |other: &Slice| {
+            !is_empty(other)
+                && slice.start_x < other.start_x + other.width
+                && other.start_x < slice.start_x + slice.width
+                && slice.start_y < other.start_y + other.height
+                && other.start_y < slice.start_y + slice.height
+        };
+        match others.iter().position(overlaps) {
+            Some(other) => Err(Error::SliceError(format!(
+                "slice overlaps slice {}",
+                other
+            ))),
+            None => Ok(()),
+        }
+    }
+
+    /// Splits 'planes' into the rows of the region of each slice, so that
+    /// slices can be written in parallel. Every slice must have passed
+    /// check_slice_region.
+    fn split_planes<'a, 'b, T>(
+        &self,
+        planes: &'a mut [PlaneMut<'b, T>],
+        slices: &[Slice],
+    ) -> Vec<SliceRows<'a, 'b, T>> {
+        let mut rows: Vec<Vec<Vec<&'a mut [T]>>> = slices
+            .iter()
+            .map(|_| planes.iter().map(|_| Vec::new()).collect())
+            .collect();
+        for (p, plane) in planes.iter_mut().enumerate() {
+            // The regions of the plane from left to right, and their slice.
+            let mut regions: Vec<(Region, usize)> = slices
+                .iter()
+                .enumerate()
+                .map(|(i, slice)| (self.plane_region(slice, p), i))
+                .filter(|&((_, _, width, height), _)| width > 0 && height > 0)
+                .collect();
+            regions.sort_unstable();
+
+            for (y, mut row) in plane.data.chunks_mut(plane.stride).enumerate()
+            {
+                let mut x = 0;
+                for &((region_x, region_y, width, height), i) in &regions {
+                    if !(region_y..region_y + height).contains(&y) {
+                        continue;
+                    }
+                    let (_, rest) =
+                        std::mem::take(&mut row).split_at_mut(region_x - x);
+                    let (region_row, rest) = rest.split_at_mut(width);
+                    rows[i][p].push(region_row);
+                    row = rest;
+                    x = region_x + width;
+                }
+            }
+        }
+
+        rows.into_iter().map(SliceRows::Split).collect()
+    }
+
+    /// Starts decoding a slice: checks its integrity, reads its header and
+    /// gets its states ready.
+    ///
+    /// 'coder' must be positioned at the start of the slice. For the first
+    /// slice, that is right after the frame header.
+    ///
+    /// Returns the coders to decode the content of the slice with, or None
+    /// if the slice is concealed instead, as recorded in 'slice.concealed'.
+    /// Unless concealment is disabled, only slices which cannot even be
+    /// located in the frame fail.
+    ///
+    /// Only the decoder parameters are shared, so this may be called for
+    /// several slices of the same frame at once.
+    ///
+    /// See: 4.4. Slice
+    fn start_slice<'a>(
+        &self,
+        buf: &'a [u8],
+        coder: RangeCoder<'a>,
+        info: &SliceInfo,
+        slicenum: usize,
+        slice: &mut Slice,
+    ) -> Result<Option<SliceCoders<'a>>> {
+        if self.concealment == Concealment::Disabled {
+            // Before we do anything, let's try and check the integrity
+            let result = match self.slice_damage(buf, info) {
+                Some(SliceDamage::ErrorStatus(error_status)) => {
+                    Err(Error::SliceError(format!(
+                        "error_status is non-zero: {}",
+                        error_status
+                    )))
+                }
+                Some(_) => {
+                    Err(Error::InvalidInputData("CRC mismatch".to_owned()))
+                }
+                None => self
+                    .start_slice_unchecked(buf, coder, info, slicenum, slice),
+            };
+            slice.states_damaged |= result.is_err();
+            return result.map(Some);
+        }
+
+        let damage = match self.slice_damage(buf, info) {
+            Some(damage) => {
+                // The position of the slice is taken from the previous
+                // frame if possible, since its header is not to be
+                // trusted.
+                if self.record.version < 3 {
+                    self.set_slice_boundaries(slice);
+                } else if slice.width == 0 {
+                    // Slices keep their position if their header is
+                    // invalid.
+                    let _ = self.parse_slice_header(
+                        &mut coder.clone(),
+                        slicenum,
+                        slice,
+                    );
+                }
+                damage
+            }
+            None => match self
+                .start_slice_unchecked(buf, coder, info, slicenum, slice)
+            {
+                Ok(coders) => return Ok(Some(coders)),
+                Err(err) => SliceDamage::Invalid(err.to_string()),
+            },
+        };
+        self.conceal_slice(slice, damage)?;
+
+        Ok(None)
+    }
+
+    /// Finishes a slice started by start_slice, decoding its content with
+    /// 'coders' into 'rows', or writing the slice of the previous frame
+    /// there if that is what conceals it.
+    fn finish_slice<T: Sample>(
+        &self,
+        coders: Option<SliceCoders>,
+        slice: &mut Slice,
+        rows: &mut SliceRows<'_, '_, T>,
+    ) -> Result<()> {
+        if let Some((mut coder, mut golomb_coder)) = coders {
+            // Don't worry, I fully understand how non-idiomatic and
+            // ugly passing both c and gc is.
+            match self.decode_slice_content(
+                &mut coder,
+                &mut golomb_coder.as_mut(),
+                slice,
+                rows,
+            ) {
+                Ok(()) => {
+                    if self.concealment == Concealment::PreviousFrame {
+                        self.keep_slice_samples(slice, rows);
+                    }
+                    return Ok(());
+                }
+                Err(err) if self.concealment == Concealment::Disabled => {
+                    slice.states_damaged = true;
+                    return Err(err);
+                }
+                Err(err) => self.conceal_slice(
+                    slice,
+                    SliceDamage::Invalid(err.to_string()),
+                )?,
+            }
+        }
+
+        if let Some(Concealed {
+            method: Concealment::PreviousFrame,
+            ..
+        }) = slice.concealed
+        {
+            self.write_previous_slice(slice, rows);
+        }
+
+        Ok(())
+    }
+
+    /// Records that a damaged slice is concealed, and how, in
+    /// 'slice.concealed'.
+    ///
+    /// Fails if the slice cannot be located in the frame.
+    fn conceal_slice(
+        &self,
+        slice: &mut Slice,
+        damage: SliceDamage,
+    ) -> Result<()> {
+        slice.states_damaged = true;
+        if slice.width == 0 || slice.height == 0 {
+            return Err(Error::SliceError(format!(
+                "cannot locate damaged slice: {:?}",
+                damage
+            )));
+        }
+
+        let method = if self.concealment == Concealment::PreviousFrame
+            && !slice.restore_previous()
+        {
+            // Nothing is kept to conceal the slice with until it is
+            // decoded again.
+            slice.previous.valid = false;
+            Concealment::MidGray
+        } else {
+            self.concealment
+        };
+        slice.concealed = Some(Concealed { damage, method });
+
+        Ok(())
+    }
+
+    /// Copies the samples of a slice, just decoded into 'rows', to conceal
+    /// the slice of the next frame with.
+    fn keep_slice_samples<T: Sample>(
+        &self,
+        slice: &mut Slice,
+        rows: &mut SliceRows<'_, '_, T>,
+    ) {
+        let regions = self.plane_regions(slice);
+        slice.save_previous();
+        let samples = &mut slice.previous.samples;
+        samples.resize_with(rows.num_planes(), Vec::new);
+        for (p, samples) in samples.iter_mut().enumerate() {
+            let (_, _, _, height) = regions[p];
+            samples.clear();
+            for y in 0..height {
+                samples.extend(rows.row(p, y).iter().map(|&s| s.to_u32()));
+            }
+        }
+    }
+
+    /// Writes the samples of the slice of the previous frame, kept by
+    /// keep_slice_samples, into 'rows'.
+    fn write_previous_slice<T: Sample>(
+        &self,
+        slice: &Slice,
+        rows: &mut SliceRows<'_, '_, T>,
+    ) {
+        let regions = self.plane_regions(slice);
+        for (p, samples) in slice
+            .previous
+            .samples
+            .iter()
+            .enumerate()
+            .take(rows.num_planes())
+        {
+            let (_, _, width, height) = regions[p];
+            for y in 0..height {
+                if let Some(src) = samples.get(y * width..(y + 1) * width) {
+                    write_line(rows.row(p, y), src);
+                }
+            }
+        }
+    }
+
+    /// Fills the slices concealed with mid-gray or by interpolation, once
+    /// every other slice is written.
+    fn conceal_slices<T: Sample>(&self, planes: &mut [PlaneMut<'_, T>]) {
+        let pixel_format = PixelFormat::from_record(&self.record);
+        let bits = self.record.bits_per_raw_sample as u32;
+
+        // Slices are interpolated from the samples of good slices only.
+        let is_filled = |slice: &Slice| {
+            matches!(
+                slice.concealed,
+                Some(Concealed {
+                    method: Concealment::MidGray | Concealment::Interpolate,
+                    ..
+                })
+            )
+        };
+        for (p, plane) in planes.iter_mut().enumerate() {
+            let (plane_width, plane_height) =
+                pixel_format.plane_dimensions(p, self.width, self.height);
+            let regions: Vec<Region> = self
+                .current_frame
+                .slices
+                .iter()
+                .filter(|slice| is_filled(slice))
+                .map(|slice| self.plane_region(slice, p))
+                .collect();
+            let available = |x: usize, y: usize| {
+                !regions.iter().any(|&(rx, ry, rw, rh)| {
+                    (rx..rx + rw).contains(&x) && (ry..ry + rh).contains(&y)
+                })
+            };
+            // Alpha is made opaque rather than half transparent.
+            let fill = if pixel_format.has_alpha()
+                && p == pixel_format.num_planes() - 1
+            {
+                T::from_u32((1 << bits) - 1)
+            } else {
+                T::from_u32(1 << (bits - 1))
+            };
+
+            for slice in &self.current_frame.slices {
+                let region = self.plane_region(slice, p);
+                match slice.concealed {
+                    Some(Concealed {
+                        method: Concealment::MidGray,
+                        ..
+                    }) => fill_region(plane, region, fill),
+                    Some(Concealed {
+                        method: Concealment::Interpolate,
+                        ..
+                    }) => interpolate_region(
+                        plane,
+                        region,
+                        (plane_width as usize, plane_height as usize),
+                        &available,
+                        fill,
+                    ),
+                    _ => {}
+                }
+            }
+        }
+    }
+
+    /// Returns the area a slice covers in plane 'p'.
+    ///
+    /// Chroma samples coded by two slices are written by the first one
+    /// only, so that slices cover disjoint areas: the area ends where the
+    /// next slice starts, rounded down. The last slice of a row or column
+    /// may not code the last chroma sample, when it starts on an odd
+    /// position and has an even size: that sample is left unwritten.
+    fn plane_region(&self, slice: &Slice, p: usize) -> Region {
+        let (width, height, start_x, start_y, _) =
+            self.plane_geometry(slice, p);
+        let (h_shift, v_shift) = self.plane_shifts(p);
+        let end = |start: u32, size: u32, frame_size: u32, shift: u8| {
+            if start + size == frame_size {
+                ceil_rshift(frame_size, shift)
+            } else {
+                (start + size) >> shift
+            }
+        };
+        let end_x = end(slice.start_x, slice.width, self.width, h_shift);
+        let end_y = end(slice.start_y, slice.height, self.height, v_shift);
+        (
+            start_x as usize,
+            start_y as usize,
+            (end_x as usize - start_x as usize).min(width as usize),
+            (end_y as usize - start_y as usize).min(height as usize),
+        )
+    }
+
+    /// Returns the area a slice covers in each plane, see plane_region.
+    fn plane_regions(&self, slice: &Slice) -> [Region; 4] {
+        let mut regions = [(0, 0, 0, 0); 4];
+        for (p, region) in regions.iter_mut().enumerate() {
+            *region = self.plane_region(slice, p);
+        }
+        regions
     }
 
     /// Initializes initial state for the range coder.
@@ -265,8 +1015,8 @@ impl Decoder {
         for (i, default_state_transition) in
             DEFAULT_STATE_TRANSITION.iter().enumerate().skip(1)
         {
-            self.state_transition[i] = (*default_state_transition as i16
-                + self.record.state_transition_delta[i])
+            self.state_transition[i] = (*default_state_transition as i16)
+                .wrapping_add(self.record.state_transition_delta[i])
                 as u8;
         }
 
@@ -284,9 +1034,10 @@ impl Decoder {
                     } else {
                         128 as i16
                     };
-                    self.initial_states[i][j][k] =
-                        ((pred + self.record.initial_state_delta[i][j][k])
-                            & 255) as u8;
+                    self.initial_states[i][j][k] = (pred.wrapping_add(
+                        self.record.initial_state_delta[i][j][k],
+                    ) & 255)
+                        as u8;
                 }
             }
         }
@@ -298,127 +1049,189 @@ impl Decoder {
     ///      * 3.8.1.3. Initial Values for the Context Model
     ///      * 3.8.2.4. Initial Values for the VLC context state
     pub fn parse_footers(&mut self, buf: &[u8]) -> Result<()> {
-        let err =
-            count_slices(buf, &mut self.current_frame, self.record.ec != 0);
+        // Versions 0 and 1 have a single slice and no footer.
+        let err = if self.record.version < 2 {
+            self.current_frame.slice_info.clear();
+            self.current_frame.slice_info.push(SliceInfo {
+                pos: 0,
+                size: buf.len() as u32,
+                error_status: 0,
+            });
+            Ok(())
+        } else if self.record.version == 2 {
+            let slice_count = self.current_frame.slice_headers.len();
+            locate_slices(buf, &mut self.current_frame, slice_count)
+        } else {
+            count_slices(buf, &mut self.current_frame, self.record.ec != 0)
+        };
         if let Err(err) = err {
             return Err(Error::SliceError(format!(
                 "couldn't count slices: {}",
                 err
             )));
         }
-
-        let mut slices: Vec<Slice> =
-            vec![Default::default(); self.current_frame.slice_info.len()];
-        if !self.current_frame.keyframe {
-            if slices.len() != self.current_frame.slices.len() {
-                return Err(Error::SliceError("inter frames must have the same number of slices as the preceding intra frame".to_owned()));
-            }
-            for (i, slice) in slices.iter_mut().enumerate() {
-                slice.state = self.current_frame.slices[i].state.clone();
-            }
-            if self.record.coder_type == 0 {
-                for (i, slice) in slices.iter_mut().enumerate() {
-                    slice.golomb_state =
-                        self.current_frame.slices[i].golomb_state.clone();
-                }
+
+        // Slices are kept from frame to frame, so that inter frames carry
+        // on with the states of the previous one, and so that no memory is
+        // allocated once the stream is going.
+        let slice_count = self.current_frame.slice_info.len();
+        if !self.current_frame.keyframe
+            && slice_count != self.current_frame.slices.len()
+        {
+            return Err(Error::SliceError("inter frames must have the same number of slices as the preceding intra frame".to_owned()));
+        }
+        self.current_frame
+            .slices
+            .resize_with(slice_count, Default::default);
+        if self.record.version < 3 {
+            for (slice, header) in self
+                .current_frame
+                .slices
+                .iter_mut()
+                .zip(&self.current_frame.slice_headers)
+            {
+                slice.header.clone_from(header);
             }
         }
-        self.current_frame.slices = slices;
 
         Ok(())
     }
 
-    /// Parses a slice's header.
+    /// Returns where slice number 'slicenum' of the current frame is.
+    fn slice_location(&self, slicenum: usize) -> Location {
+        // The frame count is increased with the frame header.
+        Location::Slice(self.frame_count.saturating_sub(1), slicenum)
+    }
+
+    /// Parses the header of slice number 'slicenum' of the current frame.
     ///
     /// See: 4.5. Slice Header
     pub fn parse_slice_header(
-        &mut self,
+        &self,
         coder: &mut RangeCoder,
         slicenum: usize,
-    ) {
-        // 4. Bitstream
-        let mut slice_state: [u8; CONTEXT_SIZE as usize] =
-            [128; CONTEXT_SIZE as usize];
+        slice: &mut Slice,
+    ) -> Result<()> {
+        // Elements are kept with the slice until the frame is decoded.
+        let mut tracer = Tracer::new(
+            match self.trace {
+                Some(_) => Some(&mut slice.trace as &mut dyn Trace),
+                None => None,
+            },
+            self.slice_location(slicenum),
+        );
 
-        // 4.5.1. slice_x
-        self.current_frame.slices[slicenum].header.slice_x =
-            coder.ur(&mut slice_state);
-        // 4.5.2. slice_y
-        self.current_frame.slices[slicenum].header.slice_y =
-            coder.ur(&mut slice_state);
-        // 4.5.3 slice_width
-        self.current_frame.slices[slicenum]
-            .header
-            .slice_width_minus1 = coder.ur(&mut slice_state);
-        // 4.5.4 slice_height
-        self.current_frame.slices[slicenum]
-            .header
-            .slice_height_minus1 = coder.ur(&mut slice_state);
-
-        // 4.5.5. quant_table_set_index_count
-        let mut quant_table_set_index_count = 1;
-        if self.record.chroma_planes {
-            quant_table_set_index_count += 1;
+        let mut invalid = None;
+        slice.header.read(
+            coder,
+            &self.record,
+            &mut tracer,
+            &mut |element| {
+                invalid.get_or_insert(element);
+            },
+        )?;
+        if let Some(invalid) = invalid {
+            return Err(invalid.into());
         }
-        if self.record.extra_plane {
-            quant_table_set_index_count += 1;
+
+        self.set_slice_boundaries(slice);
+
+        Ok(())
+    }
+
+    /// Parses the slice layout coded in the header of a keyframe, for
+    /// versions older than 3.
+    ///
+    /// Versions 0 and 1 always have a single slice covering the whole
+    /// frame, which uses the only quantization table set for all planes.
+    fn parse_slice_layout(
+        &mut self,
+        coder: &mut RangeCoder,
+        tracer: &mut Tracer,
+    ) -> Result<()> {
+        let quant_table_set_index_count =
+            self.record.quant_table_set_index_count();
+
+        // The headers of the previous keyframe are reused, so that no
+        // memory is allocated.
+        let slice_headers = &mut self.current_frame.slice_headers;
+
+        if self.record.version < 2 {
+            slice_headers.truncate(1);
+            slice_headers.resize_with(1, Default::default);
+            slice_headers[0].reset();
+            slice_headers[0]
+                .quant_table_set_index
+                .resize(quant_table_set_index_count, 0);
+            return Ok(());
         }
 
-        // 4.5.6. quant_table_set_index
-        self.current_frame.slices[slicenum]
-            .header
-            .quant_table_set_index =
-            vec![0; quant_table_set_index_count as usize];
-        for i in 0..quant_table_set_index_count {
-            self.current_frame.slices[slicenum]
-                .header
-                .quant_table_set_index[i] = coder.ur(&mut slice_state) as u8;
+        // See: 4.2. Parameters
+        let mut state: [u8; CONTEXT_SIZE as usize] =
+            [128; CONTEXT_SIZE as usize];
+
+        let num_h_slices = self.record.num_h_slices_minus1 as u64 + 1;
+        let num_v_slices = self.record.num_v_slices_minus1 as u64 + 1;
+
+        let slice_count =
+            tracer.ur(coder, &mut state, "4.3. slice_count")? as u64;
+        if slice_count == 0 || slice_count > num_h_slices * num_v_slices {
+            return Err(Error::SliceError(format!(
+                "invalid slice count: {}",
+                slice_count
+            )));
         }
 
-        // 4.5.7. picture_structure
-        self.current_frame.slices[slicenum].header.picture_structure =
-            coder.ur(&mut slice_state) as u8;
+        slice_headers.resize_with(slice_count as usize, Default::default);
+        for header in slice_headers.iter_mut() {
+            header.reset();
+            let mut invalid = None;
+            header.read_position(
+                coder,
+                &mut state,
+                &self.record,
+                tracer,
+                &mut |element| {
+                    invalid.get_or_insert(element);
+                },
+            )?;
+            if let Some(invalid) = invalid {
+                return Err(invalid.into());
+            }
+        }
 
-        // It's really weird for slices within the same frame to code
-        // their own SAR values...
-        //
-        // See: * 4.5.8. sar_num
-        //      * 4.5.9. sar_den
-        self.current_frame.slices[slicenum].header.sar_num =
-            coder.ur(&mut slice_state);
-        self.current_frame.slices[slicenum].header.sar_den =
-            coder.ur(&mut slice_state);
+        Ok(())
+    }
 
+    /// Calculates the position and size of a slice, in pixels, from its
+    /// header.
+    fn set_slice_boundaries(&self, slice: &mut Slice) {
         // Calculate bounaries for easy use elsewhere
         //
         // See: * 4.6.3. slice_pixel_height
         //      * 4.6.4. slice_pixel_y
         //      * 4.7.2. slice_pixel_width
         //      * 4.7.3. slice_pixel_x
-        self.current_frame.slices[slicenum].start_x =
-            self.current_frame.slices[slicenum].header.slice_x * self.width
-                / (self.record.num_h_slices_minus1 as u32 + 1);
-        self.current_frame.slices[slicenum].start_y =
-            self.current_frame.slices[slicenum].header.slice_y * self.height
-                / (self.record.num_v_slices_minus1 as u32 + 1);
-        self.current_frame.slices[slicenum].width =
-            ((self.current_frame.slices[slicenum].header.slice_x
-                + self.current_frame.slices[slicenum]
-                    .header
-                    .slice_width_minus1
-                + 1)
-                * self.width
-                / (self.record.num_h_slices_minus1 as u32 + 1))
-                - self.current_frame.slices[slicenum].start_x;
-        self.current_frame.slices[slicenum].height =
-            ((self.current_frame.slices[slicenum].header.slice_y
-                + self.current_frame.slices[slicenum]
-                    .header
-                    .slice_height_minus1
-                + 1)
-                * self.height
-                / (self.record.num_v_slices_minus1 as u32 + 1))
-                - self.current_frame.slices[slicenum].start_y;
+        //
+        // The slice position was checked against the number of slices, so
+        // none of this can go past the frame.
+        let num_h_slices = self.record.num_h_slices_minus1 as u64 + 1;
+        let num_v_slices = self.record.num_v_slices_minus1 as u64 + 1;
+        let slice_x = slice.header.slice_x as u64;
+        let slice_y = slice.header.slice_y as u64;
+        let width = self.width as u64;
+        let height = self.height as u64;
+
+        slice.start_x = (slice_x * width / num_h_slices) as u32;
+        slice.start_y = (slice_y * height / num_v_slices) as u32;
+        slice.width = ((slice_x + slice.header.slice_width_minus1 as u64 + 1)
+            * width
+            / num_h_slices) as u32
+            - slice.start_x;
+        slice.height =
+            ((slice_y + slice.header.slice_height_minus1 as u64 + 1) * height
+                / num_v_slices) as u32
+                - slice.start_y;
     }
 
     /// Line decoding.
@@ -427,26 +1240,28 @@ impl Decoder {
     /// but it needs to be separate because of RGB mode where every line
     /// is done in its entirety instead of per plane.
     ///
-    /// Many could be refactored into being in the context, but I haven't
-    /// got to it yet, so instead, I shall repent once for each function
-    /// argument, twice daily.
+    /// Samples are written to the slice's own line buffer for 'plane',
+    /// which keeps the lines prediction needs, see Slice::lines.
     ///
     /// See: 4.7. Line
     #[allow(clippy::too_many_arguments)]
-    pub fn decode_line(
-        &mut self,
+    fn decode_line(
+        &self,
         coder: &mut RangeCoder,
         golomb_coder: &mut Option<&mut Coder>,
-        slicenum: usize,
-        frame: &mut Frame,
+        slice: &mut Slice,
         width: isize,
         height: isize,
-        stride: isize,
-        offset: isize,
         yy: isize,
-        plane: isize,
-        qt: isize,
-    ) {
+        plane: usize,
+        qt: usize,
+    ) -> Result<()> {
+        // 4.5.11. slice_coding_mode
+        if slice.header.slice_coding_mode == 1 {
+            self.decode_line_pcm(coder, slice, width, yy, plane);
+            return Ok(());
+        }
+
         // Runs are horizontal and thus cannot run more than a line.
         //
         // See: 3.8.2.2.1. Run Length Coding
@@ -454,69 +1269,45 @@ impl Decoder {
             golomb_coder.new_line();
         }
 
+        // 3.8. Coding of the Sample Difference
+        let shift = if self.record.colorspace_type == 1 {
+            self.record.bits_per_raw_sample + 1
+        } else {
+            self.record.bits_per_raw_sample
+        };
+
+        // See also: 3.6. Quantization Table Set Indexes
+        let quant_table = &self.record.quant_tables
+            [slice.header.quant_table_set_index[qt] as usize];
+
+        // Once the buffer is full, the oldest line makes room for this one.
+        let yy = if yy > 2 {
+            slice.lines[plane].copy_within(width as usize.., 0);
+            2
+        } else {
+            yy
+        };
+
         // 4.7.4. sample_difference
         for x in 0..width as usize {
-            // 3.8. Coding of the Sample Difference
-            let shift = if self.record.colorspace_type == 1 {
-                self.record.bits_per_raw_sample + 1
-            } else {
-                self.record.bits_per_raw_sample
-            };
-
             // Derive neighbours
             //
-            // See pred.go for details.
+            // See pred.rs for details.
             #[allow(non_snake_case)]
             #[allow(clippy::many_single_char_names)]
-            let (T, L, t, l, tr, tl) = if self.record.bits_per_raw_sample == 8
-                && self.record.colorspace_type != 1
-            {
-                derive_borders(
-                    &frame.buf[plane as usize][offset as usize..],
-                    x as isize,
-                    yy,
-                    width,
-                    height,
-                    stride,
-                )
-            } else if self.record.bits_per_raw_sample == 16
-                && self.record.colorspace_type == 1
-            {
-                derive_borders32(
-                    &frame.buf32[plane as usize][offset as usize..],
-                    x as isize,
-                    yy,
-                    width,
-                    height,
-                    stride,
-                )
-            } else {
-                derive_borders16(
-                    &frame.buf16[plane as usize][offset as usize..],
-                    x as isize,
-                    yy,
-                    width,
-                    height,
-                    stride,
-                )
-            };
+            let (T, L, t, l, tr, tl) = derive_borders32(
+                &slice.lines[plane],
+                x as isize,
+                yy,
+                width,
+                height,
+                width,
+            );
 
-            // See pred.go for details.
+            // See pred.rs for details.
             //
-            // See also: * 3.4. Context
-            //           * 3.6. Quantization Table Set Indexes
-            let mut context = get_context(
-                &self.record.quant_tables[self.current_frame.slices[slicenum]
-                    .header
-                    .quant_table_set_index[qt as usize]
-                    as usize],
-                T,
-                L,
-                t,
-                l,
-                tr,
-                tl,
-            );
+            // See also: 3.4. Context
+            let mut context = get_context(quant_table, T, L, t, l, tr, tl);
             let sign = if context < 0 {
                 context = -context;
                 true
@@ -527,69 +1318,118 @@ impl Decoder {
             let mut diff = if let Some(ref mut golomb_coder) = golomb_coder {
                 golomb_coder.sg(
                     context,
-                    &mut self.current_frame.slices[slicenum].golomb_state
-                        [qt as usize][context as usize],
+                    &mut slice.golomb_state[qt][context as usize],
                     shift as usize,
-                )
+                )?
             } else {
-                coder.sr(&mut self.current_frame.slices[slicenum].state
-                    [qt as usize][context as usize])
+                coder.sr(&mut slice.state[qt][context as usize])?
             };
 
             // 3.4. Context
             if sign {
-                diff = -diff;
+                diff = diff.wrapping_neg();
             }
 
             // 3.8. Coding of the Sample Difference
-            let mut val = diff;
-            if self.record.colorspace_type == 0
-                && self.record.bits_per_raw_sample == 16
-                && golomb_coder.is_none()
-            {
-                // 3.3. Median Predictor
-                let left16s = if l >= 32768 { l - 65536 } else { l };
-                let top16s = if t >= 32768 { t - 65536 } else { t };
-                let diag16s = if tl >= 32768 { tl - 65536 } else { tl };
+            //
+            // Corrupt streams may code any difference, and only the low
+            // bits of the sum are kept anyway.
+            let signed16 = self.record.colorspace_type == 0
+                && self.record.bits_per_raw_sample == 16;
+            let val = diff.wrapping_add(predict(l, t, tl, signed16) as i32)
+                & ((1 << shift) - 1);
 
-                val += get_median(left16s, top16s, left16s + top16s - diag16s)
-                    as i32;
-            } else {
-                val += get_median(l, t, l + t - tl) as i32;
-            }
+            slice.lines[plane][(yy as usize * width as usize) + x] =
+                val as u32;
+        }
 
-            val &= (1 << shift) - 1;
+        Ok(())
+    }
 
-            if self.record.bits_per_raw_sample == 8
-                && self.record.colorspace_type != 1
-            {
-                frame.buf[plane as usize]
-                    [offset as usize + (yy as usize * stride as usize) + x] =
-                    val as u8;
-            } else if self.record.bits_per_raw_sample == 16
-                && self.record.colorspace_type == 1
-            {
-                frame.buf32[plane as usize]
-                    [offset as usize + (yy as usize * stride as usize) + x] =
-                    val as u32;
-            } else {
-                frame.buf16[plane as usize]
-                    [offset as usize + (yy as usize * stride as usize) + x] =
-                    val as u16;
+    /// Decodes a line of raw samples, as coded by slices in PCM mode.
+    ///
+    /// Each bit is range coded with a fresh state, even in Golomb-Rice
+    /// mode, and no transform is applied to RGB samples.
+    ///
+    /// See: 4.5.11. slice_coding_mode
+    fn decode_line_pcm(
+        &self,
+        coder: &mut RangeCoder,
+        slice: &mut Slice,
+        width: isize,
+        yy: isize,
+        plane: usize,
+    ) {
+        let width = width as usize;
+        let row = (yy as usize).min(2);
+        let line = &mut slice.lines[plane][row * width..(row + 1) * width];
+        for sample in line.iter_mut() {
+            let mut val = 0;
+            for _ in 0..self.record.bits_per_raw_sample {
+                let mut state = 128;
+                val = (val << 1) | coder.get(&mut state) as u32;
             }
+            *sample = val;
+        }
+    }
+
+    /// Returns the width, height, horizontal and vertical position of a
+    /// plane of a slice, and the width of that plane in the frame.
+    ///
+    /// Chroma planes of a slice are sized by rounding the size of the
+    /// slice up, and placed by rounding its position down, as FFmpeg does.
+    /// Slices which do not start on a multiple of the subsampling factor
+    /// thus code the samples they share with the previous slice again.
+    ///
+    /// See: * 4.6.2. plane_pixel_height
+    ///      * 4.7.1. plane_pixel_width
+    fn plane_geometry(
+        &self,
+        slice: &Slice,
+        plane: usize,
+    ) -> (isize, isize, isize, isize, isize) {
+        let (h_shift, v_shift) = self.plane_shifts(plane);
+        (
+            ceil_rshift(slice.width, h_shift) as isize,
+            ceil_rshift(slice.height, v_shift) as isize,
+            (slice.start_x >> h_shift) as isize,
+            (slice.start_y >> v_shift) as isize,
+            ceil_rshift(self.width, h_shift) as isize,
+        )
+    }
+
+    /// Returns the horizontal and vertical subsampling of plane 'plane'.
+    fn plane_shifts(&self, plane: usize) -> (u8, u8) {
+        let chroma_planes = if self.record.chroma_planes { 2 } else { 0 };
+
+        if self.record.colorspace_type == 1
+            || plane == 0
+            || plane == 1 + chroma_planes
+        {
+            (0, 0)
+        } else {
+            (
+                self.record.log2_h_chroma_subsample,
+                self.record.log2_v_chroma_subsample,
+            )
         }
     }
 
     /// Decoding happens here.
     ///
+    /// Each line is written into 'rows' as soon as it is decoded,
+    /// converted from JPEG2000-RCT to RGB if needed.
+    ///
     /// See: * 4.6. Slice Content
-    pub fn decode_slice_content(
-        &mut self,
+    ///      * 3.7.1. YCbCr
+    ///      * 3.7.2. RGB
+    fn decode_slice_content<T: Sample>(
+        &self,
         coder: &mut RangeCoder,
         golomb_coder: &mut Option<&mut Coder>,
-        slicenum: usize,
-        frame: &mut Frame,
-    ) {
+        slice: &mut Slice,
+        rows: &mut SliceRows<'_, '_, T>,
+    ) -> Result<()> {
         // 4.6.1. primary_color_count
         let mut primary_color_count = 1;
         let mut chroma_planes = 0;
@@ -601,6 +1441,14 @@ impl Decoder {
             primary_color_count += 1;
         }
 
+        // The buffers of the previous frame are reused, and every sample
+        // gets overwritten before prediction reads it.
+        slice.lines.resize_with(primary_color_count, Vec::new);
+        for p in 0..primary_color_count {
+            let (width, _, _, _, _) = self.plane_geometry(slice, p);
+            slice.lines[p].resize(3 * width as usize, 0);
+        }
+
         if self.record.colorspace_type != 1 {
             // YCbCr Mode
             //
@@ -608,50 +1456,17 @@ impl Decoder {
             //
             // See: 3.7.1. YCbCr
             for p in 0..primary_color_count {
-                // See: * 4.6.2. plane_pixel_height
-                //      * 4.7.1. plane_pixel_width
-                let (
-                    plane_pixel_height,
-                    plane_pixel_width,
-                    plane_pixel_stride,
-                    start_x,
-                    start_y,
-                    quant_table,
-                ) = if p == 0 || p == 1 + chroma_planes {
-                    let quant_table = if p == 0 { 0 } else { chroma_planes };
-                    (
-                        self.current_frame.slices[slicenum].height as isize,
-                        self.current_frame.slices[slicenum].width as isize,
-                        self.width as isize,
-                        self.current_frame.slices[slicenum].start_x as isize,
-                        self.current_frame.slices[slicenum].start_y as isize,
-                        quant_table,
-                    )
+                let (plane_pixel_width, plane_pixel_height, _, _, _) =
+                    self.plane_geometry(slice, p);
+                // Lines are cut to the region of the slice by write_line.
+                let (_, _, _, region_height) = self.plane_region(slice, p);
+                // The alpha plane always uses the last plane context.
+                let quant_table = if p == 0 {
+                    0
+                } else if p == 1 + chroma_planes {
+                    self.record.quant_table_set_index_count() - 1
                 } else {
-                    // This is, of course, silly, but I want to do it "by the spec".
-                    (
-                        (self.current_frame.slices[slicenum].height as f64
-                            / (1 << self.record.log2_v_chroma_subsample)
-                                as f64)
-                            .ceil() as isize,
-                        (self.current_frame.slices[slicenum].width as f64
-                            / (1 << self.record.log2_h_chroma_subsample)
-                                as f64)
-                            .ceil() as isize,
-                        (self.width as f64
-                            / (1 << self.record.log2_h_chroma_subsample)
-                                as f64)
-                            .ceil() as isize,
-                        (self.current_frame.slices[slicenum].start_x as f64
-                            / ((1 << self.record.log2_v_chroma_subsample)
-                                as f64))
-                            .ceil() as isize,
-                        (self.current_frame.slices[slicenum].start_y as f64
-                            / ((1 << self.record.log2_h_chroma_subsample)
-                                as f64))
-                            .ceil() as isize,
-                        1,
-                    )
+                    1
                 };
 
                 // 3.8.2.2.1. Run Length Coding
@@ -660,20 +1475,26 @@ impl Decoder {
                 }
 
                 for y in 0..plane_pixel_height {
-                    let offset = start_y * plane_pixel_stride + start_x;
                     self.decode_line(
                         coder,
                         golomb_coder,
-                        slicenum,
-                        frame,
+                        slice,
                         plane_pixel_width,
                         plane_pixel_height,
-                        plane_pixel_stride,
-                        offset,
                         y,
                         p,
                         quant_table,
-                    );
+                    )?;
+                    if (y as usize) < region_height {
+                        write_line(
+                            rows.row(p, y as usize),
+                            last_line(
+                                &slice.lines[p],
+                                plane_pixel_width as usize,
+                                y as usize,
+                            ),
+                        );
+                    }
                 }
             }
         } else {
@@ -683,240 +1504,491 @@ impl Decoder {
             //
             // See: 3.7.2. RGB
             if let Some(ref mut golomb_coder) = golomb_coder {
-                golomb_coder.new_plane(
-                    self.current_frame.slices[slicenum].width as u32,
-                );
+                golomb_coder.new_plane(slice.width);
             }
 
-            let offset = (self.current_frame.slices[slicenum].start_y
-                * self.width
-                + self.current_frame.slices[slicenum].start_x)
-                as isize;
-            for y in 0..self.current_frame.slices[slicenum].height as isize {
+            let width = slice.width as isize;
+            let height = slice.height as isize;
+            for y in 0..height {
                 // RGB *must* have chroma planes, so this is safe.
                 self.decode_line(
                     coder,
                     golomb_coder,
-                    //self.current_frame.slices[slicenum],
-                    slicenum,
-                    frame,
-                    self.current_frame.slices[slicenum].width as isize,
-                    self.current_frame.slices[slicenum].height as isize,
-                    self.width as isize,
-                    offset,
+                    slice,
+                    width,
+                    height,
                     y,
                     0,
                     0,
-                );
+                )?;
                 self.decode_line(
                     coder,
                     golomb_coder,
-                    //self.current_frame.slices[slicenum],
-                    slicenum,
-                    frame,
-                    self.current_frame.slices[slicenum].width as isize,
-                    self.current_frame.slices[slicenum].height as isize,
-                    self.width as isize,
-                    offset,
+                    slice,
+                    width,
+                    height,
                     y,
                     1,
                     1,
-                );
+                )?;
                 self.decode_line(
                     coder,
                     golomb_coder,
-                    //self.current_frame.slices[slicenum],
-                    slicenum,
-                    frame,
-                    self.current_frame.slices[slicenum].width as isize,
-                    self.current_frame.slices[slicenum].height as isize,
-                    self.width as isize,
-                    offset,
+                    slice,
+                    width,
+                    height,
                     y,
                     2,
                     1,
-                );
+                )?;
                 if self.record.extra_plane {
                     self.decode_line(
                         coder,
                         golomb_coder,
-                        //self.current_frame.slices[slicenum],
-                        slicenum,
-                        frame,
-                        self.current_frame.slices[slicenum].width as isize,
-                        self.current_frame.slices[slicenum].height as isize,
-                        self.width as isize,
-                        offset,
+                        slice,
+                        width,
+                        height,
                         y,
                         3,
                         2,
-                    );
+                    )?;
                 }
+                self.write_rgb_line(slice, rows, y as usize);
             }
+        }
 
-            // Convert to RGB all at once, cache locality be damned.
-            if self.record.bits_per_raw_sample == 8 {
-                rct8(
-                    &mut frame.buf,
-                    &frame.buf16,
-                    self.current_frame.slices[slicenum].width as isize,
-                    self.current_frame.slices[slicenum].height as isize,
-                    self.width as isize,
-                    offset,
-                );
-            } else if self.record.bits_per_raw_sample >= 9
-                && self.record.bits_per_raw_sample <= 15
-                && !self.record.extra_plane
+        Ok(())
+    }
+
+    /// Writes line 'y' of an RGB slice into 'rows', converting it from
+    /// JPEG2000-RCT.
+    ///
+    /// See: 3.7.2. RGB
+    fn write_rgb_line<T: Sample>(
+        &self,
+        slice: &Slice,
+        rows: &mut SliceRows<'_, '_, T>,
+        y: usize,
+    ) {
+        let width = slice.width as usize;
+        let lines = &slice.lines;
+        let [first, second, red_row, alpha_row] = rows.rows(y);
+
+        // RGB slices coded in PCM mode hold plain samples, but still in the
+        // plane order of the JPEG2000-RCT output.
+        //
+        // See: 4.5.11. slice_coding_mode
+        let (green_row, blue_row) = if self.swaps_rgb_planes() {
+            (second, first)
+        } else {
+            (first, second)
+        };
+        if slice.header.slice_coding_mode == 1 {
+            write_line(green_row, last_line(&lines[0], width, y));
+            write_line(blue_row, last_line(&lines[1], width, y));
+            write_line(red_row, last_line(&lines[2], width, y));
+        } else {
+            let bits = self.record.bits_per_raw_sample as usize;
+            let by_coef = slice.header.slice_rct_by_coef as i32;
+            let ry_coef = slice.header.slice_rct_ry_coef as i32;
+            let coded = last_line(&lines[0], width, y)
+                .iter()
+                .zip(last_line(&lines[1], width, y))
+                .zip(last_line(&lines[2], width, y));
+            let dst = green_row
+                .iter_mut()
+                .zip(blue_row.iter_mut())
+                .zip(red_row.iter_mut());
+            for (((green, blue), red), ((&coded_y, &cb), &cr)) in
+                dst.zip(coded)
             {
-                // See: 3.7.2. RGB
-                rct_mid(
-                    &mut frame.buf16,
-                    self.current_frame.slices[slicenum].width as isize,
-                    self.current_frame.slices[slicenum].height as isize,
-                    self.width as isize,
-                    offset,
-                    self.record.bits_per_raw_sample as usize,
-                );
-            } else {
-                rct16(
-                    &mut frame.buf16,
-                    &frame.buf32,
-                    self.current_frame.slices[slicenum].width as isize,
-                    self.current_frame.slices[slicenum].height as isize,
-                    self.width as isize,
-                    offset,
-                );
+                let (g, b, r) = rct(coded_y, cb, cr, bits, by_coef, ry_coef);
+                *green = T::from_u32(g);
+                *blue = T::from_u32(b);
+                *red = T::from_u32(r);
             }
         }
+        if self.record.extra_plane {
+            write_line(alpha_row, last_line(&lines[3], width, y));
+        }
+    }
+
+    /// Whether the first two RGB planes are swapped, which FFmpeg does for
+    /// 9 to 15 bit streams without an alpha plane.
+    fn swaps_rgb_planes(&self) -> bool {
+        self.record.bits_per_raw_sample >= 9
+            && self.record.bits_per_raw_sample <= 15
+            && !self.record.extra_plane
     }
 
     /// Resets the range coder and Golomb-Rice coder states.
-    pub fn reset_slice_states(&mut self, slicenum: usize) {
+    ///
+    /// Each plane context has its own states, initialized from the
+    /// quantization table set it uses, so this must be called after the
+    /// slice header is known.
+    ///
+    /// The states are overwritten in place, so no memory is allocated as
+    /// long as the quantization table sets keep their sizes.
+    pub fn reset_slice_states(&self, slice: &mut Slice) {
+        let quant_table_set_index = &slice.header.quant_table_set_index;
+
         // Range coder states
-        self.current_frame.slices[slicenum].state =
-            vec![Vec::new(); self.initial_states.len()];
-        for i in 0..self.initial_states.len() {
-            self.current_frame.slices[slicenum].state[i] =
-                vec![Vec::new(); self.initial_states[i].len()];
-            for j in 0..self.initial_states[i].len() {
-                self.current_frame.slices[slicenum].state[i][j] =
-                    vec![0; self.initial_states[i][j].len()];
-                self.current_frame.slices[slicenum].state[i][j]
-                    .copy_from_slice(&self.initial_states[i][j]);
-            }
+        slice
+            .state
+            .resize_with(quant_table_set_index.len(), Vec::new);
+        for (states, &i) in slice.state.iter_mut().zip(quant_table_set_index) {
+            states.clone_from(&self.initial_states[i as usize]);
         }
 
         // Golomb-Rice Code states
         if self.record.coder_type == 0 {
-            self.current_frame.slices[slicenum].golomb_state =
-                vec![Vec::new(); self.record.quant_table_set_count as usize];
-            for i in 0..self.current_frame.slices[slicenum].golomb_state.len()
+            slice
+                .golomb_state
+                .resize_with(quant_table_set_index.len(), Vec::new);
+            for (states, &i) in
+                slice.golomb_state.iter_mut().zip(quant_table_set_index)
             {
-                self.current_frame.slices[slicenum].golomb_state[i] = vec![
-                    Default::default();
-                    self.record.context_count[i]
-                        as usize
-                ];
-                for j in 0..self.current_frame.slices[slicenum].golomb_state[i]
-                    .len()
-                {
-                    self.current_frame.slices[slicenum].golomb_state[i][j] =
-                        State::new();
-                }
+                states.clear();
+                states.resize(
+                    self.record.context_count[i as usize] as usize,
+                    State::new(),
+                );
             }
         }
     }
 
-    pub fn decode_slice(
-        &mut self,
-        buf: &[u8],
-        slicenum: isize,
-        frame: &mut Frame,
-    ) -> Result<()> {
-        // Before we do anything, let's try and check the integrity
-        //
-        // See: * 4.8.2. error_status
-        //      * 4.8.3. slice_crc_parity
-        if self.record.ec == 1 {
-            if self.current_frame.slice_info[slicenum as usize].error_status
-                != 0
-            {
-                return Err(Error::SliceError(format!(
-                    "error_status is non-zero: {}",
-                    self.current_frame.slice_info[slicenum as usize]
-                        .error_status
-                )));
-            }
+    /// Whether the states of a slice, carried over from the previous frame,
+    /// match the quantization table sets in its header.
+    fn slice_states_match(&self, slice: &Slice) -> bool {
+        let quant_table_set_index = &slice.header.quant_table_set_index;
+        slice.state.len() == quant_table_set_index.len()
+            && slice.state.iter().zip(quant_table_set_index).all(
+                |(states, &i)| {
+                    states.len()
+                        == self.record.context_count[i as usize] as usize
+                },
+            )
+    }
 
-            let slice_buf_first = &buf[self.current_frame.slice_info
-                [slicenum as usize]
-                .pos as usize..];
-            let slice_buf_end =
-                &slice_buf_first[..self.current_frame.slice_info
-                    [slicenum as usize]
-                    .size as usize
-                    + 8]; // 8 bytes for footer size
-            if crc32_mpeg2(&slice_buf_end) != 0 {
-                return Err(Error::InvalidInputData(
-                    "CRC mismatch".to_owned(),
-                ));
-            }
+    /// Passes the elements of the footer of slice 'i' on to its trace,
+    /// with their positions from the start of the slice.
+    ///
+    /// See: 4.8. Slice Footer
+    fn trace_slice_footer(
+        &self,
+        buf: &[u8],
+        i: usize,
+        info: &SliceInfo,
+        slice: &mut Slice,
+    ) {
+        // Version 2 has no footer after the first slice, and versions 0
+        // and 1 none at all.
+        if self.record.version < 2 || (self.record.version == 2 && i == 0) {
+            return;
         }
 
-        // If this is a keyframe, refresh states.
-        //
-        // See: * 3.8.1.3. Initial Values for the Context Model
-        //      * 3.8.2.4. Initial Values for the VLC context state
-        if self.current_frame.keyframe {
-            self.reset_slice_states(slicenum as usize);
+        let mut tracer =
+            Tracer::new(Some(&mut slice.trace), self.slice_location(i));
+        let size = info.size as usize;
+        tracer.element("4.8.1. slice_size", size as i64, size);
+        if self.record.version >= 3 && self.record.ec != 0 {
+            let footer = &buf[info.pos as usize + size..];
+            tracer.element(
+                "4.8.2. error_status",
+                info.error_status as i64,
+                size + 3,
+            );
+            tracer.element(
+                "4.8.3. slice_crc_parity",
+                u32::from_be_bytes([
+                    footer[4], footer[5], footer[6], footer[7],
+                ]) as i64,
+                size + 4,
+            );
         }
+    }
 
-        let mut coder = RangeCoder::new(
-            &buf[self.current_frame.slice_info[slicenum as usize].pos
-                as usize..],
-        );
-
-        // 4. Bitstream
-        let mut state: [u8; CONTEXT_SIZE as usize] =
-            [128; CONTEXT_SIZE as usize];
+    /// Checks the integrity of a slice, if the stream allows it.
+    ///
+    /// See: * 4.8.2. error_status
+    ///      * 4.8.3. slice_crc_parity
+    fn slice_damage(
+        &self,
+        buf: &[u8],
+        info: &SliceInfo,
+    ) -> Option<SliceDamage> {
+        if self.record.ec == 1 {
+            if info.error_status != 0 {
+                return Some(SliceDamage::ErrorStatus(info.error_status));
+            }
 
-        // Skip keyframe bit on slice 0
-        if slicenum == 0 {
-            coder.br(&mut state);
+            let slice_buf_first = &buf[info.pos as usize..];
+            let slice_buf_end = &slice_buf_first[..info.size as usize + 8]; // 8 bytes for footer size
+            if crc32_mpeg2(slice_buf_end) != 0 {
+                return Some(SliceDamage::CrcMismatch);
+            }
         }
 
+        None
+    }
+
+    /// Starts decoding a slice whose integrity is checked already, see
+    /// start_slice.
+    fn start_slice_unchecked<'a>(
+        &self,
+        buf: &'a [u8],
+        mut coder: RangeCoder<'a>,
+        info: &SliceInfo,
+        slicenum: usize,
+        slice: &mut Slice,
+    ) -> Result<SliceCoders<'a>> {
         if self.record.coder_type == 2 {
             // Custom state transition table
             coder.set_table(&self.state_transition);
         }
 
-        self.parse_slice_header(&mut coder, slicenum as usize);
+        // Only version 3 and later code a slice header, older versions
+        // code the slice layout in the keyframe header.
+        //
+        // See: 4.4. Slice
+        if self.record.version >= 3 {
+            self.parse_slice_header(&mut coder, slicenum, slice)?;
+        } else {
+            self.set_slice_boundaries(slice);
+        }
+
+        // If this is a keyframe, or the slice asks for it, refresh states.
+        //
+        // See: * 3.8.1.3. Initial Values for the Context Model
+        //      * 3.8.2.4. Initial Values for the VLC context state
+        //      * 4.5.10. reset_contexts
+        if self.current_frame.keyframe || slice.header.reset_contexts {
+            self.reset_slice_states(slice);
+            slice.states_damaged = false;
+        } else if slice.states_damaged {
+            return Err(Error::SliceError(
+                "the states were lost with a damaged slice".to_owned(),
+            ));
+        } else if !self.slice_states_match(slice) {
+            // The states carried over from the previous frame must fit the
+            // quantization table sets this slice uses.
+            return Err(Error::SliceError(
+                "quant_table_set_index changed in an inter frame".to_owned(),
+            ));
+        }
 
-        let mut golomb_coder = if self.record.coder_type == 0 {
+        let golomb_coder = if self.record.coder_type == 0 {
             // We're switching to Golomb-Rice mode now so we need the bitstream
-            // position.
+            // position. Older streams did not terminate the range coder.
             //
             // See: 3.8.1.1.1. Termination
-            coder.sentinal_end();
-            let offset = coder.get_pos() - 1;
-            Some(Coder::new(
-                &buf[self.current_frame.slice_info[slicenum as usize].pos
-                    as usize
-                    + offset as usize..],
-            ))
+            if self.record.version > 3
+                || (self.record.version == 3 && self.record.micro_version > 1)
+            {
+                coder.sentinal_end();
+            }
+            // Version 2 slices other than the top-left one carry no range
+            // coded data at all.
+            let offset = if self.record.version < 3
+                && (slice.start_x != 0 || slice.start_y != 0)
+            {
+                0
+            } else {
+                coder.get_pos() - 1
+            };
+            if offset < 0 || offset > info.size as isize {
+                return Err(Error::SliceError(
+                    "range coded data runs past the end of the slice"
+                        .to_owned(),
+                ));
+            }
+            let start = info.pos as usize + offset as usize;
+            let end = info.pos as usize + info.size as usize;
+            Some(Coder::new(&buf[start..end]))
         } else {
             None
         };
 
-        // Don't worry, I fully understand how non-idiomatic and
-        // ugly passing both c and gc is.
-        self.decode_slice_content(
-            &mut coder,
-            &mut golomb_coder.as_mut(),
-            slicenum as usize,
-            frame,
-        );
+        Ok((coder, golomb_coder))
+    }
+}
 
-        Ok(())
+/// Divides 'value' by 2^'shift', rounding up.
+fn ceil_rshift(value: u32, shift: u8) -> u32 {
+    ((value as u64 + (1 << shift) - 1) >> shift) as u32
+}
+
+/// Area of a plane, in samples: horizontal and vertical position, width
+/// and height.
+type Region = (usize, usize, usize, usize);
+
+/// Coders of a slice, positioned at the start of its content: the range
+/// coder, and the Golomb-Rice coder if the stream uses it.
+type SliceCoders<'a> = (RangeCoder<'a>, Option<Coder<'a>>);
+
+/// SliceRows is the region of each plane of the frame that a slice is
+/// written to.
+enum SliceRows<'a, 'b, T> {
+    /// The planes of the frame, and the region of the slice in each one,
+    /// for slices decoded one after the other.
+    Planes(&'a mut [PlaneMut<'b, T>], [Region; 4]),
+    /// The rows of the region of the slice in each plane, split off the
+    /// planes so that slices can be decoded in parallel.
+    Split(Vec<Vec<&'a mut [T]>>),
+}
+
+impl<T> SliceRows<'_, '_, T> {
+    /// Returns the number of planes of the frame.
+    fn num_planes(&self) -> usize {
+        match self {
+            SliceRows::Planes(planes, _) => planes.len(),
+            SliceRows::Split(rows) => rows.len(),
+        }
+    }
+
+    /// Returns row 'y' of the region of plane 'p'.
+    fn row(&mut self, p: usize, y: usize) -> &mut [T] {
+        match self {
+            SliceRows::Planes(planes, regions) => {
+                let (x, region_y, width, _) = regions[p];
+                let plane = &mut planes[p];
+                let pos = (region_y + y) * plane.stride + x;
+                &mut plane.data[pos..pos + width]
+            }
+            SliceRows::Split(rows) => &mut *rows[p][y],
+        }
+    }
+
+    /// Returns row 'y' of the region of every plane, for RGB slices, whose
+    /// planes are all the same size. Missing planes get empty rows.
+    fn rows(&mut self, y: usize) -> [&mut [T]; 4] {
+        let mut rows: [&mut [T]; 4] = [&mut [], &mut [], &mut [], &mut []];
+        match self {
+            SliceRows::Planes(planes, regions) => {
+                for ((row, plane), &(x, region_y, width, _)) in
+                    rows.iter_mut().zip(planes.iter_mut()).zip(&*regions)
+                {
+                    let pos = (region_y + y) * plane.stride + x;
+                    *row = &mut plane.data[pos..pos + width];
+                }
+            }
+            SliceRows::Split(split) => {
+                for (row, plane_rows) in rows.iter_mut().zip(split.iter_mut())
+                {
+                    *row = &mut *plane_rows[y];
+                }
+            }
+        }
+        rows
+    }
+}
+
+/// Returns line 'y' of a plane of a slice, of 'width' samples, from the
+/// last lines kept in 'lines', see Slice::lines.
+fn last_line(lines: &[u32], width: usize, y: usize) -> &[u32] {
+    let row = y.min(2);
+    &lines[row * width..(row + 1) * width]
+}
+
+/// Writes a line of decoded samples into 'dst'.
+fn write_line<T: Sample>(dst: &mut [T], src: &[u32]) {
+    for (dst, &val) in dst.iter_mut().zip(src) {
+        *dst = T::from_u32(val);
+    }
+}
+
+/// Tells which slice an error comes from.
+fn slice_failed(i: usize, err: Error) -> Error {
+    Error::SliceError(format!("slice {} failed: {}", i, err))
+}
+
+/// Sample is a type of sample a frame can hold.
+trait Sample: Copy + Send + Sync {
+    fn from_u32(value: u32) -> Self;
+    fn to_u32(self) -> u32;
+}
+
+impl Sample for u8 {
+    fn from_u32(value: u32) -> Self {
+        value as u8
+    }
+
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+}
+
+impl Sample for u16 {
+    fn from_u32(value: u32) -> Self {
+        value as u16
     }
+
+    fn to_u32(self) -> u32 {
+        self as u32
+    }
+}
+
+/// Sets every sample of a region of a plane to 'value'.
+fn fill_region<T: Sample>(
+    plane: &mut PlaneMut<'_, T>,
+    region: Region,
+    value: T,
+) {
+    let (x, y, width, height) = region;
+    for row in y..y + height {
+        let pos = row * plane.stride + x;
+        plane.data[pos..pos + width].fill(value);
+    }
+}
+
+/// Fills a region of a plane by linear interpolation between the rows
+/// above and below it, or else between the columns on its left and right.
+/// Only rows and columns wholly 'available' are used, and when there are
+/// none, the region is set to 'fallback'.
+fn interpolate_region<T: Sample>(
+    plane: &mut PlaneMut<'_, T>,
+    region: Region,
+    (plane_width, plane_height): (usize, usize),
+    available: &dyn Fn(usize, usize) -> bool,
+    fallback: T,
+) {
+    let (x, y, width, height) = region;
+    let stride = plane.stride;
+    let data = &mut *plane.data;
+    let get = |data: &[T], x: usize, y: usize| data[y * stride + x].to_u32();
+    // Linear interpolation of the 'i'th of 'n' samples from 'a' to 'b'.
+    let lerp = |a: u32, b: u32, i: usize, n: usize| {
+        let (a, b) = (a as i64, b as i64);
+        T::from_u32((a + (b - a) * (i as i64 + 1) / (n as i64 + 1)) as u32)
+    };
+
+    let above = y > 0 && (x..x + width).all(|c| available(c, y - 1));
+    let below = y + height < plane_height
+        && (x..x + width).all(|c| available(c, y + height));
+    if above || below {
+        for c in x..x + width {
+            let a = get(data, c, if above { y - 1 } else { y + height });
+            let b = if below { get(data, c, y + height) } else { a };
+            for r in 0..height {
+                data[(y + r) * stride + c] = lerp(a, b, r, height);
+            }
+        }
+        return;
+    }
+
+    let left = x > 0 && (y..y + height).all(|r| available(x - 1, r));
+    let right = x + width < plane_width
+        && (y..y + height).all(|r| available(x + width, r));
+    if left || right {
+        for r in y..y + height {
+            let a = get(data, if left { x - 1 } else { x + width }, r);
+            let b = if right { get(data, x + width, r) } else { a };
+            for c in 0..width {
+                data[r * stride + x + c] = lerp(a, b, c, width);
+            }
+        }
+        return;
+    }
+
+    fill_region(plane, region, fallback);
 }
@@ -0,0 +1,521 @@
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+
+use crate::constants::CONTEXT_SIZE;
+use crate::crc32mpeg2::crc32_mpeg2_parity;
+use crate::error::{Error, Result};
+use crate::frame::PictureStructure;
+use crate::pred::{derive_borders32, get_context, get_median};
+use crate::range::RangeEncoder;
+use crate::record::ConfigRecord;
+
+/// Run lengths of the quantization tables FFmpeg uses for 8 bit samples,
+/// as coded in the configuration record.
+///
+/// The first set has 11 levels for the three nearest differences, the
+/// second one adds 5 levels for the farther ones.
+///
+/// See: 4.9. Quantization Table Set
+const QUANT11: &[usize] = &[1, 1, 3, 7, 23, 93];
+const QUANT5: &[usize] = &[1, 3, 124];
+const UNUSED: &[usize] = &[128];
+const QUANT_TABLE_SETS: [[&[usize]; 5]; 2] = [
+    [QUANT11, QUANT11, QUANT11, UNUSED, UNUSED],
+    [QUANT11, QUANT11, QUANT5, QUANT5, QUANT5],
+];
+
+/// Parameters of the frames given to an Encoder.
+///
+/// Frames are in planar YCbCr, with 8 bits per sample.
+#[derive(Clone, Debug)]
+pub struct EncoderConfig {
+    /// Width of the frames, in pixels.
+    pub width: u32,
+    /// Height of the frames, in pixels.
+    pub height: u32,
+    /// Whether or not chroma planes are present.
+    pub chroma_planes: bool,
+    /// The log2 horizontal chroma subsampling value.
+    pub log2_h_chroma_subsample: u8,
+    /// The log2 vertical chroma subsampling value.
+    pub log2_v_chroma_subsample: u8,
+    /// Whether or not an alpha plane is present.
+    pub extra_plane: bool,
+    /// Number of slices across the frame.
+    pub num_h_slices: u32,
+    /// Number of slices down the frame.
+    pub num_v_slices: u32,
+    /// Quantization table set used by every plane: 0 for the smaller
+    /// contexts of the first set, 1 for the larger ones of the second.
+    pub context_model: u8,
+    /// Whether frames are progressive or interlaced, and in which field
+    /// order.
+    pub picture_structure: PictureStructure,
+    /// Sample aspect ratio of the frames, if known.
+    pub sample_aspect_ratio: Option<(u32, u32)>,
+    /// Whether or not slice footers carry an error_status and a CRC, so
+    /// that damaged slices can be detected.
+    pub ec: bool,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            width: 0,
+            height: 0,
+            chroma_planes: true,
+            log2_h_chroma_subsample: 1,
+            log2_v_chroma_subsample: 1,
+            extra_plane: false,
+            num_h_slices: 2,
+            num_v_slices: 2,
+            context_model: 0,
+            picture_structure: PictureStructure::Progressive,
+            sample_aspect_ratio: None,
+            ec: true,
+        }
+    }
+}
+
+/// Encoder is a FFV1 encoder instance.
+///
+/// It produces FFV1 version 3 intra frames, range coded with the default
+/// state transition table, with a CRC protecting each slice unless 'ec' is
+/// unset.
+pub struct Encoder {
+    config: EncoderConfig,
+    record: ConfigRecord,
+    record_buf: Vec<u8>,
+    pool: Option<ThreadPool>,
+}
+
+impl Encoder {
+    /// Creates a new FFV1 encoder instance.
+    pub fn new(config: EncoderConfig) -> Result<Self> {
+        if config.width == 0 || config.height == 0 {
+            return Err(Error::InvalidConfiguration(format!(
+                "invalid dimensions: {}x{}",
+                config.width, config.height
+            )));
+        }
+        if config.log2_h_chroma_subsample > 2
+            || config.log2_v_chroma_subsample > 2
+        {
+            return Err(Error::InvalidConfiguration(format!(
+                "unsupported chroma subsampling: {} and {}",
+                config.log2_h_chroma_subsample, config.log2_v_chroma_subsample
+            )));
+        }
+        // Every slice must hold at least one pixel.
+        if config.num_h_slices == 0
+            || config.num_h_slices > config.width.min(256)
+            || config.num_v_slices == 0
+            || config.num_v_slices > config.height.min(256)
+        {
+            return Err(Error::InvalidConfiguration(format!(
+                "invalid number of slices: {}x{}",
+                config.num_h_slices, config.num_v_slices
+            )));
+        }
+        // Chroma planes of slices start at rounded down positions, so the
+        // last slice of a row or column may stop short of the edge of the
+        // plane.
+        //
+        // See: 4.7.1. plane_pixel_width
+        let reaches_edge = |size: u32, slices: u32, shift: u8| {
+            let start =
+                (size as u64 * (slices as u64 - 1) / slices as u64) as u32;
+            (start >> shift) + ceil_rshift(size - start, shift)
+                == ceil_rshift(size, shift)
+        };
+        if config.chroma_planes
+            && !(reaches_edge(
+                config.width,
+                config.num_h_slices,
+                config.log2_h_chroma_subsample,
+            ) && reaches_edge(
+                config.height,
+                config.num_v_slices,
+                config.log2_v_chroma_subsample,
+            ))
+        {
+            return Err(Error::InvalidConfiguration(format!(
+                "{}x{} slices leave chroma samples of a {}x{} frame uncoded",
+                config.num_h_slices,
+                config.num_v_slices,
+                config.width,
+                config.height
+            )));
+        }
+        if config.context_model as usize >= QUANT_TABLE_SETS.len() {
+            return Err(Error::InvalidConfiguration(format!(
+                "invalid context_model: {}",
+                config.context_model
+            )));
+        }
+
+        let record = Self::config_record_for(&config)?;
+        let record_buf = record.write_config_record()?;
+
+        Ok(Encoder {
+            config,
+            record,
+            record_buf,
+            pool: None,
+        })
+    }
+
+    /// Returns the configuration record to store in the codec private
+    /// data of the container, and to give to Decoder::new.
+    ///
+    /// See: 4.2. Configuration Record
+    pub fn config_record(&self) -> &[u8] {
+        &self.record_buf
+    }
+
+    /// Sets the number of threads used to encode the slices of a frame.
+    ///
+    /// By default, and when 'threads' is 0, slices are encoded on the
+    /// global rayon thread pool, which has one thread per logical CPU.
+    pub fn set_threads(&mut self, threads: usize) -> Result<()> {
+        if threads == 0 {
+            self.pool = None;
+            return Ok(());
+        }
+
+        let pool = match ThreadPoolBuilder::new().num_threads(threads).build()
+        {
+            Ok(pool) => pool,
+            Err(err) => {
+                return Err(Error::InvalidConfiguration(format!(
+                    "cannot create a pool of {} threads: {}",
+                    threads, err
+                )))
+            }
+        };
+        self.pool = Some(pool);
+
+        Ok(())
+    }
+
+    /// Builds the configuration record.
+    ///
+    /// See: 4.2. Configuration Record
+    fn config_record_for(config: &EncoderConfig) -> Result<ConfigRecord> {
+        let mut record = ConfigRecord {
+            version: 3,
+            micro_version: 4,
+            coder_type: 1,
+            colorspace_type: 0,
+            bits_per_raw_sample: 8,
+            chroma_planes: config.chroma_planes,
+            log2_h_chroma_subsample: config.log2_h_chroma_subsample,
+            log2_v_chroma_subsample: config.log2_v_chroma_subsample,
+            extra_plane: config.extra_plane,
+            num_h_slices_minus1: (config.num_h_slices - 1) as u8,
+            num_v_slices_minus1: (config.num_v_slices - 1) as u8,
+            quant_table_set_count: QUANT_TABLE_SETS.len() as u8,
+            ec: config.ec as u8,
+            intra: 1,
+            ..Default::default()
+        };
+        for (i, runs) in QUANT_TABLE_SETS.iter().enumerate() {
+            record.set_quant_table_set(i, runs)?;
+        }
+
+        Ok(record)
+    }
+
+    /// Returns the number of planes of a frame.
+    fn plane_count(&self) -> usize {
+        let mut plane_count = 1;
+        if self.config.chroma_planes {
+            plane_count += 2;
+        }
+        if self.config.extra_plane {
+            plane_count += 1;
+        }
+        plane_count
+    }
+
+    /// Returns the width and height of a plane, with chroma planes
+    /// rounded up.
+    ///
+    /// See: * 4.6.2. plane_pixel_height
+    ///      * 4.7.1. plane_pixel_width
+    fn plane_size(&self, plane: usize, width: u32, height: u32) -> (u32, u32) {
+        if !self.config.chroma_planes || plane == 0 || plane == 3 {
+            (width, height)
+        } else {
+            let h_shift = self.config.log2_h_chroma_subsample;
+            let v_shift = self.config.log2_v_chroma_subsample;
+            (ceil_rshift(width, h_shift), ceil_rshift(height, v_shift))
+        }
+    }
+
+    /// Returns the position in a plane of the sample at 'x', 'y' in the
+    /// frame, with chroma positions rounded down.
+    fn plane_position(&self, plane: usize, x: u32, y: u32) -> (u32, u32) {
+        if !self.config.chroma_planes || plane == 0 || plane == 3 {
+            (x, y)
+        } else {
+            (
+                x >> self.config.log2_h_chroma_subsample,
+                y >> self.config.log2_v_chroma_subsample,
+            )
+        }
+    }
+
+    /// EncodeFrame takes the planes of a frame and encodes them into a
+    /// FFV1 packet.
+    ///
+    /// Planes are luma, then the two chroma planes if present, then alpha
+    /// if present, each one stored contiguously with a stride equal to
+    /// its width.
+    ///
+    /// Slices are coded in parallel, with one rayon task per slice. See
+    /// `set_threads` to control the number of threads.
+    pub fn encode_frame(&self, planes: &[&[u8]]) -> Result<Vec<u8>> {
+        if planes.len() != self.plane_count() {
+            return Err(Error::InvalidInputData(format!(
+                "expected {} planes, got {}",
+                self.plane_count(),
+                planes.len()
+            )));
+        }
+        for (p, plane) in planes.iter().enumerate() {
+            let (width, height) =
+                self.plane_size(p, self.config.width, self.config.height);
+            if plane.len() != width as usize * height as usize {
+                return Err(Error::InvalidInputData(format!(
+                    "plane {} holds {} samples instead of {}",
+                    p,
+                    plane.len(),
+                    width * height
+                )));
+            }
+        }
+
+        let slice_count =
+            (self.config.num_h_slices * self.config.num_v_slices) as usize;
+        let encode = || {
+            (0..slice_count)
+                .into_par_iter()
+                .map(|i| self.encode_slice(planes, i))
+                .collect::<Vec<_>>()
+        };
+        let slices = match self.pool {
+            Some(ref pool) => pool.install(encode),
+            None => encode(),
+        };
+
+        Ok(slices.concat())
+    }
+
+    /// Encodes a single slice, followed by its footer.
+    ///
+    /// The first slice starts with the frame header.
+    ///
+    /// See: * 4.3. Frame
+    ///      * 4.4. Slice
+    fn encode_slice(&self, planes: &[&[u8]], index: usize) -> Vec<u8> {
+        let mut coder = RangeEncoder::new();
+
+        // 4.3. Frame
+        if index == 0 {
+            // 4. Bitstream
+            let mut state: [u8; CONTEXT_SIZE as usize] =
+                [128; CONTEXT_SIZE as usize];
+            // Every frame is a keyframe.
+            coder.put_bool(&mut state, true);
+        }
+
+        // See: * 4.6.3. slice_pixel_height
+        //      * 4.6.4. slice_pixel_y
+        //      * 4.7.2. slice_pixel_width
+        //      * 4.7.3. slice_pixel_x
+        let num_h_slices = self.config.num_h_slices as u64;
+        let num_v_slices = self.config.num_v_slices as u64;
+        let slice_x = index as u64 % num_h_slices;
+        let slice_y = index as u64 / num_h_slices;
+        let width = self.config.width as u64;
+        let height = self.config.height as u64;
+        let start_x = (slice_x * width / num_h_slices) as u32;
+        let start_y = (slice_y * height / num_v_slices) as u32;
+        let end_x = ((slice_x + 1) * width / num_h_slices) as u32;
+        let end_y = ((slice_y + 1) * height / num_v_slices) as u32;
+
+        self.write_slice_header(&mut coder, slice_x as u32, slice_y as u32);
+        self.encode_slice_content(
+            &mut coder,
+            planes,
+            start_x,
+            start_y,
+            end_x - start_x,
+            end_y - start_y,
+        );
+
+        // 3.8.1.1.1. Termination
+        coder.sentinal_end();
+        let mut buf = coder.finish();
+
+        // 4.8. Slice Footer
+        //
+        // See: * 4.8.1. slice_size
+        //      * 4.8.2. error_status
+        //      * 4.8.3. slice_crc_parity
+        let size = buf.len() as u32;
+        buf.extend_from_slice(&size.to_be_bytes()[1..]);
+        if self.config.ec {
+            buf.push(0);
+            let parity = crc32_mpeg2_parity(&buf);
+            buf.extend_from_slice(&parity);
+        }
+
+        buf
+    }
+
+    /// Writes a slice's header.
+    ///
+    /// See: 4.5. Slice Header
+    fn write_slice_header(
+        &self,
+        coder: &mut RangeEncoder,
+        slice_x: u32,
+        slice_y: u32,
+    ) {
+        // 4. Bitstream
+        let mut slice_state: [u8; CONTEXT_SIZE as usize] =
+            [128; CONTEXT_SIZE as usize];
+
+        // 4.5.1. slice_x
+        coder.put_ur(&mut slice_state, slice_x);
+        // 4.5.2. slice_y
+        coder.put_ur(&mut slice_state, slice_y);
+        // 4.5.3 slice_width
+        coder.put_ur(&mut slice_state, 0);
+        // 4.5.4 slice_height
+        coder.put_ur(&mut slice_state, 0);
+
+        // 4.5.6. quant_table_set_index
+        for _ in 0..self.record.quant_table_set_index_count() {
+            coder.put_ur(&mut slice_state, self.config.context_model as u32);
+        }
+
+        // 4.5.7. picture_structure
+        coder.put_ur(
+            &mut slice_state,
+            self.config.picture_structure.to_coded() as u32,
+        );
+
+        // See: * 4.5.8. sar_num
+        //      * 4.5.9. sar_den
+        let (sar_num, sar_den) =
+            self.config.sample_aspect_ratio.unwrap_or((0, 1));
+        coder.put_ur(&mut slice_state, sar_num);
+        coder.put_ur(&mut slice_state, sar_den);
+    }
+
+    /// Encoding happens here.
+    ///
+    /// Planes are independent, and each plane context starts from the
+    /// initial states, since every frame is a keyframe.
+    ///
+    /// See: * 3.7.1. YCbCr
+    ///      * 4.6. Slice Content
+    fn encode_slice_content(
+        &self,
+        coder: &mut RangeEncoder,
+        planes: &[&[u8]],
+        start_x: u32,
+        start_y: u32,
+        width: u32,
+        height: u32,
+    ) {
+        let context_model = self.config.context_model as usize;
+        let context_count = self.record.context_count[context_model] as usize;
+        let quant_table = &self.record.quant_tables[context_model];
+
+        // 3.8.1.3. Initial Values for the Context Model
+        let mut states =
+            vec![
+                vec![vec![128; CONTEXT_SIZE as usize]; context_count];
+                self.record.quant_table_set_index_count()
+            ];
+
+        let chroma_planes = if self.config.chroma_planes { 2 } else { 0 };
+        for (p, plane) in planes.iter().enumerate() {
+            // The alpha plane always uses the last plane context.
+            let qt = if p == 0 {
+                0
+            } else if p == 1 + chroma_planes {
+                self.record.quant_table_set_index_count() - 1
+            } else {
+                1
+            };
+
+            // Chroma slices start on rounded down positions and have rounded
+            // up sizes, so samples shared with the previous slice are coded
+            // again.
+            let (plane_x, plane_y) = self.plane_position(p, start_x, start_y);
+            let (width, height) = self.plane_size(p, width, height);
+            let (plane_end_x, plane_end_y) =
+                (plane_x + width, plane_y + height);
+            let (plane_width, _) =
+                self.plane_size(p, self.config.width, self.config.height);
+
+            // The neighbours of each sample are taken from the slice only.
+            //
+            // See: 3.1. Border
+            let mut samples = Vec::with_capacity((width * height) as usize);
+            for y in plane_y..plane_end_y {
+                let pos = (y * plane_width) as usize;
+                samples.extend(
+                    plane[pos + plane_x as usize..pos + plane_end_x as usize]
+                        .iter()
+                        .map(|&sample| sample as u32),
+                );
+            }
+
+            for y in 0..height as usize {
+                for x in 0..width as usize {
+                    // See pred.rs for details.
+                    #[allow(non_snake_case)]
+                    #[allow(clippy::many_single_char_names)]
+                    let (T, L, t, l, tr, tl) = derive_borders32(
+                        &samples,
+                        x as isize,
+                        y as isize,
+                        width as isize,
+                        height as isize,
+                        width as isize,
+                    );
+
+                    // 3.4. Context
+                    let mut context =
+                        get_context(quant_table, T, L, t, l, tr, tl);
+
+                    // 3.8. Coding of the Sample Difference
+                    //
+                    // The difference is folded into the range of a sample,
+                    // since the decoder only keeps the low bits of the sum.
+                    let sample = samples[y * width as usize + x] as i32;
+                    let pred = get_median(l, t, l + t - tl) as i32;
+                    let mut diff = (sample - pred) as i8 as i32;
+
+                    if context < 0 {
+                        context = -context;
+                        diff = -diff;
+                    }
+
+                    coder.put_sr(&mut states[qt][context as usize], diff);
+                }
+            }
+        }
+    }
+}
+
+/// Divides 'value' by 2^'shift', rounding up.
+fn ceil_rshift(value: u32, shift: u8) -> u32 {
+    ((value as u64 + (1 << shift) - 1) >> shift) as u32
+}
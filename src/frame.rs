@@ -0,0 +1,686 @@
+use std::fmt;
+use std::mem;
+
+use crate::error::{Error, Result};
+use crate::record::ConfigRecord;
+
+/// PixelFormat describes the planes of a decoded frame.
+///
+/// Every format is planar, and the alpha plane, if any, always comes
+/// last.
+///
+/// See: * 3.7. Color Spaces
+///      * 4.1. Parameters
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PixelFormat {
+    /// Luma only, e.g. gray8 or gray16.
+    Gray { bit_depth: u8, alpha: bool },
+    /// Luma, then Cb and Cr subsampled by 2^log2_h_chroma_subsample
+    /// horizontally and 2^log2_v_chroma_subsample vertically, e.g. yuv420p
+    /// or yuv444p10.
+    Yuv {
+        bit_depth: u8,
+        log2_h_chroma_subsample: u8,
+        log2_v_chroma_subsample: u8,
+        alpha: bool,
+    },
+    /// Green, blue and red, e.g. gbrp16 or gbrap.
+    Gbr { bit_depth: u8, alpha: bool },
+}
+
+impl PixelFormat {
+    /// Returns the pixel format of the frames a configuration record
+    /// describes.
+    pub(crate) fn from_record(record: &ConfigRecord) -> Self {
+        let bit_depth = record.bits_per_raw_sample;
+        let alpha = record.extra_plane;
+        if record.colorspace_type == 1 {
+            PixelFormat::Gbr { bit_depth, alpha }
+        } else if record.chroma_planes {
+            PixelFormat::Yuv {
+                bit_depth,
+                log2_h_chroma_subsample: record.log2_h_chroma_subsample,
+                log2_v_chroma_subsample: record.log2_v_chroma_subsample,
+                alpha,
+            }
+        } else {
+            PixelFormat::Gray { bit_depth, alpha }
+        }
+    }
+
+    /// Returns the number of bits per sample, from 8 to 16.
+    pub fn bit_depth(&self) -> u8 {
+        match *self {
+            PixelFormat::Gray { bit_depth, .. }
+            | PixelFormat::Yuv { bit_depth, .. }
+            | PixelFormat::Gbr { bit_depth, .. } => bit_depth,
+        }
+    }
+
+    /// Whether or not an alpha plane is present.
+    pub fn has_alpha(&self) -> bool {
+        match *self {
+            PixelFormat::Gray { alpha, .. }
+            | PixelFormat::Yuv { alpha, .. }
+            | PixelFormat::Gbr { alpha, .. } => alpha,
+        }
+    }
+
+    /// Returns the log2 horizontal and vertical chroma subsampling values,
+    /// which are 0 for formats without chroma planes.
+    pub fn chroma_subsample(&self) -> (u8, u8) {
+        match *self {
+            PixelFormat::Yuv {
+                log2_h_chroma_subsample,
+                log2_v_chroma_subsample,
+                ..
+            } => (log2_h_chroma_subsample, log2_v_chroma_subsample),
+            _ => (0, 0),
+        }
+    }
+
+    /// Returns the number of planes.
+    pub fn num_planes(&self) -> usize {
+        let planes = match self {
+            PixelFormat::Gray { .. } => 1,
+            _ => 3,
+        };
+        planes + self.has_alpha() as usize
+    }
+
+    /// Returns the width and height of plane 'p' of a 'width'x'height'
+    /// frame. Chroma planes are rounded up, so that odd dimensions keep
+    /// their last column and row.
+    ///
+    /// See: * 4.6.2. plane_pixel_height
+    ///      * 4.7.1. plane_pixel_width
+    pub fn plane_dimensions(
+        &self,
+        p: usize,
+        width: u32,
+        height: u32,
+    ) -> (u32, u32) {
+        match self {
+            PixelFormat::Yuv {
+                log2_h_chroma_subsample,
+                log2_v_chroma_subsample,
+                ..
+            } if p == 1 || p == 2 => (
+                ((width as u64 + (1 << log2_h_chroma_subsample) - 1)
+                    >> log2_h_chroma_subsample) as u32,
+                ((height as u64 + (1 << log2_v_chroma_subsample) - 1)
+                    >> log2_v_chroma_subsample) as u32,
+            ),
+            _ => (width, height),
+        }
+    }
+}
+
+/// Formats the pixel format the way FFmpeg names it, e.g. yuv420p,
+/// yuva444p10, gbrp16 or gray8.
+impl fmt::Display for PixelFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let alpha = if self.has_alpha() { "a" } else { "" };
+        match *self {
+            PixelFormat::Gray { bit_depth, .. } => {
+                let name = if self.has_alpha() { "ya" } else { "gray" };
+                write!(f, "{}{}", name, bit_depth)
+            }
+            PixelFormat::Yuv {
+                log2_h_chroma_subsample,
+                log2_v_chroma_subsample,
+                ..
+            } => {
+                match (log2_h_chroma_subsample, log2_v_chroma_subsample) {
+                    (0, 0) => write!(f, "yuv{}444p", alpha)?,
+                    (0, 1) => write!(f, "yuv{}440p", alpha)?,
+                    (1, 0) => write!(f, "yuv{}422p", alpha)?,
+                    (1, 1) => write!(f, "yuv{}420p", alpha)?,
+                    (2, 0) => write!(f, "yuv{}411p", alpha)?,
+                    (2, 2) => write!(f, "yuv{}410p", alpha)?,
+                    (h, v) => write!(f, "yuv{}p_h{}v{}", alpha, h, v)?,
+                }
+                if self.bit_depth() > 8 {
+                    write!(f, "{}", self.bit_depth())?;
+                }
+                Ok(())
+            }
+            PixelFormat::Gbr { .. } => {
+                write!(f, "gbr{}p", alpha)?;
+                if self.bit_depth() > 8 {
+                    write!(f, "{}", self.bit_depth())?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+/// PictureStructure tells whether a frame is interlaced, and if so, which
+/// of its fields comes first in time.
+///
+/// Interlaced frames hold both fields, the top one on even rows and the
+/// bottom one on odd rows.
+///
+/// See: 4.5.7. picture_structure
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum PictureStructure {
+    /// Not coded, as in FFV1 versions 0 to 2, or a reserved value.
+    #[default]
+    Unknown,
+    /// Interlaced, top field first.
+    TopFieldFirst,
+    /// Interlaced, bottom field first.
+    BottomFieldFirst,
+    /// Progressive.
+    Progressive,
+}
+
+impl PictureStructure {
+    /// Returns the picture structure a slice header codes.
+    pub(crate) fn from_coded(picture_structure: u8) -> Self {
+        match picture_structure {
+            1 => PictureStructure::TopFieldFirst,
+            2 => PictureStructure::BottomFieldFirst,
+            3 => PictureStructure::Progressive,
+            _ => PictureStructure::Unknown,
+        }
+    }
+
+    /// Returns the value a slice header codes the picture structure with.
+    pub(crate) fn to_coded(self) -> u8 {
+        match self {
+            PictureStructure::Unknown => 0,
+            PictureStructure::TopFieldFirst => 1,
+            PictureStructure::BottomFieldFirst => 2,
+            PictureStructure::Progressive => 3,
+        }
+    }
+
+    /// Whether or not the frame is interlaced.
+    pub fn is_interlaced(&self) -> bool {
+        matches!(
+            self,
+            PictureStructure::TopFieldFirst
+                | PictureStructure::BottomFieldFirst
+        )
+    }
+}
+
+/// Concealment sets what the decoder does with damaged slices.
+///
+/// A slice is damaged when its footer codes a non-zero error_status, when
+/// it does not match its CRC, or when it cannot be decoded. Frames whose
+/// slices cannot even be located still fail.
+///
+/// See: 4.8. Slice Footer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Default)]
+pub enum Concealment {
+    /// Fail the whole frame.
+    #[default]
+    Disabled,
+    /// Repeat the slice of the previous frame, if it covers the same
+    /// area, or fill the slice with mid-gray otherwise.
+    PreviousFrame,
+    /// Fill the slice with mid-gray, and make alpha opaque.
+    MidGray,
+    /// Interpolate the slice from the rows above and below it, or else
+    /// from the columns on its left and right, taken from slices that are
+    /// not damaged. Slices with no such neighbour are filled with
+    /// mid-gray.
+    Interpolate,
+}
+
+/// SliceDamage tells why a slice was concealed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SliceDamage {
+    /// The slice footer codes this non-zero error_status.
+    ErrorStatus(u8),
+    /// The slice does not match its CRC.
+    CrcMismatch,
+    /// The slice cannot be decoded, for this reason.
+    Invalid(String),
+}
+
+/// Concealed tells why and how a slice was concealed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Concealed {
+    /// What is wrong with the slice.
+    pub damage: SliceDamage,
+    /// How the slice was concealed, which is never
+    /// Concealment::PreviousFrame when the previous frame could not be
+    /// used.
+    pub method: Concealment,
+}
+
+/// SliceMetadata describes a slice of a decoded frame, as coded by its
+/// header and footer.
+///
+/// See: * 4.5. Slice Header
+///      * 4.8. Slice Footer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SliceMetadata {
+    /// Horizontal position of the slice, in luma pixels.
+    pub x: u32,
+    /// Vertical position of the slice, in luma pixels.
+    pub y: u32,
+    /// Width of the slice, in luma pixels.
+    pub width: u32,
+    /// Height of the slice, in luma pixels.
+    pub height: u32,
+    /// Quantization table set index of each plane context.
+    pub quant_table_set_index: Vec<u8>,
+    /// Picture structure coded by the slice.
+    pub picture_structure: PictureStructure,
+    /// Sample aspect ratio numerator, 0 if unknown.
+    pub sar_num: u32,
+    /// Sample aspect ratio denominator, 0 if unknown.
+    pub sar_den: u32,
+    /// Error status from the slice footer, 0 for slices without one.
+    pub error_status: u8,
+    /// Why and how the slice was concealed, if it was.
+    pub concealed: Option<Concealed>,
+}
+
+/// Samples holds the planes of a frame, with the smallest sample type
+/// its bit depth fits in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Samples {
+    /// 8 bit samples.
+    U8(Vec<Vec<u8>>),
+    /// 9 to 16 bit samples.
+    U16(Vec<Vec<u16>>),
+}
+
+/// Plane is a view of a plane of a frame.
+#[derive(Clone, Copy, Debug)]
+pub struct Plane<'a, T> {
+    /// Samples of the plane, a row every 'stride' samples.
+    pub data: &'a [T],
+    /// Width of the plane, in samples.
+    pub width: u32,
+    /// Height of the plane, in samples.
+    pub height: u32,
+    /// Distance between the start of two rows, in samples.
+    pub stride: usize,
+}
+
+impl<'a, T> Plane<'a, T> {
+    /// Returns the samples of row 'y'.
+    pub fn row(&self, y: u32) -> &'a [T] {
+        let pos = y as usize * self.stride;
+        &self.data[pos..pos + self.width as usize]
+    }
+}
+
+/// PlaneMut is a buffer provided by the caller to decode a plane into.
+#[derive(Debug)]
+pub struct PlaneMut<'a, T> {
+    /// Samples of the plane, a row every 'stride' samples.
+    pub data: &'a mut [T],
+    /// Distance between the start of two rows, in samples.
+    pub stride: usize,
+}
+
+/// Writable views of the planes a frame is decoded into.
+pub(crate) enum PlanesMut<'p, 'a> {
+    U8(&'p mut [PlaneMut<'a, u8>]),
+    U16(&'p mut [PlaneMut<'a, u16>]),
+}
+
+/// FrameLayout sets how the planes of the frames returned by
+/// Decoder::decode_frame are laid out in memory.
+///
+/// The default layout packs rows one after the other, with a stride equal
+/// to the plane width.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FrameLayout {
+    /// Alignment of the first sample of every row, in bytes. Must be 0,
+    /// which keeps the natural alignment of the samples, or a power of
+    /// two.
+    pub row_alignment: usize,
+    /// Stride of each plane, in samples, rounded up to keep rows aligned.
+    /// It must be at least the plane width. Planes without one, or with
+    /// 0, get the smallest stride their width and alignment allow.
+    pub strides: Vec<usize>,
+}
+
+impl FrameLayout {
+    /// Checks that the row alignment is valid.
+    pub(crate) fn validate(&self) -> Result<()> {
+        if self.row_alignment != 0 && !self.row_alignment.is_power_of_two() {
+            return Err(Error::InvalidConfiguration(format!(
+                "row alignment {} is not a power of two",
+                self.row_alignment
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Frame contains a decoded FFV1 frame and relevant data about the frame.
+#[derive(Debug)]
+pub struct Frame {
+    width: u32,
+    height: u32,
+    pixel_format: PixelFormat,
+    picture_structure: PictureStructure,
+    slices: Vec<SliceMetadata>,
+    samples: Samples,
+    // Offset of the first sample and stride of each plane, in samples.
+    layouts: Vec<(usize, usize)>,
+}
+
+impl Frame {
+    /// Creates a frame with every sample set to zero, and its planes laid
+    /// out as 'layout' says.
+    pub(crate) fn new(
+        width: u32,
+        height: u32,
+        pixel_format: PixelFormat,
+        layout: &FrameLayout,
+    ) -> Result<Self> {
+        let mut layouts = Vec::with_capacity(pixel_format.num_planes());
+        let samples = if pixel_format.bit_depth() == 8 {
+            Samples::U8(alloc_planes(
+                width,
+                height,
+                pixel_format,
+                layout,
+                &mut layouts,
+            )?)
+        } else {
+            Samples::U16(alloc_planes(
+                width,
+                height,
+                pixel_format,
+                layout,
+                &mut layouts,
+            )?)
+        };
+
+        Ok(Frame {
+            width,
+            height,
+            pixel_format,
+            picture_structure: PictureStructure::Unknown,
+            slices: Vec::new(),
+            samples,
+            layouts,
+        })
+    }
+
+    /// Width of the frame, in pixels.
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    /// Height of the frame, in pixels.
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    /// Pixel format of the frame.
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    /// Picture structure of the frame, as coded by its first slice.
+    pub fn picture_structure(&self) -> PictureStructure {
+        self.picture_structure
+    }
+
+    pub(crate) fn set_picture_structure(
+        &mut self,
+        picture_structure: PictureStructure,
+    ) {
+        self.picture_structure = picture_structure;
+    }
+
+    /// Returns the slices of the frame, in the order they are coded.
+    pub fn slices(&self) -> &[SliceMetadata] {
+        &self.slices
+    }
+
+    pub(crate) fn set_slices(&mut self, slices: Vec<SliceMetadata>) {
+        self.slices = slices;
+    }
+
+    /// Sample aspect ratio of the frame, as coded by its first slice, or
+    /// None if it is unknown.
+    ///
+    /// See: * 4.5.8. sar_num
+    ///      * 4.5.9. sar_den
+    pub fn sample_aspect_ratio(&self) -> Option<(u32, u32)> {
+        match self.slices.first() {
+            Some(slice) if slice.sar_num != 0 && slice.sar_den != 0 => {
+                Some((slice.sar_num, slice.sar_den))
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether or not some slices code another sample aspect ratio than
+    /// the first one.
+    pub fn sample_aspect_ratio_mismatch(&self) -> bool {
+        self.slices.iter().any(|slice| {
+            (slice.sar_num, slice.sar_den)
+                != (self.slices[0].sar_num, self.slices[0].sar_den)
+        })
+    }
+
+    /// Returns the samples of every plane.
+    ///
+    /// With a FrameLayout other than the default one, rows may not start
+    /// at the beginning of the buffers nor be packed. Use plane_u8 or
+    /// plane_u16 to find them.
+    pub fn samples(&self) -> &Samples {
+        &self.samples
+    }
+
+    /// Calls 'f' with writable views of every plane.
+    pub(crate) fn with_planes_mut<R>(
+        &mut self,
+        f: impl FnOnce(PlanesMut<'_, '_>) -> R,
+    ) -> R {
+        let layouts = &self.layouts;
+        match &mut self.samples {
+            Samples::U8(bufs) => {
+                let mut planes = planes_mut(bufs, layouts);
+                f(PlanesMut::U8(&mut planes))
+            }
+            Samples::U16(bufs) => {
+                let mut planes = planes_mut(bufs, layouts);
+                f(PlanesMut::U16(&mut planes))
+            }
+        }
+    }
+
+    /// Takes the samples of every plane.
+    pub fn into_samples(self) -> Samples {
+        self.samples
+    }
+
+    /// Returns the number of planes.
+    pub fn num_planes(&self) -> usize {
+        self.pixel_format.num_planes()
+    }
+
+    /// Returns plane 'p', if it exists and holds 8 bit samples.
+    pub fn plane_u8(&self, p: usize) -> Option<Plane<'_, u8>> {
+        match &self.samples {
+            Samples::U8(planes) => {
+                planes.get(p).map(|data| self.plane(p, data))
+            }
+            Samples::U16(_) => None,
+        }
+    }
+
+    /// Returns plane 'p', if it exists and holds 9 to 16 bit samples.
+    pub fn plane_u16(&self, p: usize) -> Option<Plane<'_, u16>> {
+        match &self.samples {
+            Samples::U8(_) => None,
+            Samples::U16(planes) => {
+                planes.get(p).map(|data| self.plane(p, data))
+            }
+        }
+    }
+
+    /// Splits the frame into its top field, made of the even rows of
+    /// every plane, and its bottom field, made of the odd ones. Which
+    /// comes first is given by picture_structure, which both fields keep.
+    ///
+    /// Fields are 'height' / 2 rows high, rounded up for the top one, and
+    /// their planes are packed. Subsampled chroma planes may be a row
+    /// short for a field, which then repeats its last row.
+    pub fn split_fields(&self) -> (Frame, Frame) {
+        (self.field(0), self.field(1))
+    }
+
+    // Builds the field made of the rows of parity 'parity'.
+    fn field(&self, parity: u32) -> Frame {
+        let height = (self.height + 1 - parity) / 2;
+        let dimensions = (0..self.num_planes()).map(|p| {
+            self.pixel_format.plane_dimensions(p, self.width, height)
+        });
+        let layouts = dimensions
+            .clone()
+            .map(|(width, _)| (0, width as usize))
+            .collect();
+        let samples = match &self.samples {
+            Samples::U8(planes) => Samples::U8(
+                planes
+                    .iter()
+                    .enumerate()
+                    .zip(dimensions)
+                    .map(|((p, data), (_, height))| {
+                        field_plane(self.plane(p, data), parity, height)
+                    })
+                    .collect(),
+            ),
+            Samples::U16(planes) => Samples::U16(
+                planes
+                    .iter()
+                    .enumerate()
+                    .zip(dimensions)
+                    .map(|((p, data), (_, height))| {
+                        field_plane(self.plane(p, data), parity, height)
+                    })
+                    .collect(),
+            ),
+        };
+
+        Frame {
+            width: self.width,
+            height,
+            pixel_format: self.pixel_format,
+            picture_structure: self.picture_structure,
+            slices: self.slices.clone(),
+            samples,
+            layouts,
+        }
+    }
+
+    fn plane<'a, T>(&self, p: usize, data: &'a [T]) -> Plane<'a, T> {
+        let (width, height) =
+            self.pixel_format
+                .plane_dimensions(p, self.width, self.height);
+        let (offset, stride) = self.layouts[p];
+        Plane {
+            data: &data[offset..],
+            width,
+            height,
+            stride,
+        }
+    }
+}
+
+/// Allocates the planes of a frame, and records their offset and stride
+/// in 'layouts'.
+fn alloc_planes<T: Clone + Default>(
+    width: u32,
+    height: u32,
+    pixel_format: PixelFormat,
+    layout: &FrameLayout,
+    layouts: &mut Vec<(usize, usize)>,
+) -> Result<Vec<Vec<T>>> {
+    let size = mem::size_of::<T>();
+    let alignment = layout.row_alignment.max(size);
+    let align_samples = alignment / size;
+
+    let mut bufs = Vec::with_capacity(pixel_format.num_planes());
+    for p in 0..pixel_format.num_planes() {
+        let (width, height) = pixel_format.plane_dimensions(p, width, height);
+        let width = width as usize;
+        let stride = match layout.strides.get(p) {
+            Some(&stride) if stride != 0 && stride < width => {
+                return Err(Error::InvalidConfiguration(format!(
+                    "stride {} of plane {} is less than its width {}",
+                    stride, p, width
+                )))
+            }
+            Some(&stride) => stride.max(width),
+            None => width,
+        };
+        let too_large = || {
+            Error::InvalidConfiguration(format!(
+                "plane {} is too large for its layout",
+                p
+            ))
+        };
+        let stride = stride
+            .checked_add(align_samples - 1)
+            .map(|stride| stride / align_samples * align_samples)
+            .ok_or_else(too_large)?;
+
+        // Leave room to move the first row to an aligned address.
+        let len = stride
+            .checked_mul(height as usize)
+            .and_then(|len| len.checked_add(align_samples - 1))
+            .ok_or_else(too_large)?;
+        let buf = vec![T::default(); len];
+        let misalignment = buf.as_ptr() as usize % alignment;
+        let offset = (alignment - misalignment) % alignment / size;
+
+        bufs.push(buf);
+        layouts.push((offset, stride));
+    }
+
+    Ok(bufs)
+}
+
+// Views each buffer from its offset, with its stride.
+fn planes_mut<'a, T>(
+    bufs: &'a mut [Vec<T>],
+    layouts: &[(usize, usize)],
+) -> Vec<PlaneMut<'a, T>> {
+    bufs.iter_mut()
+        .zip(layouts)
+        .map(|(buf, &(offset, stride))| PlaneMut {
+            data: &mut buf[offset..],
+            stride,
+        })
+        .collect()
+}
+
+// Gathers the 'height' rows of parity 'parity' of a plane.
+fn field_plane<T: Copy>(
+    plane: Plane<'_, T>,
+    parity: u32,
+    height: u32,
+) -> Vec<T> {
+    let mut samples =
+        Vec::with_capacity(plane.width as usize * height as usize);
+    for y in 0..height {
+        let y = 2 * y + parity;
+        let y = if y < plane.height {
+            y
+        } else if y >= 2 && y - 2 < plane.height {
+            y - 2
+        } else {
+            plane.height - 1
+        };
+        samples.extend_from_slice(plane.row(y));
+    }
+    samples
+}
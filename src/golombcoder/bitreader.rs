@@ -1,3 +1,5 @@
+use crate::error::{Error, Result};
+
 pub struct BitReader<'a> {
     buf: &'a [u8],
     pos: isize,
@@ -17,11 +19,19 @@ impl<'a> BitReader<'a> {
     }
 
     /// Reads 'count' bits, up to 32.
-    pub fn u(&mut self, count: u32) -> u32 {
+    pub fn u(&mut self, count: u32) -> Result<u32> {
         if count > 32 {
-            panic!("WTF more than 32 bits");
+            return Err(Error::InvalidInputData(format!(
+                "cannot read {} bits at once",
+                count
+            )));
         }
         while count > self.bits_in_buf {
+            if self.pos as usize >= self.buf.len() {
+                return Err(Error::InvalidInputData(
+                    "read past the end of the Golomb-Rice data".to_owned(),
+                ));
+            }
             self.bit_buf <<= 8;
             self.bit_buf |= self.buf[self.pos as usize] as u32;
             self.bits_in_buf += 8;
@@ -32,11 +42,14 @@ impl<'a> BitReader<'a> {
                     break;
                 }
                 if count <= 32 {
-                    return self.u(16) << 16 | self.u(count - 16);
+                    return Ok(self.u(16)? << 16 | self.u(count - 16)?);
                 }
             }
         }
         self.bits_in_buf -= count;
-        (self.bit_buf >> self.bits_in_buf) & ((1 << count) - 1)
+        Ok(
+            ((self.bit_buf as u64 >> self.bits_in_buf) & ((1 << count) - 1))
+                as u32,
+        )
     }
 }
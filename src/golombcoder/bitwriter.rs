@@ -0,0 +1,46 @@
+pub struct BitWriter {
+    buf: Vec<u8>,
+    bit_buf: u64,
+    bits_in_buf: u32,
+}
+
+impl Default for BitWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BitWriter {
+    /// Creates a new bitwriter.
+    pub fn new() -> Self {
+        Self {
+            buf: Vec::new(),
+            bit_buf: 0,
+            bits_in_buf: 0,
+        }
+    }
+
+    /// Writes the low 'count' bits of 'value', up to 32, which BitReader
+    /// reads back with u(count).
+    pub fn u(&mut self, count: u32, value: u32) {
+        assert!(count <= 32, "cannot write {} bits at once", count);
+
+        self.bit_buf <<= count;
+        self.bit_buf |= value as u64 & ((1 << count) - 1);
+        self.bits_in_buf += count;
+
+        while self.bits_in_buf >= 8 {
+            self.bits_in_buf -= 8;
+            self.buf.push((self.bit_buf >> self.bits_in_buf) as u8);
+        }
+    }
+
+    /// Pads the last byte with zeros and returns the written bytes.
+    pub fn finish(mut self) -> Vec<u8> {
+        if self.bits_in_buf > 0 {
+            let padding = 8 - self.bits_in_buf;
+            self.u(padding, 0);
+        }
+        self.buf
+    }
+}
@@ -1,7 +1,9 @@
 //! Package golomb implements a Golomb-Rice coder as per
 //! Section 3.8.2. Golomb Rice Mode of draft-ietf-cellar-ffv1.
 
+use crate::error::{Error, Result};
 use crate::golombcoder::bitreader::BitReader;
+use crate::golombcoder::bitwriter::BitWriter;
 use crate::golombcoder::tables::LOG2_RUN;
 use crate::golombcoder::util::*;
 
@@ -16,6 +18,16 @@ pub struct Coder<'a> {
     w: u32,
 }
 
+/// Encoder is the encoding counterpart of Coder, and writes symbols
+/// that Coder reads back.
+#[derive(Default)]
+pub struct Encoder {
+    w: BitWriter,
+    run_mode: isize,
+    run_count: isize,
+    run_index: isize,
+}
+
 /// State contains a single set of states for the a Golomb-Rice coder as
 /// defined in 3.8.2.4.
 ///
@@ -41,6 +53,43 @@ impl State {
             count: 1,
         }
     }
+
+    /// Adapts the state to the last coded value.
+    ///
+    /// See: 3.8.2.3. Scalar Mode
+    fn update(&mut self, v: i32) {
+        self.error_sum += abs32(v);
+        self.drift += v;
+
+        if self.count == 128 {
+            self.count >>= 1;
+            self.drift >>= 1;
+            self.error_sum >>= 1;
+        }
+        self.count += 1;
+        if self.drift <= -self.count {
+            self.bias = max32(self.bias - 1, -128);
+            self.drift = max32(self.drift + self.count, -self.count + 1);
+        } else if self.drift > 0 {
+            self.bias = min32(self.bias + 1, 127);
+            self.drift = min32(self.drift - self.count, 0);
+        }
+    }
+
+    /// Gets the Golomb-Rice parameter for the next value.
+    ///
+    /// See: 3.8.2.3. Scalar Mode
+    fn k(&self) -> u32 {
+        let mut i = self.count;
+        let mut k = 0;
+
+        while i < self.error_sum {
+            k += 1;
+            i += i;
+        }
+
+        k
+    }
 }
 
 /// Simple sign extension.
@@ -96,7 +145,12 @@ impl<'a> Coder<'a> {
     ///
     /// See: * 3.8.2. Golomb Rice Mode
     ///      * 4. Bitstream
-    pub fn sg(&mut self, context: i32, state: &mut State, bits: usize) -> i32 {
+    pub fn sg(
+        &mut self,
+        context: i32,
+        state: &mut State,
+        bits: usize,
+    ) -> Result<i32> {
         // Section 3.8.2.2. Run Mode
         if context == 0 && self.run_mode == 0 {
             self.run_mode = 1;
@@ -105,16 +159,21 @@ impl<'a> Coder<'a> {
         // Section 3.8.2.2.1. Run Length Coding
         if self.run_mode != 0 {
             if self.run_count == 0 && self.run_mode == 1 {
-                if self.r.u(1) == 1 {
+                if self.r.u(1)? == 1 {
                     self.run_count = 1 << LOG2_RUN[self.run_index as usize];
-                    if self.x + self.run_count as u32 <= self.w {
+                    // The run index stops at the end of the table, which
+                    // only matters for lines wider than 2^24 samples.
+                    if self.x as u64 + self.run_count as u64 <= self.w as u64
+                        && (self.run_index as usize) < LOG2_RUN.len() - 1
+                    {
                         self.run_index += 1;
                     }
                 } else {
                     if LOG2_RUN[self.run_index as usize] != 0 {
-                        self.run_count =
-                            self.r.u(LOG2_RUN[self.run_index as usize] as u32)
-                                as isize;
+                        self.run_count = self
+                            .r
+                            .u(LOG2_RUN[self.run_index as usize] as u32)?
+                            as isize;
                     } else {
                         self.run_count = 0;
                     }
@@ -130,17 +189,17 @@ impl<'a> Coder<'a> {
             // No more repeats; the run is over. Read a new symbol.
             if self.run_count < 0 {
                 self.new_run();
-                let mut diff = self.get_vlc_symbol(state, bits);
+                let mut diff = self.get_vlc_symbol(state, bits)?;
                 // 3.8.2.2.2. Level Coding
                 if diff >= 0 {
                     diff += 1;
                 }
                 self.x += 1;
-                diff
+                Ok(diff)
             } else {
                 // The run is still going; return a difference of zero.
                 self.x += 1;
-                0
+                Ok(0)
             }
         } else {
             // We aren't in run mode; get a new symbol.
@@ -152,16 +211,23 @@ impl<'a> Coder<'a> {
     /// Gets the next Golomb-Rice coded symbol.
     ///
     /// See: 3.8.2.3. Scalar Mode
-    pub fn get_vlc_symbol(&mut self, state: &mut State, bits: usize) -> i32 {
-        let mut i = state.count;
-        let mut k = 0 as u32;
+    pub fn get_vlc_symbol(
+        &mut self,
+        state: &mut State,
+        bits: usize,
+    ) -> Result<i32> {
+        let k = state.k();
 
-        while i < state.error_sum {
-            k += 1;
-            i += i;
+        // Valid streams never need that many bits, and bounding k keeps
+        // the state values within range.
+        if k > 20 {
+            return Err(Error::InvalidInputData(format!(
+                "invalid Golomb-Rice parameter: {}",
+                k
+            )));
         }
 
-        let mut v = self.get_sr_golomb(k, bits);
+        let mut v = self.get_sr_golomb(k, bits)?;
 
         if 2 * state.drift < -state.count {
             v = -1 - v;
@@ -169,47 +235,185 @@ impl<'a> Coder<'a> {
 
         let ret = sign_extend(v + state.bias, bits);
 
-        state.error_sum += abs32(v);
-        state.drift += v;
-
-        if state.count == 128 {
-            state.count >>= 1;
-            state.drift >>= 1;
-            state.error_sum >>= 1;
-        }
-        state.count += 1;
-        if state.drift <= -state.count {
-            state.bias = max32(state.bias - 1, -128);
-            state.drift = max32(state.drift + state.count, -state.count + 1);
-        } else if state.drift > 0 {
-            state.bias = min32(state.bias + 1, 127);
-            state.drift = min32(state.drift - state.count, 0);
-        }
+        state.update(v);
 
-        ret
+        Ok(ret)
     }
 
     /// Gets the next signed Golomb-Rice code
     ///
     /// See: 3.8.2.1. Signed Golomb Rice Codes
-    pub fn get_sr_golomb(&mut self, k: u32, bits: usize) -> i32 {
-        let v = self.get_ur_golomb(k, bits);
+    pub fn get_sr_golomb(&mut self, k: u32, bits: usize) -> Result<i32> {
+        let v = self.get_ur_golomb(k, bits)?;
         if v & 1 == 1 {
-            -(v >> 1) - 1
+            Ok(-(v >> 1) - 1)
         } else {
-            v >> 1
+            Ok(v >> 1)
         }
     }
 
     /// Gets the next unsigned Golomb-Rice code
     ///
     /// See: 3.8.2.1. Signed Golomb Rice Codes
-    pub fn get_ur_golomb(&mut self, k: u32, bits: usize) -> i32 {
+    pub fn get_ur_golomb(&mut self, k: u32, bits: usize) -> Result<i32> {
         for prefix in 0..12 {
-            if self.r.u(1) == 1 {
-                return self.r.u(k) as i32 + (prefix << k) as i32;
+            if self.r.u(1)? == 1 {
+                return Ok(self.r.u(k)? as i32 + (prefix << k));
             }
         }
-        self.r.u(bits as u32) as i32 + 11
+        Ok(self.r.u(bits as u32)? as i32 + 11)
+    }
+}
+
+impl Encoder {
+    /// Creates a new Golomb-Rice encoder.
+    pub fn new() -> Self {
+        Default::default()
+    }
+
+    /// newPlane should be called on a given Encoder as each new Plane is
+    /// processed. It ends the last line and resets the run index.
+    ///
+    /// See: 3.8.2.2.1. Run Length Coding
+    pub fn new_plane(&mut self) {
+        self.end_line();
+        self.run_index = 0;
+    }
+
+    /// Starts a new run.
+    pub fn new_run(&mut self) {
+        self.run_mode = 0;
+        self.run_count = 0;
+    }
+
+    /// newLine ends the last line and starts a new run, since runs can
+    /// only be per-line.
+    pub fn new_line(&mut self) {
+        self.end_line();
+    }
+
+    /// Writes out the run still going at the end of a line.
+    ///
+    /// A run that is too short to be coded on its own is coded as a full
+    /// one, which Coder cuts at the end of the line.
+    ///
+    /// See: 3.8.2.2.1. Run Length Coding
+    fn end_line(&mut self) {
+        if self.run_mode != 0 {
+            self.put_full_runs();
+            if self.run_count > 0 {
+                self.w.u(1, 1);
+            }
+        }
+        self.new_run();
+    }
+
+    /// Writes out as many full runs as the current run holds.
+    ///
+    /// See: 3.8.2.2.1. Run Length Coding
+    fn put_full_runs(&mut self) {
+        while self.run_count >= 1 << LOG2_RUN[self.run_index as usize] {
+            self.run_count -= 1 << LOG2_RUN[self.run_index as usize];
+            // The run index stops at the end of the table, as in Coder.
+            if (self.run_index as usize) < LOG2_RUN.len() - 1 {
+                self.run_index += 1;
+            }
+            self.w.u(1, 1);
+        }
+    }
+
+    /// PutSG puts the next Golomb-Rice coded signed scalar symbol.
+    ///
+    /// The symbols of a line must be put with the same contexts as
+    /// Coder::sg reads them with.
+    ///
+    /// See: * 3.8.2. Golomb Rice Mode
+    ///      * 4. Bitstream
+    pub fn put_sg(
+        &mut self,
+        context: i32,
+        state: &mut State,
+        v: i32,
+        bits: usize,
+    ) {
+        // Section 3.8.2.2. Run Mode
+        if context == 0 && self.run_mode == 0 {
+            self.run_mode = 1;
+        }
+
+        // Section 3.8.2.2.1. Run Length Coding
+        let mut v = v;
+        if self.run_mode != 0 {
+            if v == 0 {
+                // The run is still going.
+                self.run_count += 1;
+                return;
+            }
+
+            // The run is over, code its length and the symbol ending it.
+            self.put_full_runs();
+            let log2_run = LOG2_RUN[self.run_index as usize] as u32;
+            self.w.u(1 + log2_run, self.run_count as u32);
+            if self.run_index != 0 {
+                self.run_index -= 1;
+            }
+            self.new_run();
+
+            // 3.8.2.2.2. Level Coding
+            if v > 0 {
+                v -= 1;
+            }
+        }
+
+        self.put_vlc_symbol(state, v, bits);
+    }
+
+    /// Puts the next Golomb-Rice coded symbol.
+    ///
+    /// See: 3.8.2.3. Scalar Mode
+    pub fn put_vlc_symbol(&mut self, state: &mut State, v: i32, bits: usize) {
+        let k = state.k();
+
+        let v = sign_extend(v - state.bias, bits);
+        let code = if 2 * state.drift < -state.count {
+            -1 - v
+        } else {
+            v
+        };
+
+        self.put_sr_golomb(code, k, bits);
+
+        state.update(v);
+    }
+
+    /// Puts the next signed Golomb-Rice code
+    ///
+    /// See: 3.8.2.1. Signed Golomb Rice Codes
+    pub fn put_sr_golomb(&mut self, v: i32, k: u32, bits: usize) {
+        let v = if v < 0 { -2 * v - 1 } else { 2 * v };
+        self.put_ur_golomb(v, k, bits);
+    }
+
+    /// Puts the next unsigned Golomb-Rice code
+    ///
+    /// Values too large for a prefix of less than 12 bits are escaped, and
+    /// coded on 'bits' bits.
+    ///
+    /// See: 3.8.2.1. Signed Golomb Rice Codes
+    pub fn put_ur_golomb(&mut self, v: i32, k: u32, bits: usize) {
+        let prefix = v >> k;
+        if prefix < 12 {
+            let low = v as u32 & ((1 << k) - 1);
+            self.w.u(prefix as u32 + 1 + k, (1 << k) | low);
+        } else {
+            self.w.u(12, 0);
+            self.w.u(bits as u32, (v - 11) as u32);
+        }
+    }
+
+    /// Ends the last line and returns the written bytes.
+    pub fn finish(mut self) -> Vec<u8> {
+        self.end_line();
+        self.w.finish()
     }
 }
@@ -1,4 +1,5 @@
 pub mod bitreader;
+pub mod bitwriter;
 pub mod golomb;
 pub mod tables;
 pub mod util;
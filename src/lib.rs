@@ -4,11 +4,22 @@ pub use golombcoder::*;
 pub mod rangecoder;
 pub use rangecoder::*;
 
+#[cfg(feature = "avi")]
+pub mod avi;
+pub mod conformance;
 pub mod constants;
 pub mod crc32mpeg2;
 pub mod decoder;
+pub mod encoder;
 pub mod error;
+pub mod frame;
 pub mod jpeg2000rct;
+#[cfg(feature = "mkv")]
+pub mod mkv;
+#[cfg(feature = "mp4")]
+pub mod mp4;
 pub mod pred;
 pub mod record;
 pub mod slice;
+pub mod trace;
+pub mod vfw;
@@ -0,0 +1,492 @@
+//! Reads FFV1 video tracks from Matroska and WebM files.
+//!
+//! Only what is needed to get FFV1 frames out is parsed: the tracks, and
+//! the blocks of the clusters. Files are read front to back, without ever
+//! seeking, so they can be streamed.
+//!
+//! Cross-references are to
+//! https://www.matroska.org/technical/elements.html
+
+use std::io::{self, Read};
+
+use crate::error::{Error, Result};
+use crate::vfw::BitmapInfoHeader;
+
+// Element IDs, with their length marker bits
+const EBML: u32 = 0x1A45_DFA3;
+const DOC_TYPE: u32 = 0x4282;
+const SEGMENT: u32 = 0x1853_8067;
+const INFO: u32 = 0x1549_A966;
+const TIMESTAMP_SCALE: u32 = 0x2A_D7B1;
+const TRACKS: u32 = 0x1654_AE6B;
+const TRACK_ENTRY: u32 = 0xAE;
+const TRACK_NUMBER: u32 = 0xD7;
+const TRACK_TYPE: u32 = 0x83;
+const CODEC_ID: u32 = 0x86;
+const CODEC_PRIVATE: u32 = 0x63A2;
+const CONTENT_ENCODINGS: u32 = 0x6D80;
+const VIDEO: u32 = 0xE0;
+const PIXEL_WIDTH: u32 = 0xB0;
+const PIXEL_HEIGHT: u32 = 0xBA;
+const CLUSTER: u32 = 0x1F43_B675;
+const TIMESTAMP: u32 = 0xE7;
+const SIMPLE_BLOCK: u32 = 0xA3;
+const BLOCK_GROUP: u32 = 0xA0;
+const BLOCK: u32 = 0xA1;
+
+// TrackType of video tracks
+const TRACK_TYPE_VIDEO: u64 = 1;
+
+// Default TimestampScale, in nanoseconds
+const DEFAULT_TIMESTAMP_SCALE: u64 = 1_000_000;
+
+/// Track is a FFV1 video track.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Track {
+    /// Number of the track, which its packets refer to.
+    pub number: u64,
+    /// CodecID of the track, either "V_FFV1" or "V_MS/VFW/FOURCC".
+    pub codec_id: String,
+    /// Width of the frames.
+    pub width: u32,
+    /// Height of the frames.
+    pub height: u32,
+    /// Configuration record to create the Decoder with, which is empty
+    /// for FFV1 versions 0 and 1.
+    pub config_record: Vec<u8>,
+}
+
+/// Packet holds a frame of a FFV1 track.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    /// Number of the track the frame belongs to.
+    pub track: u64,
+    /// Presentation timestamp of the frame, in nanoseconds.
+    pub timestamp: i64,
+    /// The coded frame.
+    pub data: Vec<u8>,
+}
+
+/// MkvReader reads the FFV1 tracks of a Matroska or WebM file, and the
+/// packets of their frames.
+///
+/// Packets of other tracks are skipped.
+pub struct MkvReader<R: Read> {
+    reader: R,
+    tracks: Vec<Track>,
+    timestamp_scale: u64,
+    cluster_timestamp: i64,
+}
+
+impl<R: Read> MkvReader<R> {
+    /// Reads the headers of a file, up to its first cluster.
+    ///
+    /// Fails if the file has no FFV1 track.
+    pub fn new(reader: R) -> Result<Self> {
+        let mut mkv = MkvReader {
+            reader,
+            tracks: Vec::new(),
+            timestamp_scale: DEFAULT_TIMESTAMP_SCALE,
+            cluster_timestamp: 0,
+        };
+
+        match mkv.read_element_header()? {
+            Some((EBML, Some(size))) => {
+                let header = mkv.read_payload(size)?;
+                let doc_type = find_element(&header, DOC_TYPE)?;
+                if doc_type != Some(b"matroska") && doc_type != Some(b"webm") {
+                    return Err(Error::InvalidInputData(
+                        "not a Matroska or WebM file".to_owned(),
+                    ));
+                }
+            }
+            _ => {
+                return Err(Error::InvalidInputData(
+                    "no EBML header".to_owned(),
+                ))
+            }
+        }
+
+        // Top level elements are read until the first cluster, whose
+        // content is left for next_packet.
+        loop {
+            match mkv.read_element_header()? {
+                Some((SEGMENT, _)) => {}
+                Some((INFO, Some(size))) => {
+                    let info = mkv.read_payload(size)?;
+                    if let Some(scale) = find_element(&info, TIMESTAMP_SCALE)?
+                    {
+                        mkv.timestamp_scale = read_uint(scale)?;
+                    }
+                }
+                Some((TRACKS, Some(size))) => {
+                    let tracks = mkv.read_payload(size)?;
+                    mkv.read_tracks(&tracks)?;
+                }
+                Some((CLUSTER, _)) | None => break,
+                Some((_, size)) => mkv.skip_payload(size)?,
+            }
+        }
+
+        if mkv.tracks.is_empty() {
+            return Err(Error::InvalidInputData("no FFV1 track".to_owned()));
+        }
+
+        Ok(mkv)
+    }
+
+    /// Returns the FFV1 tracks of the file.
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    /// Reads the next packet of a FFV1 track, or None at the end of the
+    /// file.
+    pub fn next_packet(&mut self) -> Result<Option<Packet>> {
+        loop {
+            match self.read_element_header()? {
+                // Clusters and block groups are read element by element,
+                // as they may not have a known size.
+                Some((SEGMENT, _))
+                | Some((CLUSTER, _))
+                | Some((BLOCK_GROUP, _)) => {}
+                Some((TIMESTAMP, Some(size))) => {
+                    let timestamp = self.read_payload(size)?;
+                    self.cluster_timestamp = read_uint(&timestamp)? as i64;
+                }
+                Some((SIMPLE_BLOCK, Some(size)))
+                | Some((BLOCK, Some(size))) => {
+                    let block = self.read_payload(size)?;
+                    if let Some(packet) = self.read_block(block)? {
+                        return Ok(Some(packet));
+                    }
+                }
+                Some((_, size)) => self.skip_payload(size)?,
+                None => return Ok(None),
+            }
+        }
+    }
+
+    /// Reads the FFV1 video tracks out of the content of Tracks.
+    fn read_tracks(&mut self, tracks: &[u8]) -> Result<()> {
+        for entry in Elements(tracks) {
+            let (id, entry) = entry?;
+            if id != TRACK_ENTRY {
+                continue;
+            }
+
+            let mut number = 0;
+            let mut track_type = 0;
+            let mut codec_id: &[u8] = &[];
+            let mut codec_private: &[u8] = &[];
+            let mut encoded = false;
+            let mut width = 0;
+            let mut height = 0;
+            for element in Elements(entry) {
+                match element? {
+                    (TRACK_NUMBER, value) => number = read_uint(value)?,
+                    (TRACK_TYPE, value) => track_type = read_uint(value)?,
+                    (CODEC_ID, value) => codec_id = value,
+                    (CODEC_PRIVATE, value) => codec_private = value,
+                    (CONTENT_ENCODINGS, _) => encoded = true,
+                    (VIDEO, video) => {
+                        for element in Elements(video) {
+                            match element? {
+                                (PIXEL_WIDTH, value) => {
+                                    width = read_uint(value)? as u32
+                                }
+                                (PIXEL_HEIGHT, value) => {
+                                    height = read_uint(value)? as u32
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            if track_type != TRACK_TYPE_VIDEO {
+                continue;
+            }
+            let config_record = match codec_id {
+                b"V_FFV1" => codec_private.to_owned(),
+                // Other VFW codecs are skipped, but FFV1 headers must be
+                // valid.
+                b"V_MS/VFW/FOURCC"
+                    if codec_private.get(16..20) == Some(b"FFV1") =>
+                {
+                    let header = BitmapInfoHeader::parse_bitmap_info_header(
+                        codec_private,
+                    )?;
+                    if width == 0 || height == 0 {
+                        let (w, h) = header.dimensions();
+                        width = w;
+                        height = h;
+                    }
+                    header.config_record
+                }
+                _ => continue,
+            };
+            if encoded {
+                return Err(Error::InvalidInputData(format!(
+                    "track {} has content encodings, which are not supported",
+                    number
+                )));
+            }
+
+            self.tracks.push(Track {
+                number,
+                codec_id: String::from_utf8_lossy(codec_id).into_owned(),
+                width,
+                height,
+                config_record,
+            });
+        }
+
+        Ok(())
+    }
+
+    /// Reads a SimpleBlock or a Block, and returns its frame if it belongs
+    /// to a FFV1 track.
+    fn read_block(&self, mut block: Vec<u8>) -> Result<Option<Packet>> {
+        let (track, len) = read_vint(&block)?;
+        let header = match block.get(len..len + 3) {
+            Some(header) => header,
+            None => {
+                return Err(Error::InvalidInputData(
+                    "truncated block header".to_owned(),
+                ))
+            }
+        };
+        if !self.tracks.iter().any(|t| Some(t.number) == track) {
+            return Ok(None);
+        }
+
+        let timestamp = i16::from_be_bytes([header[0], header[1]]);
+        // Lacing packs several frames in a block, which FFV1 has no use
+        // for.
+        let lacing = (header[2] >> 1) & 3;
+        if lacing != 0 {
+            return Err(Error::InvalidInputData(
+                "laced blocks are not supported".to_owned(),
+            ));
+        }
+
+        let timestamp =
+            match self.cluster_timestamp.checked_add(timestamp as i64) {
+                Some(timestamp) => timestamp,
+                None => {
+                    return Err(Error::InvalidInputData(
+                        "block timestamp overflows".to_owned(),
+                    ))
+                }
+            }
+            .saturating_mul(self.timestamp_scale as i64);
+        block.drain(..len + 3);
+
+        Ok(Some(Packet {
+            track: track.unwrap_or_default(),
+            timestamp,
+            data: block,
+        }))
+    }
+
+    /// Reads the ID and the size of the next element, or None at the end
+    /// of the file. Sizes are None when unknown.
+    fn read_element_header(&mut self) -> Result<Option<(u32, Option<u64>)>> {
+        let mut first = [0; 1];
+        loop {
+            match self.reader.read(&mut first) {
+                Ok(0) => return Ok(None),
+                Ok(_) => break,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(read_error(err)),
+            }
+        }
+
+        let id_len = first[0].leading_zeros() as usize + 1;
+        if id_len > 4 {
+            return Err(Error::InvalidInputData(format!(
+                "invalid element ID: {:#x}",
+                first[0]
+            )));
+        }
+        let mut id = first[0] as u32;
+        for byte in self.read_bytes(id_len - 1)? {
+            id = id << 8 | byte as u32;
+        }
+
+        let mut first = [0; 1];
+        self.reader.read_exact(&mut first).map_err(read_error)?;
+        let size_len = first[0].leading_zeros() as usize + 1;
+        if size_len > 8 {
+            return Err(Error::InvalidInputData(
+                "invalid element size".to_owned(),
+            ));
+        }
+        let mut size = vec![first[0]];
+        size.extend(self.read_bytes(size_len - 1)?);
+        let (size, _) = read_vint(&size)?;
+
+        Ok(Some((id, size)))
+    }
+
+    /// Reads the payload of an element.
+    fn read_payload(&mut self, size: u64) -> Result<Vec<u8>> {
+        // The payload is not allocated all at once, since its size is not
+        // to be trusted.
+        let mut payload = Vec::new();
+        let read = (&mut self.reader)
+            .take(size)
+            .read_to_end(&mut payload)
+            .map_err(read_error)?;
+        if (read as u64) < size {
+            return Err(Error::InvalidInputData(
+                "truncated element".to_owned(),
+            ));
+        }
+
+        Ok(payload)
+    }
+
+    /// Skips the payload of an element.
+    fn skip_payload(&mut self, size: Option<u64>) -> Result<()> {
+        let size = match size {
+            Some(size) => size,
+            None => {
+                return Err(Error::InvalidInputData(
+                    "cannot skip an element of unknown size".to_owned(),
+                ))
+            }
+        };
+        let skipped =
+            io::copy(&mut (&mut self.reader).take(size), &mut io::sink())
+                .map_err(read_error)?;
+        if skipped < size {
+            return Err(Error::InvalidInputData(
+                "truncated element".to_owned(),
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn read_bytes(&mut self, len: usize) -> Result<Vec<u8>> {
+        let mut bytes = vec![0; len];
+        self.reader.read_exact(&mut bytes).map_err(read_error)?;
+        Ok(bytes)
+    }
+}
+
+impl<R: Read> Iterator for MkvReader<R> {
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
+
+/// Iterates over the elements of the payload of a master element, as
+/// their IDs and payloads.
+struct Elements<'a>(&'a [u8]);
+
+impl<'a> Iterator for Elements<'a> {
+    type Item = Result<(u32, &'a [u8])>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.is_empty() {
+            return None;
+        }
+
+        let id_len = self.0[0].leading_zeros() as usize + 1;
+        let element = if id_len > 4 || id_len > self.0.len() {
+            Err(Error::InvalidInputData("invalid element ID".to_owned()))
+        } else {
+            let id = self.0[..id_len]
+                .iter()
+                .fold(0, |id, &byte| id << 8 | byte as u32);
+            match read_vint(&self.0[id_len..]) {
+                Ok((Some(size), len)) => {
+                    let start = id_len + len;
+                    match start.checked_add(size as usize) {
+                        Some(end) if end <= self.0.len() => {
+                            let payload = &self.0[start..end];
+                            self.0 = &self.0[end..];
+                            return Some(Ok((id, payload)));
+                        }
+                        _ => Err(Error::InvalidInputData(
+                            "truncated element".to_owned(),
+                        )),
+                    }
+                }
+                Ok((None, _)) => Err(Error::InvalidInputData(
+                    "unknown size in a master element".to_owned(),
+                )),
+                Err(err) => Err(err),
+            }
+        };
+
+        // Nothing can be read past an invalid element.
+        self.0 = &[];
+        Some(element)
+    }
+}
+
+/// Finds an element in the payload of a master element.
+fn find_element(payload: &[u8], id: u32) -> Result<Option<&[u8]>> {
+    for element in Elements(payload) {
+        let (element_id, value) = element?;
+        if element_id == id {
+            return Ok(Some(value));
+        }
+    }
+
+    Ok(None)
+}
+
+/// Reads a variable size integer, and returns it along with its length.
+/// Integers with all their bits set are reserved for unknown sizes, and
+/// read as None.
+fn read_vint(buf: &[u8]) -> Result<(Option<u64>, usize)> {
+    let len = match buf.first() {
+        Some(&first) if first != 0 => first.leading_zeros() as usize + 1,
+        _ => {
+            return Err(Error::InvalidInputData(
+                "invalid variable size integer".to_owned(),
+            ))
+        }
+    };
+    if buf.len() < len {
+        return Err(Error::InvalidInputData(
+            "truncated variable size integer".to_owned(),
+        ));
+    }
+
+    let value = buf[1..len]
+        .iter()
+        .fold((buf[0] as u64) & (0xFF >> len), |value, &byte| {
+            value << 8 | byte as u64
+        });
+    let unknown = (1u64 << (7 * len)) - 1;
+
+    Ok((if value == unknown { None } else { Some(value) }, len))
+}
+
+/// Reads an unsigned integer element.
+fn read_uint(value: &[u8]) -> Result<u64> {
+    if value.len() > 8 {
+        return Err(Error::InvalidInputData(format!(
+            "{} byte unsigned integer",
+            value.len()
+        )));
+    }
+
+    Ok(value
+        .iter()
+        .fold(0, |value, &byte| value << 8 | byte as u64))
+}
+
+fn read_error(err: io::Error) -> Error {
+    Error::InvalidInputData(format!("cannot read Matroska data: {}", err))
+}
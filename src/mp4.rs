@@ -0,0 +1,502 @@
+//! Reads FFV1 tracks from ISOBMFF files, that is MP4 and QuickTime MOV
+//! ones.
+//!
+//! The configuration record of a track is the payload of the 'glbl' box
+//! of its 'FFV1' sample entry. Samples are located through the 'stsz',
+//! 'stsc' and 'stco' or 'co64' boxes of the sample table, so fragmented
+//! files, whose samples are in 'moof' boxes, are not supported.
+
+use std::io::{self, Read, Seek, SeekFrom};
+
+use crate::error::{Error, Result};
+
+// Size of the fields of a VisualSampleEntry before its child boxes
+const VISUAL_SAMPLE_ENTRY_SIZE: usize = 78;
+
+/// Track is a FFV1 track.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Track {
+    /// ID of the track, which its packets refer to.
+    pub id: u32,
+    /// Width of the frames.
+    pub width: u32,
+    /// Height of the frames.
+    pub height: u32,
+    /// Number of time units in a second, for the timestamps of the
+    /// samples.
+    pub timescale: u32,
+    /// Payload of the 'glbl' box, to create the Decoder with, which is
+    /// empty for FFV1 versions 0 and 1.
+    pub config_record: Vec<u8>,
+    /// Number of samples of the track.
+    pub sample_count: usize,
+}
+
+/// Packet holds a sample of a FFV1 track.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Packet {
+    /// ID of the track the sample belongs to.
+    pub track: u32,
+    /// Number of the sample, from the start of its track.
+    pub sample: usize,
+    /// Timestamp of the sample, in nanoseconds.
+    pub timestamp: i64,
+    /// Whether the sample is a sync sample.
+    pub keyframe: bool,
+    /// The coded frame.
+    pub data: Vec<u8>,
+}
+
+// Location of a sample in the file
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    track: usize,
+    number: usize,
+    offset: u64,
+    size: u32,
+    timestamp: i64,
+    keyframe: bool,
+}
+
+/// Mp4Reader reads the FFV1 tracks of an MP4 or MOV file, and the packets
+/// of their samples.
+///
+/// Samples of all the FFV1 tracks are returned in the order they are
+/// stored in, and samples of other tracks are skipped.
+pub struct Mp4Reader<R: Read + Seek> {
+    reader: R,
+    tracks: Vec<Track>,
+    samples: Vec<Sample>,
+    next_sample: usize,
+}
+
+impl<R: Read + Seek> Mp4Reader<R> {
+    /// Reads the 'moov' box of a file.
+    ///
+    /// Fails if the file has no FFV1 track.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let file_size = reader.seek(SeekFrom::End(0)).map_err(read_error)?;
+        seek(&mut reader, 0)?;
+
+        let mut moov = None;
+        let mut pos = 0;
+        while pos < file_size {
+            let (box_type, header_size, size) =
+                match read_box_header(&mut reader, file_size - pos)? {
+                    Some(header) => header,
+                    None => break,
+                };
+            if &box_type == b"moov" {
+                moov = Some(read_payload(&mut reader, size - header_size)?);
+                break;
+            }
+            pos += size;
+            seek(&mut reader, pos)?;
+        }
+
+        let moov = match moov {
+            Some(moov) => moov,
+            None => {
+                return Err(Error::InvalidInputData("no moov box".to_owned()))
+            }
+        };
+
+        let mut tracks = Vec::new();
+        let mut samples = Vec::new();
+        for trak in Boxes(&moov).filter(|(t, _)| t == b"trak") {
+            if let Some((track, track_samples)) =
+                parse_trak(trak.1, tracks.len(), file_size)?
+            {
+                tracks.push(track);
+                samples.extend(track_samples);
+            }
+        }
+        if tracks.is_empty() {
+            return Err(Error::InvalidInputData("no FFV1 track".to_owned()));
+        }
+        samples.sort_by_key(|sample| sample.offset);
+
+        Ok(Mp4Reader {
+            reader,
+            tracks,
+            samples,
+            next_sample: 0,
+        })
+    }
+
+    /// Returns the FFV1 tracks of the file.
+    pub fn tracks(&self) -> &[Track] {
+        &self.tracks
+    }
+
+    /// Reads the next sample of a FFV1 track, or None after the last one.
+    pub fn next_packet(&mut self) -> Result<Option<Packet>> {
+        let sample = match self.samples.get(self.next_sample) {
+            Some(&sample) => sample,
+            None => return Ok(None),
+        };
+        self.next_sample += 1;
+
+        seek(&mut self.reader, sample.offset)?;
+        let data = read_payload(&mut self.reader, sample.size as u64)?;
+
+        Ok(Some(Packet {
+            track: self.tracks[sample.track].id,
+            sample: sample.number,
+            timestamp: sample.timestamp,
+            keyframe: sample.keyframe,
+            data,
+        }))
+    }
+}
+
+impl<R: Read + Seek> Iterator for Mp4Reader<R> {
+    type Item = Result<Packet>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_packet().transpose()
+    }
+}
+
+/// Parses a 'trak' box, and returns the track and its samples if it is
+/// a FFV1 one.
+fn parse_trak(
+    trak: &[u8],
+    index: usize,
+    file_size: u64,
+) -> Result<Option<(Track, Vec<Sample>)>> {
+    let mdia = find_box(trak, b"mdia");
+    let stbl = mdia
+        .and_then(|mdia| find_box(mdia, b"minf"))
+        .and_then(|minf| find_box(minf, b"stbl"));
+
+    // The first sample entry, past the version, the flags and the entry
+    // count of 'stsd'. Tracks without a FFV1 one are skipped, whatever
+    // else they lack.
+    let entry = stbl
+        .and_then(|stbl| find_box(stbl, b"stsd"))
+        .and_then(|stsd| Boxes(stsd.get(8..)?).next());
+    let entry = match entry {
+        Some((entry_type, entry)) if &entry_type == b"FFV1" => entry,
+        _ => return Ok(None),
+    };
+    let tkhd = find_box(trak, b"tkhd").ok_or_else(|| missing_box(b"tkhd"))?;
+    let mdhd = mdia
+        .and_then(|mdia| find_box(mdia, b"mdhd"))
+        .ok_or_else(|| missing_box(b"mdhd"))?;
+    let stbl = stbl.ok_or_else(|| missing_box(b"stbl"))?;
+    if entry.len() < VISUAL_SAMPLE_ENTRY_SIZE {
+        return Err(Error::InvalidInputData(
+            "truncated FFV1 sample entry".to_owned(),
+        ));
+    }
+    let config_record = Boxes(&entry[VISUAL_SAMPLE_ENTRY_SIZE..])
+        .find(|(t, _)| t == b"glbl")
+        .map_or(Vec::new(), |(_, glbl)| glbl.to_vec());
+
+    // track_ID and timescale come after the creation and modification
+    // times, which are 64 bit in version 1 boxes.
+    let full_box_field = |payload: &[u8], v0: usize, v1: usize| {
+        let pos = if payload.first() == Some(&1) { v1 } else { v0 };
+        match payload.get(pos..pos + 4) {
+            Some(field) => Ok(read_u32(field, 0)),
+            None => Err(Error::InvalidInputData("truncated box".to_owned())),
+        }
+    };
+    let id = full_box_field(tkhd, 12, 20)?;
+    let timescale = full_box_field(mdhd, 12, 20)?;
+
+    let track = Track {
+        id,
+        width: read_u16(entry, 24) as u32,
+        height: read_u16(entry, 26) as u32,
+        timescale,
+        config_record,
+        sample_count: 0,
+    };
+    let samples = parse_stbl(stbl, index, timescale, file_size)?;
+
+    Ok(Some((
+        Track {
+            sample_count: samples.len(),
+            ..track
+        },
+        samples,
+    )))
+}
+
+/// Locates the samples of a track with its sample table.
+fn parse_stbl(
+    stbl: &[u8],
+    track: usize,
+    timescale: u32,
+    file_size: u64,
+) -> Result<Vec<Sample>> {
+    // Sample sizes
+    let stsz = find_box(stbl, b"stsz").ok_or_else(|| missing_box(b"stsz"))?;
+    if stsz.len() < 12 {
+        return Err(Error::InvalidInputData("truncated stsz box".to_owned()));
+    }
+    let sample_size = read_u32(stsz, 4);
+    let sample_count = read_u32(stsz, 8) as usize;
+    // Samples take a byte at least, which bounds their number.
+    if sample_count as u64 > file_size {
+        return Err(Error::InvalidInputData(format!(
+            "{} samples in a file of {} bytes",
+            sample_count, file_size
+        )));
+    }
+    let sizes: Vec<u32> = if sample_size != 0 {
+        vec![sample_size; sample_count]
+    } else {
+        table(stsz, 12, sample_count, 4)?
+            .map(|entry| read_u32(entry, 0))
+            .collect()
+    };
+
+    // Chunk offsets
+    let chunk_offsets: Vec<u64> =
+        match (find_box(stbl, b"stco"), find_box(stbl, b"co64")) {
+            (Some(stco), _) => table(stco, 8, count(stco)?, 4)?
+                .map(|entry| read_u32(entry, 0) as u64)
+                .collect(),
+            (None, Some(co64)) => table(co64, 8, count(co64)?, 8)?
+                .map(|entry| read_u64(entry, 0))
+                .collect(),
+            (None, None) => return Err(missing_box(b"stco")),
+        };
+
+    // Samples per chunk, as runs of chunks starting at 'first_chunk'
+    let stsc = find_box(stbl, b"stsc").ok_or_else(|| missing_box(b"stsc"))?;
+    let runs: Vec<(u32, u32)> = table(stsc, 8, count(stsc)?, 12)?
+        .map(|entry| (read_u32(entry, 0), read_u32(entry, 4)))
+        .collect();
+
+    // Durations, as runs of samples
+    let stts = find_box(stbl, b"stts").ok_or_else(|| missing_box(b"stts"))?;
+    let mut durations = table(stts, 8, count(stts)?, 8)?.flat_map(|entry| {
+        let run = read_u32(entry, 0) as usize;
+        let duration = read_u32(entry, 4);
+        (0..run.min(sample_count)).map(move |_| duration)
+    });
+
+    // Sync samples, in increasing order and numbered from 1. All samples
+    // are without this box.
+    let mut sync_samples = match find_box(stbl, b"stss") {
+        Some(stss) => Some(
+            table(stss, 8, count(stss)?, 4)?
+                .map(|entry| read_u32(entry, 0) as usize)
+                .peekable(),
+        ),
+        None => None,
+    };
+
+    let mut samples = Vec::with_capacity(sizes.len());
+    let mut time = 0u64;
+    let mut run = 0;
+    for (chunk, &chunk_offset) in chunk_offsets.iter().enumerate() {
+        // Chunks are numbered from 1.
+        let chunk = chunk as u32 + 1;
+        while let Some(&(first, _)) = runs.get(run + 1) {
+            if first > chunk {
+                break;
+            }
+            run += 1;
+        }
+        let per_chunk = match runs.get(run) {
+            Some(&(first_chunk, per_chunk)) if first_chunk <= chunk => {
+                per_chunk
+            }
+            _ => 0,
+        };
+
+        let mut offset = chunk_offset;
+        for _ in 0..per_chunk {
+            let number = samples.len();
+            let size = match sizes.get(number) {
+                Some(&size) => size,
+                None => break,
+            };
+            let keyframe = match sync_samples {
+                Some(ref mut sync) => {
+                    while sync.next_if(|&sync| sync <= number).is_some() {}
+                    sync.peek() == Some(&(number + 1))
+                }
+                None => true,
+            };
+            samples.push(Sample {
+                track,
+                number,
+                offset,
+                size,
+                timestamp: to_ns(time, timescale),
+                keyframe,
+            });
+            offset = offset.saturating_add(size as u64);
+            time = time.saturating_add(durations.next().unwrap_or(0) as u64);
+        }
+    }
+
+    if samples.len() < sizes.len() {
+        return Err(Error::InvalidInputData(format!(
+            "{} samples, but chunks hold {}",
+            sizes.len(),
+            samples.len()
+        )));
+    }
+
+    Ok(samples)
+}
+
+// Converts a time in 'timescale' units to nanoseconds
+fn to_ns(time: u64, timescale: u32) -> i64 {
+    if timescale == 0 {
+        return 0;
+    }
+    let ns = time as u128 * 1_000_000_000 / timescale as u128;
+    ns.min(i64::MAX as u128) as i64
+}
+
+/// Iterates over the boxes of a payload, as their types and payloads.
+/// Truncated boxes end the iteration.
+struct Boxes<'a>(&'a [u8]);
+
+impl<'a> Iterator for Boxes<'a> {
+    type Item = ([u8; 4], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.0.len() < 8 {
+            return None;
+        }
+        let box_type = fourcc(&self.0[4..]);
+        let (header_size, size) = match read_u32(self.0, 0) {
+            // Up to the end
+            0 => (8, self.0.len() as u64),
+            1 if self.0.len() >= 16 => (16, read_u64(self.0, 8)),
+            size => (8, size as u64),
+        };
+        if size < header_size || size > self.0.len() as u64 {
+            self.0 = &[];
+            return None;
+        }
+
+        let payload = &self.0[header_size as usize..size as usize];
+        self.0 = &self.0[size as usize..];
+
+        Some((box_type, payload))
+    }
+}
+
+/// Finds a box in a payload.
+fn find_box<'a>(payload: &'a [u8], box_type: &[u8; 4]) -> Option<&'a [u8]> {
+    Boxes(payload)
+        .find(|(t, _)| t == box_type)
+        .map(|(_, payload)| payload)
+}
+
+fn missing_box(box_type: &[u8; 4]) -> Error {
+    Error::InvalidInputData(format!(
+        "no {} box",
+        String::from_utf8_lossy(box_type)
+    ))
+}
+
+// Reads the entry count of a full box holding a table
+fn count(payload: &[u8]) -> Result<usize> {
+    match payload.get(4..8) {
+        Some(count) => Ok(read_u32(count, 0) as usize),
+        None => Err(Error::InvalidInputData("truncated box".to_owned())),
+    }
+}
+
+// Iterates over the 'count' entries of 'entry_size' bytes of a table
+// starting at 'pos'
+fn table(
+    payload: &[u8],
+    pos: usize,
+    count: usize,
+    entry_size: usize,
+) -> Result<std::slice::ChunksExact<'_, u8>> {
+    match count
+        .checked_mul(entry_size)
+        .and_then(|size| payload.get(pos..pos.checked_add(size)?))
+    {
+        Some(entries) => Ok(entries.chunks_exact(entry_size)),
+        None => Err(Error::InvalidInputData("truncated table".to_owned())),
+    }
+}
+
+fn fourcc(buf: &[u8]) -> [u8; 4] {
+    [buf[0], buf[1], buf[2], buf[3]]
+}
+
+fn read_u16(buf: &[u8], pos: usize) -> u16 {
+    u16::from_be_bytes([buf[pos], buf[pos + 1]])
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_be_bytes(fourcc(&buf[pos..]))
+}
+
+fn read_u64(buf: &[u8], pos: usize) -> u64 {
+    (read_u32(buf, pos) as u64) << 32 | read_u32(buf, pos + 4) as u64
+}
+
+/// Reads the type, the header size and the size of a box, or None at the
+/// end of the file. Boxes of size 0 extend to the end of the file, which
+/// is 'left' bytes away.
+fn read_box_header<R: Read>(
+    reader: &mut R,
+    left: u64,
+) -> Result<Option<([u8; 4], u64, u64)>> {
+    if left < 8 {
+        return Ok(None);
+    }
+    let mut header = [0; 8];
+    reader.read_exact(&mut header).map_err(read_error)?;
+    let box_type = fourcc(&header[4..]);
+
+    let (header_size, size) = match read_u32(&header, 0) {
+        0 => (8, left),
+        1 => {
+            let mut size = [0; 8];
+            reader.read_exact(&mut size).map_err(read_error)?;
+            (16, u64::from_be_bytes(size))
+        }
+        size => (8, size as u64),
+    };
+    if size < header_size || size > left {
+        return Err(Error::InvalidInputData(format!(
+            "invalid size of {} box: {}",
+            String::from_utf8_lossy(&box_type),
+            size
+        )));
+    }
+
+    Ok(Some((box_type, header_size, size)))
+}
+
+/// Reads the payload of a box or a sample.
+fn read_payload<R: Read>(reader: &mut R, size: u64) -> Result<Vec<u8>> {
+    // The payload is not allocated all at once, since its size is not to
+    // be trusted.
+    let mut payload = Vec::new();
+    let read = reader
+        .take(size)
+        .read_to_end(&mut payload)
+        .map_err(read_error)?;
+    if (read as u64) < size {
+        return Err(Error::InvalidInputData("truncated payload".to_owned()));
+    }
+
+    Ok(payload)
+}
+
+fn seek<R: Seek>(reader: &mut R, pos: u64) -> Result<()> {
+    reader.seek(SeekFrom::Start(pos)).map_err(read_error)?;
+    Ok(())
+}
+
+fn read_error(err: io::Error) -> Error {
+    Error::InvalidInputData(format!("cannot read ISOBMFF data: {}", err))
+}
@@ -30,6 +30,9 @@ macro_rules! deriveBorders {
         ///
         /// where 'a' through 'i' are pixel values in a plane.
         ///
+        /// Rows of 'plane' are 'width' samples wide and start 'stride'
+        /// samples apart, so padded rows work as well as packed ones.
+        ///
         /// See: * 3.1. Border
         ///      * 3.2. Samples
         pub fn $func_name(
@@ -144,3 +147,23 @@ fn max(a: isize, b: isize) -> isize {
 pub fn get_median(a: isize, b: isize, c: isize) -> isize {
     a + b + c - min(a, min(b, c)) - max(a, max(b, c))
 }
+
+/// Predicts a sample from its left, top and top-left neighbours.
+///
+/// With 'signed16', the neighbours are 16 bit samples taken as signed
+/// values. The specification only asks for this with the range coder,
+/// but FFmpeg, which writes these streams, stores 16 bit YCbCr samples
+/// as signed values whatever the coder, and Golomb-Rice streams follow
+/// it.
+///
+/// See: 3.3. Median Predictor
+pub fn predict(l: isize, t: isize, tl: isize, signed16: bool) -> isize {
+    if signed16 {
+        let l = l as u16 as i16 as isize;
+        let t = t as u16 as i16 as isize;
+        let tl = tl as u16 as i16 as isize;
+        get_median(l, t, l + t - tl)
+    } else {
+        get_median(l, t, l + t - tl)
+    }
+}
@@ -4,12 +4,14 @@
 //! Cross-references are to
 //! https://tools.ietf.org/id/draft-ietf-cellar-ffv1-17
 
+use crate::error::{Error, Result};
 use crate::rangecoder::tables::DEFAULT_STATE_TRANSITION;
 use crate::rangecoder::util::min32;
 
 /// RangeCoder is an instance of a range coder, as defined in:
 ///     Martin, G. Nigel N., "Range encoding: an algorithm for
 ///     removing redundancy from a digitised message.", July 1979.
+#[derive(Clone)]
 pub struct RangeCoder<'a> {
     buf: &'a [u8],
     pos: isize,
@@ -24,8 +26,17 @@ pub struct RangeCoder<'a> {
 impl<'a> RangeCoder<'a> {
     /// Creates a new range coder instance.
     ///
+    /// 'buf' must hold at least the two bytes used to initialize the coder.
+    ///
     /// See: 3.8.1. Range Coding Mode
-    pub fn new(buf: &'a [u8]) -> Self {
+    pub fn new(buf: &'a [u8]) -> Result<Self> {
+        if buf.len() < 2 {
+            return Err(Error::InvalidInputData(format!(
+                "range coded data is too short: {} bytes",
+                buf.len()
+            )));
+        }
+
         // Figure 15.
         let mut pos: isize = 2;
         // Figure 14.
@@ -50,7 +61,7 @@ impl<'a> RangeCoder<'a> {
 
         // 3.8.1.3. Initial Values for the Context Model
         coder.set_table(&DEFAULT_STATE_TRANSITION);
-        coder
+        Ok(coder)
     }
 
     /// Refills the buffer.
@@ -87,14 +98,14 @@ impl<'a> RangeCoder<'a> {
     /// Gets the next range coded unsigned scalar symbol.
     ///
     /// See: 4. Bitstream
-    pub fn ur(&mut self, state: &mut [u8]) -> u32 {
-        self.symbol(state, false) as u32
+    pub fn ur(&mut self, state: &mut [u8]) -> Result<u32> {
+        Ok(self.symbol(state, false)? as u32)
     }
 
     /// Gets the next range coded signed scalar symbol.
     ///
     /// See: 4. Bitstream
-    pub fn sr(&mut self, state: &mut [u8]) -> i32 {
+    pub fn sr(&mut self, state: &mut [u8]) -> Result<i32> {
         self.symbol(state, true)
     }
 
@@ -108,16 +119,18 @@ impl<'a> RangeCoder<'a> {
     /// Gets the next range coded symbol.
     ///
     /// See: 3.8.1.2. Range Non Binary Values
-    pub fn symbol(&mut self, state: &mut [u8], signed: bool) -> i32 {
+    pub fn symbol(&mut self, state: &mut [u8], signed: bool) -> Result<i32> {
         if self.get(&mut state[0]) {
-            return 0;
+            return Ok(0);
         }
 
         let mut e: i32 = 0;
         while self.get(&mut state[1 + min32(e, 9) as usize]) {
             e += 1;
             if e > 31 {
-                panic!("WTF range coder!");
+                return Err(Error::InvalidInputData(
+                    "range coded symbol is too large".to_owned(),
+                ));
             }
         }
 
@@ -130,9 +143,9 @@ impl<'a> RangeCoder<'a> {
         }
 
         if signed && self.get(&mut state[11 + min32(e, 10) as usize]) {
-            -(a as i32)
+            Ok((a as i32).wrapping_neg())
         } else {
-            a as i32
+            Ok(a as i32)
         }
     }
 
@@ -165,3 +178,200 @@ impl<'a> RangeCoder<'a> {
         self.pos
     }
 }
+
+/// RangeEncoder is the encoding counterpart of RangeCoder, and writes
+/// symbols that RangeCoder reads back.
+#[derive(Clone)]
+pub struct RangeEncoder {
+    buf: Vec<u8>,
+    low: u32,
+    rng: u32,
+    outstanding_count: usize,
+    outstanding_byte: i32,
+    zero_state: [u8; 256],
+    one_state: [u8; 256],
+}
+
+impl Default for RangeEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RangeEncoder {
+    /// Creates a new range encoder instance.
+    ///
+    /// See: 3.8.1. Range Coding Mode
+    pub fn new() -> Self {
+        let mut encoder = Self {
+            buf: Vec::new(),
+            low: 0,
+            // Figure 13.
+            rng: 0xFF00,
+            outstanding_count: 0,
+            outstanding_byte: -1,
+            zero_state: [0; 256],
+            one_state: [0; 256],
+        };
+
+        // 3.8.1.3. Initial Values for the Context Model
+        encoder.set_table(&DEFAULT_STATE_TRANSITION);
+        encoder
+    }
+
+    /// Writes out the bytes that can no longer change.
+    ///
+    /// A byte followed by 0xFF bytes may still be affected by a carry, so
+    /// those are held back until it is known.
+    fn renorm(&mut self) {
+        while self.rng < 0x100 {
+            if self.outstanding_byte < 0 {
+                self.outstanding_byte = (self.low >> 8) as i32;
+            } else if self.low <= 0xFF00 {
+                self.buf.push(self.outstanding_byte as u8);
+                for _ in 0..self.outstanding_count {
+                    self.buf.push(0xFF);
+                }
+                self.outstanding_count = 0;
+                self.outstanding_byte = (self.low >> 8) as i32;
+            } else if self.low >= 0x10000 {
+                self.buf.push((self.outstanding_byte + 1) as u8);
+                for _ in 0..self.outstanding_count {
+                    self.buf.push(0x00);
+                }
+                self.outstanding_count = 0;
+                self.outstanding_byte = (self.low >> 8) as i32 - 0x100;
+            } else {
+                self.outstanding_count += 1;
+            }
+
+            self.low = (self.low & 0xFF) << 8;
+            self.rng <<= 8;
+        }
+    }
+
+    /// Puts the next boolean state.
+    pub fn put(&mut self, state: &mut u8, bit: bool) {
+        // Figure 10.
+        let rangeoff = (self.rng * *state as u32) >> 8;
+        if bit {
+            self.low += self.rng - rangeoff;
+            self.rng = rangeoff;
+            *state = self.one_state[*state as usize];
+        } else {
+            self.rng -= rangeoff;
+            *state = self.zero_state[*state as usize];
+        }
+        self.renorm();
+    }
+
+    /// Puts the next range coded unsigned scalar symbol.
+    ///
+    /// See: 4. Bitstream
+    pub fn put_ur(&mut self, state: &mut [u8], v: u32) {
+        self.put_magnitude(state, v, None);
+    }
+
+    /// Puts the next range coded signed scalar symbol.
+    ///
+    /// See: 4. Bitstream
+    pub fn put_sr(&mut self, state: &mut [u8], v: i32) {
+        self.put_magnitude(state, v.unsigned_abs(), Some(v < 0));
+    }
+
+    /// Puts the next range coded Boolean symbol.
+    ///
+    /// See: 4. Bitstream
+    pub fn put_bool(&mut self, state: &mut [u8], v: bool) {
+        self.put(&mut state[0], v);
+    }
+
+    /// Puts the next range coded symbol.
+    ///
+    /// Unsigned symbols are read back as 'v as u32' by RangeCoder::ur.
+    ///
+    /// See: 3.8.1.2. Range Non Binary Values
+    pub fn put_symbol(&mut self, state: &mut [u8], v: i32, signed: bool) {
+        if signed {
+            self.put_sr(state, v);
+        } else {
+            self.put_ur(state, v as u32);
+        }
+    }
+
+    /// Puts the magnitude of a symbol, followed by its sign for signed
+    /// symbols.
+    ///
+    /// See: 3.8.1.2. Range Non Binary Values
+    fn put_magnitude(&mut self, state: &mut [u8], a: u32, sign: Option<bool>) {
+        if a == 0 {
+            self.put(&mut state[0], true);
+            return;
+        }
+
+        let e = 31 - a.leading_zeros() as i32;
+
+        self.put(&mut state[0], false);
+        for i in 0..e {
+            self.put(&mut state[1 + min32(i, 9) as usize], true);
+        }
+        self.put(&mut state[1 + min32(e, 9) as usize], false);
+
+        for i in (0..e).rev() {
+            self.put(&mut state[22 + min32(i, 9) as usize], (a >> i) & 1 == 1);
+        }
+
+        if let Some(sign) = sign {
+            self.put(&mut state[11 + min32(e, 10) as usize], sign);
+        }
+    }
+
+    pub fn set_table(&mut self, table: &[u8; 256]) {
+        // 3.8.1.4. State Transition Table
+
+        // Figure 17.
+        self.one_state[..256].clone_from_slice(&table[..256]);
+
+        // Figure 18.
+        for i in 1..255 {
+            self.zero_state[i] = (256 - self.one_state[256 - i] as u16) as u8;
+        }
+    }
+
+    /// Puts the sentinel symbol ending a range coded bytestream, which
+    /// RangeCoder reads with sentinal_end. 'finish' must be called next.
+    ///
+    /// Once it has read the sentinel, RangeCoder is one byte past the end
+    /// of the bytestream, so what follows can be found without knowing
+    /// its length.
+    ///
+    /// See: 3.8.1.1.1. Termination
+    ///        * Sentinal Mode
+    pub fn sentinal_end(&mut self) {
+        let mut state: u8 = 129;
+        self.put(&mut state, false);
+    }
+
+    /// Flushes the range encoder and returns the coded bytes.
+    ///
+    /// Without a sentinel, the decoder must be given the exact length of
+    /// the bytestream, since anything after it would be read as part of
+    /// it.
+    ///
+    /// See: 3.8.1.1.1. Termination
+    pub fn finish(mut self) -> Vec<u8> {
+        self.rng = 0xFF;
+        self.low += 0xFF;
+        self.renorm();
+        self.rng = 0xFF;
+        self.renorm();
+
+        // RangeCoder needs two bytes to start, and bytes past the end are
+        // read as zeros anyway.
+        if self.buf.len() < 2 {
+            self.buf.resize(2, 0);
+        }
+
+        self.buf
+    }
+}
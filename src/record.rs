@@ -1,7 +1,8 @@
 use crate::constants::{CONTEXT_SIZE, MAX_CONTEXT_INPUTS, MAX_QUANT_TABLES};
-use crate::crc32mpeg2::crc32_mpeg2;
+use crate::crc32mpeg2::{crc32_mpeg2, crc32_mpeg2_parity};
 use crate::error::{Error, Result};
-use crate::range::RangeCoder;
+use crate::range::{RangeCoder, RangeEncoder};
+use crate::trace::{Location, Trace, Tracer};
 
 pub struct ConfigRecord {
     pub version: u8,
@@ -26,21 +27,112 @@ pub struct ConfigRecord {
     pub intra: u8,
 }
 
+impl Default for ConfigRecord {
+    fn default() -> Self {
+        ConfigRecord {
+            version: 0,
+            micro_version: 0,
+            coder_type: 0,
+            state_transition_delta: [0; 256],
+            colorspace_type: 0,
+            bits_per_raw_sample: 8,
+            chroma_planes: false,
+            log2_h_chroma_subsample: 0,
+            log2_v_chroma_subsample: 0,
+            extra_plane: false,
+            num_h_slices_minus1: 0,
+            num_v_slices_minus1: 0,
+            quant_table_set_count: 0,
+            context_count: [0; MAX_QUANT_TABLES as usize],
+            quant_tables: [[[0; 256]; MAX_CONTEXT_INPUTS as usize];
+                MAX_QUANT_TABLES as usize],
+            states_coded: false,
+            initial_state_delta: Vec::new(),
+            ec: 0,
+            intra: 0,
+        }
+    }
+}
+
 impl ConfigRecord {
     /// Parses the configuration record from the codec private data.
     ///
     /// See: * 4.1. Parameters
     ///      * 4.2. Configuration Record
     pub fn parse_config_record(buf: &[u8]) -> Result<Self> {
-        // Before we do anything, CRC check.
+        Self::parse_config_record_traced(buf, &mut Tracer::disabled())
+    }
+
+    /// Parses the configuration record like parse_config_record, passing
+    /// every syntax element read on to 'trace'.
+    pub fn parse_config_record_with_trace(
+        buf: &[u8],
+        trace: &mut dyn Trace,
+    ) -> Result<Self> {
+        let mut tracer = Tracer::new(Some(trace), Location::ConfigRecord);
+        Self::parse_config_record_traced(buf, &mut tracer)
+    }
+
+    fn parse_config_record_traced(
+        buf: &[u8],
+        tracer: &mut Tracer,
+    ) -> Result<Self> {
+        let mut coder = RangeCoder::new(buf)?;
+        let record = Self::parse_parameters(&mut coder, true, tracer)?;
+
+        // Only version 3 and later protect the record with a CRC.
         //
         // See: 4.2.2. configuration_record_crc_parity
-        if crc32_mpeg2(buf) != 0 {
+        if record.version >= 3 && buf.len() >= 4 {
+            let parity = &buf[buf.len() - 4..];
+            tracer.element(
+                "4.2.2. configuration_record_crc_parity",
+                u32::from_be_bytes([
+                    parity[0], parity[1], parity[2], parity[3],
+                ]) as i64,
+                buf.len() - 4,
+            );
+        }
+        if record.version >= 3 && crc32_mpeg2(buf) != 0 {
             return Err(Error::InvalidConfiguration(
                 "failed CRC check for configuration record".to_owned(),
             ));
         }
-        let mut coder = RangeCoder::new(buf);
+
+        Ok(record)
+    }
+
+    /// Parses the parameters coded in the header of a keyframe.
+    ///
+    /// This is where FFV1 versions 0 and 1, which have no configuration
+    /// record, store them. 'coder' must be positioned right after the
+    /// keyframe bit.
+    ///
+    /// See: * 4.1. Parameters
+    ///      * 4.3. Frame
+    pub fn parse_keyframe_header(coder: &mut RangeCoder) -> Result<Self> {
+        Self::parse_parameters(coder, false, &mut Tracer::disabled())
+    }
+
+    /// Parses the parameters coded in the header of a keyframe like
+    /// parse_keyframe_header, passing every syntax element read on to
+    /// 'tracer'.
+    pub(crate) fn parse_keyframe_header_traced(
+        coder: &mut RangeCoder,
+        tracer: &mut Tracer,
+    ) -> Result<Self> {
+        Self::parse_parameters(coder, false, tracer)
+    }
+
+    /// Parses the parameters, either from the configuration record or from
+    /// the header of a keyframe.
+    ///
+    /// See: 4.1. Parameters
+    fn parse_parameters(
+        coder: &mut RangeCoder,
+        config_record: bool,
+        tracer: &mut Tracer,
+    ) -> Result<Self> {
         let mut state_transition_delta: [i16; 256] = [0; 256];
         let mut context_count: [i32; MAX_QUANT_TABLES as usize] =
             [0; MAX_QUANT_TABLES as usize];
@@ -54,23 +146,34 @@ impl ConfigRecord {
             [128; CONTEXT_SIZE as usize];
 
         // 4.1.1. version
-        let version = coder.ur(&mut state) as u8;
-        if version != 3 {
+        let version = tracer.ur(coder, &mut state, "4.1.1. version")? as u8;
+        if config_record && !(2..=4).contains(&version) {
             return Err(Error::InvalidConfiguration(
-                "only FFV1 version 3 is supported".to_owned(),
+                "only FFV1 versions 2 to 4 are supported".to_owned(),
             ));
         }
+        if !config_record && version > 1 {
+            return Err(Error::InvalidConfiguration(format!(
+                "version {} must be coded in a configuration record",
+                version
+            )));
+        }
 
         // 4.1.2. micro_version
-        let micro_version = coder.ur(&mut state) as u8;
-        if micro_version < 1 {
-            return Err(Error::InvalidConfiguration(
-                "only FFV1 micro version >1 supported".to_owned(),
-            ));
+        let mut micro_version = 0;
+        if version >= 3 {
+            micro_version =
+                tracer.ur(coder, &mut state, "4.1.2. micro_version")? as u8;
+            if micro_version < 1 {
+                return Err(Error::InvalidConfiguration(
+                    "only FFV1 micro version >1 supported".to_owned(),
+                ));
+            }
         }
 
         // 4.1.3. coder_type
-        let coder_type = coder.ur(&mut state) as u8;
+        let coder_type =
+            tracer.ur(coder, &mut state, "4.1.3. coder_type")? as u8;
         if coder_type > 2 {
             return Err(Error::InvalidConfiguration(format!(
                 "invalid coder_type: {}",
@@ -83,12 +186,17 @@ impl ConfigRecord {
             for state_transition_delta in
                 state_transition_delta.iter_mut().skip(1)
             {
-                *state_transition_delta = coder.sr(&mut state) as i16;
+                *state_transition_delta = tracer.sr(
+                    coder,
+                    &mut state,
+                    "4.1.4. state_transition_delta",
+                )? as i16;
             }
         }
 
         // 4.1.5. colorspace_type
-        let colorspace_type = coder.ur(&mut state) as u8;
+        let colorspace_type =
+            tracer.ur(coder, &mut state, "4.1.5. colorspace_type")? as u8;
         if colorspace_type > 1 {
             return Err(Error::InvalidConfiguration(format!(
                 "invalid colorspace_type: {}",
@@ -97,18 +205,27 @@ impl ConfigRecord {
         }
 
         // 4.1.7. bits_per_raw_sample
-        let mut bits_per_raw_sample = coder.ur(&mut state) as u8;
+        //
+        // Version 0 has no bits_per_raw_sample, and is always 8 bits.
+        let mut bits_per_raw_sample = 0;
+        if version >= 1 {
+            bits_per_raw_sample =
+                tracer.ur(coder, &mut state, "4.1.7. bits_per_raw_sample")?
+                    as u8;
+        }
         if bits_per_raw_sample == 0 {
             bits_per_raw_sample = 8;
         }
-        if coder_type == 0 && bits_per_raw_sample != 8 {
-            return Err(Error::InvalidConfiguration(
-                "golomb-rice mode cannot have >8bit per sample".to_owned(),
-            ));
+        if bits_per_raw_sample > 16 {
+            return Err(Error::InvalidConfiguration(format!(
+                "unsupported bits_per_raw_sample: {}",
+                bits_per_raw_sample
+            )));
         }
 
         // 4.1.6. chroma_planes
-        let chroma_planes = coder.br(&mut state);
+        let chroma_planes =
+            tracer.br(coder, &mut state, "4.1.6. chroma_planes");
         if colorspace_type == 1 && !chroma_planes {
             return Err(Error::InvalidConfiguration(
                 "RGB must contain chroma planes".to_owned(),
@@ -116,7 +233,9 @@ impl ConfigRecord {
         }
 
         // 4.1.8. log2_h_chroma_subsample
-        let log2_h_chroma_subsample = coder.ur(&mut state) as u8;
+        let log2_h_chroma_subsample =
+            tracer.ur(coder, &mut state, "4.1.8. log2_h_chroma_subsample")?
+                as u8;
         if colorspace_type == 1 && log2_h_chroma_subsample != 0 {
             return Err(Error::InvalidConfiguration(
                 "RGB cannot be subsampled".to_owned(),
@@ -124,22 +243,43 @@ impl ConfigRecord {
         }
 
         // 4.1.9. log2_v_chroma_subsample
-        let log2_v_chroma_subsample = coder.ur(&mut state) as u8;
+        let log2_v_chroma_subsample =
+            tracer.ur(coder, &mut state, "4.1.9. log2_v_chroma_subsample")?
+                as u8;
         if colorspace_type == 1 && log2_v_chroma_subsample != 0 {
             return Err(Error::InvalidConfiguration(
                 "RGB cannot be subsampled".to_owned(),
             ));
         }
+        if log2_h_chroma_subsample > 2 || log2_v_chroma_subsample > 2 {
+            return Err(Error::InvalidConfiguration(format!(
+                "unsupported chroma subsampling: {} and {}",
+                log2_h_chroma_subsample, log2_v_chroma_subsample
+            )));
+        }
 
         // 4.1.10. extra_plane
-        let extra_plane = coder.br(&mut state);
-        // 4.1.11. num_h_slices
-        let num_h_slices_minus1 = coder.ur(&mut state) as u8;
-        // 4.1.12. num_v_slices
-        let num_v_slices_minus1 = coder.ur(&mut state) as u8;
-
-        // 4.1.13. quant_table_set_count
-        let quant_table_set_count = coder.ur(&mut state) as u8;
+        let extra_plane = tracer.br(coder, &mut state, "4.1.10. extra_plane");
+
+        // Versions 0 and 1 have a single slice and a single quantization
+        // table set.
+        let mut num_h_slices_minus1 = 0;
+        let mut num_v_slices_minus1 = 0;
+        let mut quant_table_set_count = 1;
+        if version >= 2 {
+            // 4.1.11. num_h_slices
+            num_h_slices_minus1 =
+                tracer.ur(coder, &mut state, "4.1.11. num_h_slices")? as u8;
+            // 4.1.12. num_v_slices
+            num_v_slices_minus1 =
+                tracer.ur(coder, &mut state, "4.1.12. num_v_slices")? as u8;
+            // 4.1.13. quant_table_set_count
+            quant_table_set_count = tracer.ur(
+                coder,
+                &mut state,
+                "4.1.13. quant_table_set_count",
+            )? as u8;
+        }
         if quant_table_set_count == 0 {
             return Err(Error::InvalidConfiguration(
                 "quant_table_set_count may not be zero".to_owned(),
@@ -153,28 +293,32 @@ impl ConfigRecord {
 
         for i in 0..quant_table_set_count as usize {
             // 4.9.  Quantization Table Set
-            let mut scale = 1;
-            for j in 0..MAX_CONTEXT_INPUTS as usize {
+            let mut runs: Vec<Vec<usize>> =
+                vec![Vec::new(); MAX_CONTEXT_INPUTS as usize];
+            for runs in runs.iter_mut() {
                 // Each table has its own state table.
                 let mut quant_state: [u8; CONTEXT_SIZE as usize] =
                     [128; CONTEXT_SIZE as usize];
-                let mut v = 0;
                 let mut k = 0;
                 while k < 128 {
-                    let len_minus1 = coder.ur(&mut quant_state);
-                    for _ in 0..(len_minus1 + 1) as usize {
-                        quant_tables[i][j][k] = (scale * v) as i16;
-                        k += 1;
+                    let len = tracer.ur(
+                        coder,
+                        &mut quant_state,
+                        "4.9. len_minus1",
+                    )? as usize
+                        + 1;
+                    if len > 128 - k {
+                        return Err(Error::InvalidConfiguration(
+                            "quantization table runs past 128 entries"
+                                .to_owned(),
+                        ));
                     }
-                    v += 1;
+                    runs.push(len);
+                    k += len;
                 }
-                for k in 1..128 {
-                    quant_tables[i][j][256 - k] = -quant_tables[i][j][k];
-                }
-                quant_tables[i][j][128] = -quant_tables[i][j][127];
-                scale *= 2 * v - 1;
             }
-            context_count[i] = (scale + 1) as i32 / 2;
+            context_count[i] =
+                expand_quant_table_set(&runs, &mut quant_tables[i])?;
         }
 
         // Why on earth did they choose to do a variable length buffer in the
@@ -187,21 +331,34 @@ impl ConfigRecord {
             for j in 0..context_count[i] as usize {
                 initial_state_delta[i][j] = vec![0; CONTEXT_SIZE as usize];
             }
-            let states_coded = coder.br(&mut state);
+            // Without a configuration record, states always start at 128.
+            if version < 2 {
+                continue;
+            }
+            let states_coded =
+                tracer.br(coder, &mut state, "4.1.14. states_coded");
             if states_coded {
                 for j in 0..context_count[i] as usize {
                     for k in 0..CONTEXT_SIZE as usize {
-                        initial_state_delta[i][j][k] =
-                            coder.sr(&mut state) as i16;
+                        initial_state_delta[i][j][k] = tracer.sr(
+                            coder,
+                            &mut state,
+                            "4.1.15. initial_state_delta",
+                        )?
+                            as i16;
                     }
                 }
             }
         }
 
-        // 4.1.16. ec
-        let ec = coder.ur(&mut state) as u8;
-        // 4.1.17. intra
-        let intra = coder.ur(&mut state) as u8;
+        let mut ec = 0;
+        let mut intra = 0;
+        if version >= 3 {
+            // 4.1.16. ec
+            ec = tracer.ur(coder, &mut state, "4.1.16. ec")? as u8;
+            // 4.1.17. intra
+            intra = tracer.ur(coder, &mut state, "4.1.17. intra")? as u8;
+        }
 
         let config_record = ConfigRecord {
             version,
@@ -227,4 +384,286 @@ impl ConfigRecord {
 
         Ok(config_record)
     }
+
+    /// Returns the number of quantization table set indexes coded for each
+    /// slice, one for each plane context.
+    ///
+    /// See: 4.5.5. quant_table_set_index_count
+    pub(crate) fn quant_table_set_index_count(&self) -> usize {
+        let mut quant_table_set_index_count = 1;
+        if self.chroma_planes || self.version < 4 {
+            quant_table_set_index_count += 1;
+        }
+        if self.extra_plane {
+            quant_table_set_index_count += 1;
+        }
+        quant_table_set_index_count
+    }
+
+    /// Sets quantization table set 'i' from the run lengths of its tables,
+    /// as they are coded, along with its context count.
+    ///
+    /// See: 4.9. Quantization Table Set
+    pub fn set_quant_table_set<R: AsRef<[usize]>>(
+        &mut self,
+        i: usize,
+        runs: &[R],
+    ) -> Result<()> {
+        if i >= MAX_QUANT_TABLES as usize {
+            return Err(Error::InvalidConfiguration(format!(
+                "too many quant tables: {} > {}",
+                i + 1,
+                MAX_QUANT_TABLES
+            )));
+        }
+        if runs.len() != MAX_CONTEXT_INPUTS as usize
+            || runs.iter().any(|runs| {
+                runs.as_ref().contains(&0)
+                    || runs.as_ref().iter().sum::<usize>() != 128
+            })
+        {
+            return Err(Error::InvalidConfiguration(format!(
+                "quant table set {} must have {} tables of 128 entries",
+                i, MAX_CONTEXT_INPUTS
+            )));
+        }
+
+        self.context_count[i] =
+            expand_quant_table_set(runs, &mut self.quant_tables[i])?;
+
+        Ok(())
+    }
+
+    /// Writes the configuration record, as parse_config_record reads it.
+    ///
+    /// States are coded for the quantization table sets whose
+    /// initial_state_delta is not all zeros.
+    ///
+    /// See: * 4.1. Parameters
+    ///      * 4.2. Configuration Record
+    pub fn write_config_record(&self) -> Result<Vec<u8>> {
+        if !(2..=4).contains(&self.version) {
+            return Err(Error::InvalidConfiguration(
+                "only FFV1 versions 2 to 4 are supported".to_owned(),
+            ));
+        }
+
+        let mut coder = RangeEncoder::new();
+        self.write_parameters(&mut coder)?;
+        let mut buf = coder.finish();
+
+        // Only version 3 and later protect the record with a CRC.
+        //
+        // See: 4.2.2. configuration_record_crc_parity
+        if self.version >= 3 {
+            let parity = crc32_mpeg2_parity(&buf);
+            buf.extend_from_slice(&parity);
+        }
+
+        Ok(buf)
+    }
+
+    /// Writes the parameters coded in the header of a keyframe, for FFV1
+    /// versions 0 and 1. 'coder' must be positioned right after the
+    /// keyframe bit.
+    ///
+    /// See: * 4.1. Parameters
+    ///      * 4.3. Frame
+    pub fn write_keyframe_header(
+        &self,
+        coder: &mut RangeEncoder,
+    ) -> Result<()> {
+        if self.version > 1 {
+            return Err(Error::InvalidConfiguration(format!(
+                "version {} must be coded in a configuration record",
+                self.version
+            )));
+        }
+
+        self.write_parameters(coder)
+    }
+
+    /// Writes the parameters, either to the configuration record or to
+    /// the header of a keyframe.
+    ///
+    /// See: 4.1. Parameters
+    fn write_parameters(&self, coder: &mut RangeEncoder) -> Result<()> {
+        let quant_table_set_count = if self.version >= 2 {
+            self.quant_table_set_count
+        } else {
+            1
+        };
+        if quant_table_set_count == 0
+            || quant_table_set_count > MAX_QUANT_TABLES
+        {
+            return Err(Error::InvalidConfiguration(format!(
+                "invalid quant_table_set_count: {}",
+                quant_table_set_count
+            )));
+        }
+
+        // 4. Bitstream
+        let mut state: [u8; CONTEXT_SIZE as usize] =
+            [128; CONTEXT_SIZE as usize];
+
+        // 4.1.1. version
+        coder.put_ur(&mut state, self.version as u32);
+
+        // 4.1.2. micro_version
+        if self.version >= 3 {
+            coder.put_ur(&mut state, self.micro_version as u32);
+        }
+
+        // 4.1.3. coder_type
+        coder.put_ur(&mut state, self.coder_type as u32);
+
+        // 4.1.4. state_transition_delta
+        if self.coder_type > 1 {
+            for &state_transition_delta in
+                self.state_transition_delta.iter().skip(1)
+            {
+                coder.put_sr(&mut state, state_transition_delta as i32);
+            }
+        }
+
+        // 4.1.5. colorspace_type
+        coder.put_ur(&mut state, self.colorspace_type as u32);
+
+        // 4.1.7. bits_per_raw_sample
+        if self.version >= 1 {
+            coder.put_ur(&mut state, self.bits_per_raw_sample as u32);
+        }
+
+        // 4.1.6. chroma_planes
+        coder.put_bool(&mut state, self.chroma_planes);
+
+        // 4.1.8. log2_h_chroma_subsample
+        coder.put_ur(&mut state, self.log2_h_chroma_subsample as u32);
+
+        // 4.1.9. log2_v_chroma_subsample
+        coder.put_ur(&mut state, self.log2_v_chroma_subsample as u32);
+
+        // 4.1.10. extra_plane
+        coder.put_bool(&mut state, self.extra_plane);
+
+        if self.version >= 2 {
+            // 4.1.11. num_h_slices
+            coder.put_ur(&mut state, self.num_h_slices_minus1 as u32);
+            // 4.1.12. num_v_slices
+            coder.put_ur(&mut state, self.num_v_slices_minus1 as u32);
+            // 4.1.13. quant_table_set_count
+            coder.put_ur(&mut state, quant_table_set_count as u32);
+        }
+
+        for quant_tables in self
+            .quant_tables
+            .iter()
+            .take(quant_table_set_count as usize)
+        {
+            // 4.9.  Quantization Table Set
+            for quant_table in quant_tables.iter() {
+                // Each table has its own state table.
+                let mut quant_state: [u8; CONTEXT_SIZE as usize] =
+                    [128; CONTEXT_SIZE as usize];
+                for len in quant_table_runs(quant_table) {
+                    coder.put_ur(&mut quant_state, len as u32 - 1);
+                }
+            }
+        }
+
+        // Without a configuration record, states always start at 128.
+        if self.version >= 2 {
+            for i in 0..quant_table_set_count as usize {
+                let initial_state_delta = self
+                    .initial_state_delta
+                    .get(i)
+                    .map_or(&[][..], |deltas| deltas.as_slice());
+                // 4.1.14. states_coded
+                let states_coded = initial_state_delta
+                    .iter()
+                    .any(|deltas| deltas.iter().any(|&delta| delta != 0));
+                coder.put_bool(&mut state, states_coded);
+                if !states_coded {
+                    continue;
+                }
+
+                // 4.1.15. initial_state_delta
+                if initial_state_delta.len() != self.context_count[i] as usize
+                    || initial_state_delta
+                        .iter()
+                        .any(|deltas| deltas.len() != CONTEXT_SIZE as usize)
+                {
+                    return Err(Error::InvalidConfiguration(format!(
+                        "initial_state_delta of quant table set {} does not \
+                         match its {} contexts",
+                        i, self.context_count[i]
+                    )));
+                }
+                for deltas in initial_state_delta {
+                    for &delta in deltas {
+                        coder.put_sr(&mut state, delta as i32);
+                    }
+                }
+            }
+        }
+
+        if self.version >= 3 {
+            // 4.1.16. ec
+            coder.put_ur(&mut state, self.ec as u32);
+            // 4.1.17. intra
+            coder.put_ur(&mut state, self.intra as u32);
+        }
+
+        Ok(())
+    }
+}
+
+/// Expands the run lengths of the tables of a quantization table set into
+/// 'quant_tables', and returns the number of contexts of the set.
+///
+/// See: 4.9. Quantization Table Set
+fn expand_quant_table_set<R: AsRef<[usize]>>(
+    runs: &[R],
+    quant_tables: &mut [[i16; 256]; MAX_CONTEXT_INPUTS as usize],
+) -> Result<i32> {
+    let mut scale = 1;
+    for (quant_table, runs) in quant_tables.iter_mut().zip(runs) {
+        let mut v = 0;
+        let mut k = 0;
+        for &len in runs.as_ref() {
+            for _ in 0..len {
+                quant_table[k] = (scale * v) as i16;
+                k += 1;
+            }
+            v += 1;
+        }
+        for k in 1..128 {
+            quant_table[256 - k] = quant_table[k].wrapping_neg();
+        }
+        quant_table[128] = quant_table[127].wrapping_neg();
+        scale *= 2 * v - 1;
+        // This also keeps the quantized values within an i16.
+        if scale > 65535 {
+            return Err(Error::InvalidConfiguration(
+                "too many contexts".to_owned(),
+            ));
+        }
+    }
+
+    Ok((scale + 1) / 2)
+}
+
+/// Gets the run lengths a quantization table is coded with.
+///
+/// See: 4.9. Quantization Table Set
+fn quant_table_runs(quant_table: &[i16; 256]) -> Vec<usize> {
+    let mut runs: Vec<usize> = Vec::new();
+    for k in 0..128 {
+        if k > 0 && quant_table[k] == quant_table[k - 1] {
+            *runs.last_mut().unwrap() += 1;
+        } else {
+            runs.push(1);
+        }
+    }
+    runs
 }
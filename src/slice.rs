@@ -1,13 +1,19 @@
 use crate::constants::CONTEXT_SIZE;
 use crate::error::{Error, Result};
+use crate::frame::Concealed;
 use crate::golomb::State;
 use crate::range::RangeCoder;
+use crate::record::ConfigRecord;
+use crate::trace::{Element, Location, Trace, Tracer};
 
 #[derive(Clone, Default)]
 pub struct InternalFrame {
     pub keyframe: bool,
     pub slice_info: Vec<SliceInfo>,
     pub slices: Vec<Slice>,
+    /// Slice headers coded in the header of the last keyframe, for
+    /// versions older than 3, whose slices have no header of their own.
+    pub slice_headers: Vec<SliceHeader>,
 }
 
 #[derive(Clone, Default)]
@@ -17,6 +23,47 @@ pub struct SliceInfo {
     pub(crate) error_status: u8,
 }
 
+/// FrameIntegrity reports the integrity of a packet, as checked by
+/// Decoder::verify_frame without decoding it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FrameIntegrity {
+    /// Whether or not the packet codes a keyframe.
+    pub keyframe: bool,
+    /// Integrity of each slice, in coding order.
+    pub slices: Vec<SliceIntegrity>,
+}
+
+impl FrameIntegrity {
+    /// Returns whether or not every slice of the packet is intact.
+    pub fn is_intact(&self) -> bool {
+        self.slices.iter().all(SliceIntegrity::is_intact)
+    }
+}
+
+/// SliceIntegrity reports the footer of a slice and whether the slice
+/// matches its CRC.
+///
+/// See: 4.8. Slice Footer
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SliceIntegrity {
+    /// Offset of the slice in the packet, in bytes.
+    pub pos: usize,
+    /// Size of the slice, in bytes, without its footer.
+    pub size: usize,
+    /// Error status from the slice footer.
+    pub error_status: u8,
+    /// Whether or not the slice and its footer match slice_crc_parity.
+    pub crc_valid: bool,
+}
+
+impl SliceIntegrity {
+    /// Returns whether or not the slice matches its CRC and has no error
+    /// status.
+    pub fn is_intact(&self) -> bool {
+        self.error_status == 0 && self.crc_valid
+    }
+}
+
 #[derive(Clone, Default)]
 pub struct Slice {
     pub(crate) header: SliceHeader,
@@ -26,9 +73,70 @@ pub struct Slice {
     pub(crate) height: u32,
     pub(crate) state: Vec<Vec<Vec<u8>>>,
     pub(crate) golomb_state: Vec<Vec<State>>,
+    /// The last three decoded lines of each plane, oldest first, which is
+    /// as far up as prediction looks. Each line is written into the frame
+    /// as soon as it is decoded.
+    pub(crate) lines: Vec<Vec<u32>>,
+    /// Whether or not the states were left half-updated by a damaged
+    /// slice, so that only a reset makes them usable again.
+    pub(crate) states_damaged: bool,
+    /// How the slice was concealed in the last frame, if it was.
+    pub(crate) concealed: Option<Concealed>,
+    /// The slice in the previous frame, kept to conceal it with.
+    pub(crate) previous: PreviousSlice,
+    /// Syntax elements read from the slice, if the decoder is traced.
+    pub(crate) trace: Vec<Element>,
 }
 
+/// PreviousSlice holds what is needed to write a slice of the previous
+/// frame again.
 #[derive(Clone, Default)]
+pub struct PreviousSlice {
+    pub(crate) header: SliceHeader,
+    pub(crate) start_x: u32,
+    pub(crate) start_y: u32,
+    pub(crate) width: u32,
+    pub(crate) height: u32,
+    /// Samples of each plane, with a stride equal to the width of the
+    /// slice in that plane.
+    pub(crate) samples: Vec<Vec<u32>>,
+    pub(crate) valid: bool,
+}
+
+impl Slice {
+    /// Keeps the header and position of the slice, whose samples are
+    /// copied into 'previous.samples' by the caller, to conceal the slice
+    /// of the next frame with.
+    pub(crate) fn save_previous(&mut self) {
+        let previous = &mut self.previous;
+        previous.header.clone_from(&self.header);
+        previous.start_x = self.start_x;
+        previous.start_y = self.start_y;
+        previous.width = self.width;
+        previous.height = self.height;
+        previous.valid = true;
+    }
+
+    /// Takes the header of the slice of the previous frame back, if it was
+    /// kept and covers the same area, for the caller to write its samples
+    /// again.
+    ///
+    /// Returns whether or not it did.
+    pub(crate) fn restore_previous(&mut self) -> bool {
+        let previous = &self.previous;
+        if !previous.valid
+            || (previous.start_x, previous.start_y)
+                != (self.start_x, self.start_y)
+            || (previous.width, previous.height) != (self.width, self.height)
+        {
+            return false;
+        }
+
+        self.header.clone_from(&previous.header);
+        true
+    }
+}
+
 pub struct SliceHeader {
     pub(crate) slice_width_minus1: u32,
     pub(crate) slice_height_minus1: u32,
@@ -38,18 +146,305 @@ pub struct SliceHeader {
     pub(crate) picture_structure: u8,
     pub(crate) sar_num: u32,
     pub(crate) sar_den: u32,
+    pub(crate) reset_contexts: bool,
+    pub(crate) slice_coding_mode: u32,
+    pub(crate) slice_rct_by_coef: u32,
+    pub(crate) slice_rct_ry_coef: u32,
+}
+
+impl Default for SliceHeader {
+    fn default() -> Self {
+        Self {
+            slice_width_minus1: 0,
+            slice_height_minus1: 0,
+            slice_x: 0,
+            slice_y: 0,
+            quant_table_set_index: Vec::new(),
+            picture_structure: 0,
+            sar_num: 0,
+            sar_den: 0,
+            reset_contexts: false,
+            slice_coding_mode: 0,
+            // Streams that do not code the JPEG2000-RCT coefficients
+            // use these.
+            //
+            // See: * 4.5.12. slice_rct_by_coef
+            //      * 4.5.13. slice_rct_ry_coef
+            slice_rct_by_coef: 1,
+            slice_rct_ry_coef: 1,
+        }
+    }
+}
+
+impl Clone for SliceHeader {
+    fn clone(&self) -> Self {
+        let mut header = SliceHeader::default();
+        header.clone_from(self);
+        header
+    }
+
+    /// Copies 'source' without allocating, as long as the quantization
+    /// table set indexes fit.
+    fn clone_from(&mut self, source: &Self) {
+        self.slice_width_minus1 = source.slice_width_minus1;
+        self.slice_height_minus1 = source.slice_height_minus1;
+        self.slice_x = source.slice_x;
+        self.slice_y = source.slice_y;
+        self.quant_table_set_index
+            .clone_from(&source.quant_table_set_index);
+        self.picture_structure = source.picture_structure;
+        self.sar_num = source.sar_num;
+        self.sar_den = source.sar_den;
+        self.reset_contexts = source.reset_contexts;
+        self.slice_coding_mode = source.slice_coding_mode;
+        self.slice_rct_by_coef = source.slice_rct_by_coef;
+        self.slice_rct_ry_coef = source.slice_rct_ry_coef;
+    }
+}
+
+impl SliceHeader {
+    /// Resets every field to its default value, keeping the memory of the
+    /// quantization table set indexes.
+    pub(crate) fn reset(&mut self) {
+        let mut quant_table_set_index =
+            std::mem::take(&mut self.quant_table_set_index);
+        quant_table_set_index.clear();
+        *self = SliceHeader {
+            quant_table_set_index,
+            ..Default::default()
+        };
+    }
+
+    /// Reads the header of a slice of version 3 and later, with 'tracer'.
+    ///
+    /// Elements departing from the specification are passed on to
+    /// 'invalid', and reading carries on, so that every one of them is
+    /// found. Only errors of the range coder stop it.
+    ///
+    /// Returns whether or not the slice lies within the grid of slices.
+    ///
+    /// See: 4.5. Slice Header
+    pub(crate) fn read(
+        &mut self,
+        coder: &mut RangeCoder,
+        record: &ConfigRecord,
+        tracer: &mut Tracer,
+        invalid: &mut dyn FnMut(InvalidElement),
+    ) -> Result<bool> {
+        // 4. Bitstream
+        let mut state: [u8; CONTEXT_SIZE as usize] =
+            [128; CONTEXT_SIZE as usize];
+
+        // The header is left over from the previous frame.
+        self.reset();
+
+        let within_grid =
+            self.read_position(coder, &mut state, record, tracer, invalid)?;
+
+        // Reserved values are kept as such, however large.
+        //
+        // See: 4.5.7. picture_structure
+        let picture_structure =
+            tracer.ur(coder, &mut state, "4.5.7. picture_structure")?;
+        self.picture_structure = picture_structure.min(u8::MAX as u32) as u8;
+
+        // It's really weird for slices within the same frame to code
+        // their own SAR values...
+        //
+        // See: * 4.5.8. sar_num
+        //      * 4.5.9. sar_den
+        self.sar_num = tracer.ur(coder, &mut state, "4.5.8. sar_num")?;
+        self.sar_den = tracer.ur(coder, &mut state, "4.5.9. sar_den")?;
+
+        if record.version > 3 {
+            self.reset_contexts =
+                tracer.br(coder, &mut state, "4.5.10. reset_contexts");
+
+            self.slice_coding_mode =
+                tracer.ur(coder, &mut state, "4.5.11. slice_coding_mode")?;
+            if self.slice_coding_mode > 1 {
+                invalid(InvalidElement {
+                    location: tracer.location(),
+                    section: "4.5.11. slice_coding_mode",
+                    message: format!(
+                        "reserved value: {}",
+                        self.slice_coding_mode
+                    ),
+                });
+            }
+
+            if self.slice_coding_mode != 1 && record.colorspace_type == 1 {
+                self.slice_rct_by_coef = tracer.ur(
+                    coder,
+                    &mut state,
+                    "4.5.12. slice_rct_by_coef",
+                )?;
+                self.slice_rct_ry_coef = tracer.ur(
+                    coder,
+                    &mut state,
+                    "4.5.13. slice_rct_ry_coef",
+                )?;
+                if self.slice_rct_by_coef as u64
+                    + self.slice_rct_ry_coef as u64
+                    > 4
+                {
+                    invalid(InvalidElement {
+                        location: tracer.location(),
+                        section: "4.5.12. slice_rct_by_coef",
+                        message: format!(
+                            "invalid JPEG2000-RCT coefficients: {} and {}",
+                            self.slice_rct_by_coef, self.slice_rct_ry_coef
+                        ),
+                    });
+                }
+            }
+        }
+
+        Ok(within_grid)
+    }
+
+    /// Reads the position and the quantization table set indexes of a
+    /// slice with 'state', which come first in slice headers and in the
+    /// slice layout of version 2 keyframes alike. See read.
+    ///
+    /// Returns whether or not the slice lies within the grid of slices.
+    ///
+    /// See: * 4.5.1. slice_x
+    ///      * 4.5.2. slice_y
+    ///      * 4.5.3. slice_width
+    ///      * 4.5.4. slice_height
+    ///      * 4.5.6. quant_table_set_index
+    pub(crate) fn read_position(
+        &mut self,
+        coder: &mut RangeCoder,
+        state: &mut [u8],
+        record: &ConfigRecord,
+        tracer: &mut Tracer,
+        invalid: &mut dyn FnMut(InvalidElement),
+    ) -> Result<bool> {
+        self.slice_x = tracer.ur(coder, state, "4.5.1. slice_x")?;
+        self.slice_y = tracer.ur(coder, state, "4.5.2. slice_y")?;
+        self.slice_width_minus1 =
+            tracer.ur(coder, state, "4.5.3. slice_width")?;
+        self.slice_height_minus1 =
+            tracer.ur(coder, state, "4.5.4. slice_height")?;
+
+        let num_h_slices = record.num_h_slices_minus1 as u32 + 1;
+        let num_v_slices = record.num_v_slices_minus1 as u32 + 1;
+        let mut within_grid = true;
+        for &(start, size_minus1, num_slices, start_section, size_section) in &[
+            (
+                self.slice_x,
+                self.slice_width_minus1,
+                num_h_slices,
+                "4.5.1. slice_x",
+                "4.5.3. slice_width",
+            ),
+            (
+                self.slice_y,
+                self.slice_height_minus1,
+                num_v_slices,
+                "4.5.2. slice_y",
+                "4.5.4. slice_height",
+            ),
+        ] {
+            if start >= num_slices {
+                invalid(InvalidElement {
+                    location: tracer.location(),
+                    section: start_section,
+                    message: format!(
+                        "{} is past {} slices",
+                        start, num_slices
+                    ),
+                });
+                within_grid = false;
+            } else if start as u64 + size_minus1 as u64 >= num_slices as u64 {
+                invalid(InvalidElement {
+                    location: tracer.location(),
+                    section: size_section,
+                    message: format!(
+                        "{} slices from {} is past {} slices",
+                        size_minus1 as u64 + 1,
+                        start,
+                        num_slices
+                    ),
+                });
+                within_grid = false;
+            }
+        }
+
+        self.quant_table_set_index.clear();
+        for _ in 0..record.quant_table_set_index_count() {
+            let index =
+                tracer.ur(coder, state, "4.5.6. quant_table_set_index")?;
+            if index >= record.quant_table_set_count as u32 {
+                invalid(InvalidElement {
+                    location: tracer.location(),
+                    section: "4.5.6. quant_table_set_index",
+                    message: format!(
+                        "{} is past {} sets",
+                        index, record.quant_table_set_count
+                    ),
+                });
+            }
+            self.quant_table_set_index.push(index as u8);
+        }
+
+        Ok(within_grid)
+    }
+}
+
+/// InvalidElement is a syntax element whose value departs from the FFV1
+/// specification.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) struct InvalidElement {
+    /// Where the element was read.
+    pub(crate) location: Location,
+    /// Section of the specification and name of the element, e.g.
+    /// "4.5.1. slice_x".
+    pub(crate) section: &'static str,
+    /// What is wrong with the value.
+    pub(crate) message: String,
+}
+
+impl From<InvalidElement> for Error {
+    fn from(invalid: InvalidElement) -> Self {
+        Error::SliceError(format!(
+            "{}: {}: {}",
+            invalid.location, invalid.section, invalid.message
+        ))
+    }
 }
 
 /// Determines whether a given frame is a keyframe.
 ///
 /// See: 4.3. Frame
-pub fn is_keyframe(buf: &[u8]) -> bool {
+pub fn is_keyframe(buf: &[u8]) -> Result<bool> {
     // 4. Bitstream
     let mut state: [u8; CONTEXT_SIZE as usize] = [128; CONTEXT_SIZE as usize];
 
-    let mut coder = RangeCoder::new(buf);
+    let mut coder = RangeCoder::new(buf)?;
 
-    coder.br(&mut state)
+    Ok(coder.br(&mut state))
+}
+
+/// Determines whether a given frame is a keyframe like is_keyframe, and
+/// passes the keyframe element on to 'trace', as read from frame number
+/// 'frame'.
+///
+/// See: 4.3. Frame
+pub fn is_keyframe_with_trace(
+    buf: &[u8],
+    frame: usize,
+    trace: &mut dyn Trace,
+) -> Result<bool> {
+    // 4. Bitstream
+    let mut state: [u8; CONTEXT_SIZE as usize] = [128; CONTEXT_SIZE as usize];
+
+    let mut coder = RangeCoder::new(buf)?;
+    let mut tracer = Tracer::new(Some(trace), Location::Frame(frame));
+
+    Ok(tracer.br(&mut coder, &mut state, "4.3. keyframe"))
 }
 
 /// Counts the number of slices in a frame, as described in
@@ -70,8 +465,12 @@ pub fn count_slices(
     // so we can derive the slice positions within the packet, and
     // allow multithreading.
     let mut end_pos = buf.len() as isize;
-    header.slice_info = Vec::new();
+    header.slice_info.clear();
     while end_pos > 0 {
+        if end_pos < footer_size as isize {
+            return Err(Error::SliceError("invalid slice footer".to_owned()));
+        }
+
         let mut info: SliceInfo = Default::default();
 
         // 4.8.1. slice_size
@@ -80,8 +479,10 @@ pub fn count_slices(
         size |= buf[end_pos as usize - footer_size + 2] as u32;
         info.size = size;
 
-        // 4.8.2. error_status
-        info.error_status = buf[end_pos as usize - footer_size + 3] as u8;
+        // 4.8.2. error_status, which is only there with error correction.
+        if ec {
+            info.error_status = buf[end_pos as usize - footer_size + 3];
+        }
 
         info.pos = end_pos - size as isize - footer_size as isize;
         let pos = info.pos;
@@ -98,3 +499,49 @@ pub fn count_slices(
 
     Ok(())
 }
+
+/// Finds the position of each slice of a version 2 frame, whose number of
+/// slices is coded in the header of the last keyframe.
+///
+/// Every slice but the first one ends with a footer holding its size, and
+/// the first slice takes up what is left at the start of the packet.
+pub fn locate_slices(
+    buf: &[u8],
+    header: &mut InternalFrame,
+    slice_count: usize,
+) -> Result<()> {
+    let footer_size = 3;
+
+    let mut end_pos = buf.len() as isize;
+    header.slice_info.clear();
+    for _ in 1..slice_count {
+        if end_pos < footer_size as isize {
+            return Err(Error::SliceError("invalid slice footer".to_owned()));
+        }
+
+        let mut info: SliceInfo = Default::default();
+
+        let mut size = (buf[end_pos as usize - footer_size] as u32) << 16;
+        size |= (buf[end_pos as usize - footer_size + 1] as u32) << 8;
+        size |= buf[end_pos as usize - footer_size + 2] as u32;
+        info.size = size;
+
+        info.pos = end_pos - size as isize - footer_size as isize;
+        if info.pos < 0 {
+            return Err(Error::SliceError("invalid slice footer".to_owned()));
+        }
+        end_pos = info.pos;
+        header.slice_info.push(info);
+    }
+
+    header.slice_info.push(SliceInfo {
+        pos: 0,
+        size: end_pos as u32,
+        error_status: 0,
+    });
+
+    // The slices were found back to front
+    header.slice_info.reverse();
+
+    Ok(())
+}
@@ -0,0 +1,211 @@
+use std::fmt;
+use std::io::{self, Write};
+
+use crate::error::Result;
+use crate::range::RangeCoder;
+
+/// Location is where in a stream a syntax element was read, or a
+/// violation was found.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Location {
+    /// The configuration record.
+    ConfigRecord,
+    /// A frame, by its index among the frames decoded or checked.
+    Frame(usize),
+    /// A slice, by the index of its frame and its index in the frame.
+    Slice(usize, usize),
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Location::ConfigRecord => write!(f, "configuration record"),
+            Location::Frame(frame) => write!(f, "frame {}", frame),
+            Location::Slice(frame, slice) => {
+                write!(f, "frame {}, slice {}", frame, slice)
+            }
+        }
+    }
+}
+
+/// Element is a syntax element read from a stream.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Element {
+    /// Where the element was read.
+    pub location: Location,
+    /// Section of the specification and name of the element, e.g.
+    /// "4.5.1. slice_x".
+    pub syntax: &'static str,
+    /// Value read.
+    pub value: i64,
+    /// Position of the next byte the range coder was to read when reading
+    /// the element, from the start of the configuration record or packet.
+    /// Range coders read ahead, so this is up to two bytes past where the
+    /// element starts.
+    pub pos: usize,
+}
+
+impl Element {
+    /// Returns the number of the section of the specification the element
+    /// is described in, e.g. "4.5.1".
+    pub fn section(&self) -> &'static str {
+        self.syntax.split_once(". ").map_or(self.syntax, |(s, _)| s)
+    }
+
+    /// Returns the name of the element, e.g. "slice_x".
+    pub fn name(&self) -> &'static str {
+        self.syntax.split_once(". ").map_or(self.syntax, |(_, n)| n)
+    }
+}
+
+impl fmt::Display for Element {
+    /// Formats the element as a JSON object, on a single line.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{{")?;
+        match self.location {
+            Location::ConfigRecord => write!(f, r#""record":true,"#)?,
+            Location::Frame(frame) => write!(f, r#""frame":{},"#, frame)?,
+            Location::Slice(frame, slice) => {
+                write!(f, r#""frame":{},"slice":{},"#, frame, slice)?
+            }
+        }
+        write!(
+            f,
+            r#""section":"{}","name":"{}","value":{},"pos":{}}}"#,
+            self.section(),
+            self.name(),
+            self.value,
+            self.pos
+        )
+    }
+}
+
+/// Trace receives the syntax elements read from a stream, in the order
+/// they are coded.
+pub trait Trace: Send {
+    fn element(&mut self, element: &Element);
+}
+
+impl Trace for Vec<Element> {
+    fn element(&mut self, element: &Element) {
+        self.push(element.clone());
+    }
+}
+
+/// JsonLines writes each syntax element as a JSON object on its own line.
+pub struct JsonLines<W: Write> {
+    writer: W,
+    error: Option<io::Error>,
+}
+
+impl<W: Write> JsonLines<W> {
+    /// Creates a trace writing to 'writer'.
+    pub fn new(writer: W) -> Self {
+        JsonLines {
+            writer,
+            error: None,
+        }
+    }
+
+    /// Flushes the trace, and returns its writer, or the first error
+    /// writing to it.
+    pub fn finish(mut self) -> io::Result<W> {
+        if let Some(err) = self.error.take() {
+            return Err(err);
+        }
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+impl<W: Write + Send> Trace for JsonLines<W> {
+    fn element(&mut self, element: &Element) {
+        if self.error.is_none() {
+            if let Err(err) = writeln!(self.writer, "{}", element) {
+                self.error = Some(err);
+            }
+        }
+    }
+}
+
+/// Tracer reads syntax elements with a range coder, and passes them on
+/// to a trace, if there is one.
+pub(crate) struct Tracer<'a> {
+    trace: Option<&'a mut dyn Trace>,
+    location: Location,
+}
+
+impl<'a> Tracer<'a> {
+    pub(crate) fn new(
+        trace: Option<&'a mut dyn Trace>,
+        location: Location,
+    ) -> Self {
+        Tracer { trace, location }
+    }
+
+    /// Returns where the elements read are located.
+    pub(crate) fn location(&self) -> Location {
+        self.location
+    }
+
+    /// Creates a tracer which passes elements on to nothing.
+    pub(crate) fn disabled() -> Self {
+        Self::new(None, Location::ConfigRecord)
+    }
+
+    /// Passes an element on to the trace.
+    pub(crate) fn element(
+        &mut self,
+        syntax: &'static str,
+        value: i64,
+        pos: usize,
+    ) {
+        if let Some(ref mut trace) = self.trace {
+            trace.element(&Element {
+                location: self.location,
+                syntax,
+                value,
+                pos,
+            });
+        }
+    }
+
+    /// Reads an unsigned scalar symbol.
+    pub(crate) fn ur(
+        &mut self,
+        coder: &mut RangeCoder,
+        state: &mut [u8],
+        syntax: &'static str,
+    ) -> Result<u32> {
+        let pos = coder.get_pos();
+        let value = coder.ur(state)?;
+        self.element(syntax, value as i64, pos as usize);
+        Ok(value)
+    }
+
+    /// Reads a signed scalar symbol.
+    pub(crate) fn sr(
+        &mut self,
+        coder: &mut RangeCoder,
+        state: &mut [u8],
+        syntax: &'static str,
+    ) -> Result<i32> {
+        let pos = coder.get_pos();
+        let value = coder.sr(state)?;
+        self.element(syntax, value as i64, pos as usize);
+        Ok(value)
+    }
+
+    /// Reads a Boolean symbol.
+    pub(crate) fn br(
+        &mut self,
+        coder: &mut RangeCoder,
+        state: &mut [u8],
+        syntax: &'static str,
+    ) -> bool {
+        let pos = coder.get_pos();
+        let value = coder.br(state);
+        self.element(syntax, value as i64, pos as usize);
+        value
+    }
+}
@@ -0,0 +1,104 @@
+//! Parses the BITMAPINFOHEADER which Video for Windows codec private data
+//! starts with, as found in AVI 'strf' chunks and in the CodecPrivate of
+//! V_MS/VFW/FOURCC Matroska tracks.
+
+use crate::error::{Error, Result};
+
+/// Size of a BITMAPINFOHEADER without anything after it.
+pub const BITMAP_INFO_HEADER_SIZE: usize = 40;
+
+/// BitmapInfoHeader is the header of a FFV1 VFW codec private data.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct BitmapInfoHeader {
+    /// biSize, the size of the header, including the configuration record
+    /// after it.
+    pub size: u32,
+    /// biWidth, the width of the frames.
+    pub width: i32,
+    /// biHeight, the height of the frames. It is negative for frames
+    /// stored top-down.
+    pub height: i32,
+    /// biBitCount, the number of bits per pixel.
+    pub bit_count: u16,
+    /// biCompression, the fourcc of the codec, which is "FFV1".
+    pub fourcc: [u8; 4],
+    /// Configuration record after the header, which is empty for FFV1
+    /// versions 0 and 1.
+    pub config_record: Vec<u8>,
+}
+
+impl BitmapInfoHeader {
+    /// Parses the BITMAPINFOHEADER at the start of 'buf', and takes the
+    /// configuration record out of what follows it.
+    ///
+    /// biSize counts the record, so anything past it, such as the padding
+    /// some muxers add, is left out. Some muxers set biSize to the size of
+    /// the header alone though, in which case the record is the whole rest
+    /// of 'buf'.
+    pub fn parse_bitmap_info_header(buf: &[u8]) -> Result<Self> {
+        if buf.len() < BITMAP_INFO_HEADER_SIZE {
+            return Err(Error::InvalidInputData(format!(
+                "BITMAPINFOHEADER is {} bytes, less than {}",
+                buf.len(),
+                BITMAP_INFO_HEADER_SIZE
+            )));
+        }
+
+        let size = read_u32(buf, 0);
+        let width = read_u32(buf, 4) as i32;
+        let height = read_u32(buf, 8) as i32;
+        let bit_count = u16::from_le_bytes([buf[14], buf[15]]);
+        let fourcc = [buf[16], buf[17], buf[18], buf[19]];
+
+        if &fourcc != b"FFV1" {
+            return Err(Error::InvalidInputData(format!(
+                "fourcc is {:?}, not FFV1",
+                String::from_utf8_lossy(&fourcc)
+            )));
+        }
+        if (size as usize) < BITMAP_INFO_HEADER_SIZE {
+            return Err(Error::InvalidInputData(format!(
+                "biSize is {}, less than {}",
+                size, BITMAP_INFO_HEADER_SIZE
+            )));
+        }
+        if size as usize > buf.len() {
+            return Err(Error::InvalidInputData(format!(
+                "biSize is {}, but there are only {} bytes",
+                size,
+                buf.len()
+            )));
+        }
+        if width <= 0 || height == 0 {
+            return Err(Error::InvalidInputData(format!(
+                "invalid dimensions: {}x{}",
+                width, height
+            )));
+        }
+
+        let record_end = if size as usize == BITMAP_INFO_HEADER_SIZE {
+            buf.len()
+        } else {
+            size as usize
+        };
+
+        Ok(BitmapInfoHeader {
+            size,
+            width,
+            height,
+            bit_count,
+            fourcc,
+            config_record: buf[BITMAP_INFO_HEADER_SIZE..record_end].to_vec(),
+        })
+    }
+
+    /// Returns the frame width and height, whichever way the frames are
+    /// stored.
+    pub fn dimensions(&self) -> (u32, u32) {
+        (self.width.unsigned_abs(), self.height.unsigned_abs())
+    }
+}
+
+fn read_u32(buf: &[u8], pos: usize) -> u32 {
+    u32::from_le_bytes([buf[pos], buf[pos + 1], buf[pos + 2], buf[pos + 3]])
+}
@@ -0,0 +1,125 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::fs::File;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use ffv1::decoder::Decoder;
+use ffv1::frame::PlaneMut;
+use ffv1::mkv::MkvReader;
+
+// Counts every allocation made by the test
+struct CountingAllocator;
+
+static ALLOCATIONS: AtomicUsize = AtomicUsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(
+        &self,
+        ptr: *mut u8,
+        layout: Layout,
+        new_size: usize,
+    ) -> *mut u8 {
+        ALLOCATIONS.fetch_add(1, Ordering::SeqCst);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: CountingAllocator = CountingAllocator;
+
+// Reads the frame size, the configuration record and the first packet of
+// a matroska file
+fn read_first_packet(input: &str) -> (u32, u32, Vec<u8>, Vec<u8>) {
+    let mut reader = MkvReader::new(File::open(input).unwrap())
+        .expect("Cannot parse the format headers");
+    let track = reader.tracks()[0].clone();
+
+    match reader.next_packet() {
+        Ok(Some(pkt)) => {
+            (track.width, track.height, track.config_record, pkt.data)
+        }
+        _ => panic!("No packet in {}", input),
+    }
+}
+
+// Decodes the same packet over and over, and returns how many allocations
+// all but the first decoding made
+fn count_allocations(input: &str) -> usize {
+    let (width, height, extradata, packet) = read_first_packet(input);
+    let mut decoder = Decoder::new(&extradata, width, height).unwrap();
+    decoder.set_threads(1).unwrap();
+
+    let pixel_format = decoder.pixel_format().unwrap();
+    let dimensions: Vec<_> = (0..pixel_format.num_planes())
+        .map(|p| pixel_format.plane_dimensions(p, width, height))
+        .collect();
+
+    if pixel_format.bit_depth() == 8 {
+        let mut bufs: Vec<Vec<u8>> = dimensions
+            .iter()
+            .map(|&(width, height)| vec![0; (width * height) as usize])
+            .collect();
+        let mut planes: Vec<_> = bufs
+            .iter_mut()
+            .zip(&dimensions)
+            .map(|(data, &(width, _))| PlaneMut {
+                data,
+                stride: width as usize,
+            })
+            .collect();
+
+        decoder.decode_frame_into_u8(&packet, &mut planes).unwrap();
+        let before = ALLOCATIONS.load(Ordering::SeqCst);
+        for _ in 0..4 {
+            decoder.decode_frame_into_u8(&packet, &mut planes).unwrap();
+        }
+        ALLOCATIONS.load(Ordering::SeqCst) - before
+    } else {
+        let mut bufs: Vec<Vec<u16>> = dimensions
+            .iter()
+            .map(|&(width, height)| vec![0; (width * height) as usize])
+            .collect();
+        let mut planes: Vec<_> = bufs
+            .iter_mut()
+            .zip(&dimensions)
+            .map(|(data, &(width, _))| PlaneMut {
+                data,
+                stride: width as usize,
+            })
+            .collect();
+
+        decoder.decode_frame_into_u16(&packet, &mut planes).unwrap();
+        let before = ALLOCATIONS.load(Ordering::SeqCst);
+        for _ in 0..4 {
+            decoder.decode_frame_into_u16(&packet, &mut planes).unwrap();
+        }
+        ALLOCATIONS.load(Ordering::SeqCst) - before
+    }
+}
+
+// The allocator is shared by the whole test binary, so this is the only
+// test in it.
+#[test]
+fn test_no_allocations() {
+    for input in [
+        "data/ffv1_v2_yuv420p.mkv",
+        "data/ffv1_v2_bgr0.mkv",
+        "data/ffv1_v3_yuv420p.mkv",
+        "data/ffv1_v3_bgr0.mkv",
+        "data/ffv1_v3_gbrp16le.mkv",
+        "data/ffv1_v4_yuv420p.mkv",
+        "data/ffv1_v4_bgr0.mkv",
+    ]
+    .iter()
+    {
+        assert_eq!(count_allocations(input), 0, "{}", input);
+    }
+}
@@ -0,0 +1,286 @@
+use std::io::Cursor;
+
+use ffv1::avi::AviReader;
+use ffv1::decoder::Decoder;
+use ffv1::encoder::{Encoder, EncoderConfig};
+
+const WIDTH: u32 = 32;
+const HEIGHT: u32 = 16;
+
+// Writes RIFF chunks, filling their sizes in once they are ended
+#[derive(Default)]
+struct RiffWriter {
+    buf: Vec<u8>,
+    open: Vec<usize>,
+}
+
+impl RiffWriter {
+    fn begin(&mut self, id: &[u8; 4], kind: &[u8; 4]) {
+        self.buf.extend_from_slice(id);
+        self.open.push(self.buf.len());
+        self.buf.extend_from_slice(&[0; 4]);
+        self.buf.extend_from_slice(kind);
+    }
+
+    fn end(&mut self) {
+        let start = self.open.pop().unwrap();
+        let size = (self.buf.len() - start - 4) as u32;
+        self.buf[start..start + 4].copy_from_slice(&size.to_le_bytes());
+    }
+
+    // Writes a chunk, and returns the position of its payload
+    fn chunk(&mut self, id: &[u8; 4], payload: &[u8]) -> u32 {
+        self.buf.extend_from_slice(id);
+        self.buf
+            .extend_from_slice(&(payload.len() as u32).to_le_bytes());
+        let pos = self.buf.len() as u32;
+        self.buf.extend_from_slice(payload);
+        if payload.len() % 2 == 1 {
+            self.buf.push(0);
+        }
+        pos
+    }
+
+    fn pos(&self) -> u32 {
+        self.buf.len() as u32
+    }
+}
+
+fn strh(kind: &[u8; 4], handler: &[u8; 4]) -> Vec<u8> {
+    let mut strh = [&kind[..], handler].concat();
+    strh.extend_from_slice(&[0; 12]);
+    // dwScale and dwRate, for 25 frames per second
+    strh.extend_from_slice(&1u32.to_le_bytes());
+    strh.extend_from_slice(&25u32.to_le_bytes());
+    strh.resize(56, 0);
+    strh
+}
+
+fn bitmap_info_header(record: &[u8]) -> Vec<u8> {
+    let mut strf = Vec::new();
+    strf.extend_from_slice(&(40 + record.len() as u32).to_le_bytes());
+    strf.extend_from_slice(&WIDTH.to_le_bytes());
+    strf.extend_from_slice(&HEIGHT.to_le_bytes());
+    strf.extend_from_slice(&[1, 0, 24, 0]);
+    strf.extend_from_slice(b"FFV1");
+    strf.extend_from_slice(&[0; 20]);
+    strf.extend_from_slice(record);
+    strf
+}
+
+// Writes the 'hdrl' list of a file with an audio stream, numbered 0, and
+// a FFV1 stream, numbered 1, and returns the position of the entries of
+// its OpenDML super index, if it has one with 'super_entries' entries
+fn write_hdrl(
+    avi: &mut RiffWriter,
+    record: &[u8],
+    super_entries: Option<u32>,
+) -> Option<usize> {
+    avi.begin(b"LIST", b"hdrl");
+    avi.chunk(b"avih", &[0; 56]);
+    avi.begin(b"LIST", b"strl");
+    avi.chunk(b"strh", &strh(b"auds", &[0; 4]));
+    avi.chunk(b"strf", &[0; 18]);
+    avi.end();
+    avi.begin(b"LIST", b"strl");
+    avi.chunk(b"strh", &strh(b"vids", b"FFV1"));
+    avi.chunk(b"strf", &bitmap_info_header(record));
+    let super_index = super_entries.map(|entries| {
+        let mut indx = vec![4, 0, 0, 0];
+        indx.extend_from_slice(&entries.to_le_bytes());
+        indx.extend_from_slice(b"01dc");
+        indx.resize(24 + 16 * entries as usize, 0);
+        avi.chunk(b"indx", &indx) as usize + 24
+    });
+    avi.end();
+    avi.end();
+    super_index
+}
+
+// Writes an OpenDML standard index of frames, given as the positions and
+// sizes of their payloads, and whether they are keyframes
+fn write_ix(avi: &mut RiffWriter, frames: &[(u32, u32, bool)]) -> u32 {
+    let mut ix = vec![2, 0, 0, 1];
+    ix.extend_from_slice(&(frames.len() as u32).to_le_bytes());
+    ix.extend_from_slice(b"01dc");
+    // qwBaseOffset, then dwReserved
+    ix.extend_from_slice(&[0; 12]);
+    for &(pos, size, keyframe) in frames {
+        ix.extend_from_slice(&pos.to_le_bytes());
+        let flag = if keyframe { 0 } else { 0x8000_0000 };
+        ix.extend_from_slice(&(size | flag).to_le_bytes());
+    }
+    avi.chunk(b"ix01", &ix) - 8
+}
+
+fn encode() -> (Vec<u8>, Vec<Vec<u8>>) {
+    let encoder = Encoder::new(EncoderConfig {
+        width: WIDTH,
+        height: HEIGHT,
+        ..Default::default()
+    })
+    .unwrap();
+    let chroma = vec![128; (WIDTH * HEIGHT / 4) as usize];
+    let frames = (0..3)
+        .map(|i| {
+            let luma = vec![i * 50; (WIDTH * HEIGHT) as usize];
+            encoder.encode_frame(&[&luma, &chroma, &chroma]).unwrap()
+        })
+        .collect();
+
+    (encoder.config_record().to_owned(), frames)
+}
+
+// Writes a file indexed by 'idx1', with its frames grouped in a 'rec '
+// list, and a dropped frame between the second and third ones
+fn write_idx1_file(
+    record: &[u8],
+    frames: &[Vec<u8>],
+    absolute: bool,
+    indexed: bool,
+) -> Vec<u8> {
+    let mut avi = RiffWriter::default();
+    avi.begin(b"RIFF", b"AVI ");
+    write_hdrl(&mut avi, record, None);
+
+    avi.begin(b"LIST", b"movi");
+    let movi = avi.pos() - 4;
+    let mut chunks = Vec::new();
+    avi.begin(b"LIST", b"rec ");
+    chunks.push((b"00wb", 0x10, avi.chunk(b"00wb", b"audio"), 5));
+    avi.end();
+    for (i, frame) in frames.iter().enumerate() {
+        if i == 2 {
+            chunks.push((b"01dc", 0, avi.chunk(b"01dc", &[]), 0));
+        }
+        let pos = avi.chunk(b"01dc", frame);
+        let flags = if i == 1 { 0 } else { 0x10 };
+        chunks.push((b"01dc", flags, pos, frame.len() as u32));
+    }
+    avi.end();
+
+    if indexed {
+        let mut idx1 = Vec::new();
+        for (id, flags, pos, size) in chunks {
+            let offset = if absolute { pos - 8 } else { pos - 8 - movi };
+            idx1.extend_from_slice(id);
+            idx1.extend_from_slice(&(flags as u32).to_le_bytes());
+            idx1.extend_from_slice(&offset.to_le_bytes());
+            idx1.extend_from_slice(&size.to_le_bytes());
+        }
+        avi.chunk(b"idx1", &idx1);
+    }
+    avi.end();
+
+    avi.buf
+}
+
+// Reads the frames of a file, and checks them against 'frames'
+fn check_frames(file: Vec<u8>, record: &[u8], frames: &[Vec<u8>]) {
+    let mut reader = AviReader::new(Cursor::new(file)).unwrap();
+    let stream = reader.stream().clone();
+    assert_eq!(stream.number, 1);
+    assert_eq!((stream.rate, stream.scale), (25, 1));
+    assert!(stream.header.config_record == record);
+    assert_eq!(stream.header.dimensions(), (WIDTH, HEIGHT));
+
+    let mut decoder = Decoder::from_vfw(&stream.codec_private).unwrap();
+    let packets: Vec<_> = (&mut reader).map(|p| p.unwrap()).collect();
+    assert_eq!(packets.len(), frames.len());
+    for (i, packet) in packets.iter().enumerate() {
+        assert!(packet.data == frames[i]);
+        let frame = decoder.decode_frame(&packet.data).unwrap();
+        let luma = frame.plane_u8(0).unwrap().data;
+        assert!(luma.iter().all(|&sample| sample == i as u8 * 50));
+    }
+}
+
+#[test]
+fn test_idx1() {
+    let (record, frames) = encode();
+
+    for &absolute in [false, true].iter() {
+        let file = write_idx1_file(&record, &frames, absolute, true);
+        let mut reader = AviReader::new(Cursor::new(&file)).unwrap();
+        assert_eq!(reader.frame_count(), 4);
+        let packets: Vec<_> = (&mut reader).map(|p| p.unwrap()).collect();
+        let frame_numbers: Vec<_> = packets.iter().map(|p| p.frame).collect();
+        assert_eq!(frame_numbers, [0, 1, 3]);
+        let timestamps: Vec<_> = packets.iter().map(|p| p.timestamp).collect();
+        assert_eq!(timestamps, [0, 40_000_000, 120_000_000]);
+        let keyframes: Vec<_> = packets.iter().map(|p| p.keyframe).collect();
+        assert_eq!(keyframes, [true, false, true]);
+
+        check_frames(file, &record, &frames);
+    }
+}
+
+#[test]
+fn test_no_index() {
+    let (record, frames) = encode();
+    let file = write_idx1_file(&record, &frames, false, false);
+    check_frames(file, &record, &frames);
+}
+
+#[test]
+fn test_odml_index() {
+    let (record, frames) = encode();
+
+    let mut avi = RiffWriter::default();
+    avi.begin(b"RIFF", b"AVI ");
+    let super_index = write_hdrl(&mut avi, &record, Some(2)).unwrap();
+    avi.begin(b"LIST", b"movi");
+    let first = avi.chunk(b"01dc", &frames[0]);
+    let second = avi.chunk(b"01dc", &frames[1]);
+    let ix0 = write_ix(
+        &mut avi,
+        &[
+            (first, frames[0].len() as u32, true),
+            (second, frames[1].len() as u32, false),
+        ],
+    );
+    avi.end();
+    avi.end();
+
+    // The frames of the AVIX chunk are only in the OpenDML index.
+    avi.begin(b"RIFF", b"AVIX");
+    avi.begin(b"LIST", b"movi");
+    let third = avi.chunk(b"01dc", &frames[2]);
+    let ix1 = write_ix(&mut avi, &[(third, frames[2].len() as u32, true)]);
+    avi.end();
+    avi.end();
+
+    let mut file = avi.buf;
+    for (i, &ix) in [ix0, ix1].iter().enumerate() {
+        let entry = super_index + 16 * i;
+        file[entry..entry + 8].copy_from_slice(&(ix as u64).to_le_bytes());
+    }
+
+    let reader = AviReader::new(Cursor::new(&file)).unwrap();
+    let keyframes: Vec<_> = reader.map(|p| p.unwrap().keyframe).collect();
+    assert_eq!(keyframes, [true, false, true]);
+
+    check_frames(file, &record, &frames);
+}
+
+#[test]
+fn test_invalid_files() {
+    let (record, frames) = encode();
+
+    assert!(AviReader::new(Cursor::new(b"RIFF\0\0\0\0WAVE")).is_err());
+
+    // No FFV1 stream
+    let mut file = write_idx1_file(&record, &frames, false, true);
+    let strf = file.windows(4).rposition(|w| w == b"strf").unwrap() + 8;
+    file[strf + 16..strf + 20].copy_from_slice(b"H264");
+    assert!(AviReader::new(Cursor::new(&file)).is_err());
+
+    // Last frame past the end of the file
+    let mut file = write_idx1_file(&record, &frames, false, true);
+    let len = file.len();
+    file[len - 4..].copy_from_slice(&0x7fff_ffffu32.to_le_bytes());
+    let mut reader = AviReader::new(Cursor::new(&file)).unwrap();
+    assert!(reader.next_packet().unwrap().is_some());
+    assert!(reader.next_packet().unwrap().is_some());
+    assert!(reader.next_packet().is_err());
+}
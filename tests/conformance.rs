@@ -0,0 +1,300 @@
+use ffv1::conformance::{Checker, Location};
+use ffv1::decoder::Decoder;
+use ffv1::encoder::{Encoder, EncoderConfig};
+use ffv1::range::RangeEncoder;
+use ffv1::record::ConfigRecord;
+
+const WIDTH: u32 = 64;
+const HEIGHT: u32 = 48;
+
+// Encodes a 4:2:0 frame with 2x2 slices, using quantization table set
+// 'context_model'
+fn encode(context_model: u8) -> (Vec<u8>, Vec<u8>) {
+    encode_with(EncoderConfig {
+        width: WIDTH,
+        height: HEIGHT,
+        context_model,
+        ..Default::default()
+    })
+}
+
+// Encodes a 4:2:0 frame with 'config'
+fn encode_with(config: EncoderConfig) -> (Vec<u8>, Vec<u8>) {
+    let encoder = Encoder::new(config).unwrap();
+    let luma: Vec<u8> = (0..WIDTH * HEIGHT)
+        .map(|i| (i % WIDTH + i / WIDTH) as u8)
+        .collect();
+    let chroma = vec![128; (WIDTH * HEIGHT / 4) as usize];
+    let packet = encoder.encode_frame(&[&luma, &chroma, &chroma]).unwrap();
+
+    (encoder.config_record().to_owned(), packet)
+}
+
+// Rewrites a configuration record
+fn rewrite_record(
+    record: &[u8],
+    rewrite: impl FnOnce(&mut ConfigRecord),
+) -> Vec<u8> {
+    let mut record = ConfigRecord::parse_config_record(record).unwrap();
+    rewrite(&mut record);
+    record.write_config_record().unwrap()
+}
+
+// Codes the header of a version 1 frame, with the parameters of
+// 'record' if it is a keyframe. Slice contents are not checked, so there
+// are none.
+fn v1_frame(record: &[u8], keyframe: bool) -> Vec<u8> {
+    let mut record = ConfigRecord::parse_config_record(record).unwrap();
+    record.version = 1;
+    let mut coder = RangeEncoder::new();
+    coder.put_bool(&mut [128; 32], keyframe);
+    if keyframe {
+        record.write_keyframe_header(&mut coder).unwrap();
+    }
+    coder.finish()
+}
+
+// Codes a version 3 keyframe without error correction, made of 1x1 slices
+// at 'positions'. Slice contents are not checked, so there are none.
+fn v3_frame(positions: &[(u32, u32)]) -> Vec<u8> {
+    let mut packet = Vec::new();
+    for (slice, &(x, y)) in positions.iter().enumerate() {
+        let mut coder = RangeEncoder::new();
+        if slice == 0 {
+            coder.put_bool(&mut [128; 32], true);
+        }
+        // Position, size minus one, quant_table_set_index of luma and
+        // chroma, picture_structure and SAR.
+        let mut state = [128; 32];
+        for value in &[x, y, 0, 0, 0, 0, 0, 0, 0] {
+            coder.put_ur(&mut state, *value);
+        }
+        let slice = coder.finish();
+        packet.extend_from_slice(&slice);
+        packet.extend_from_slice(&(slice.len() as u32).to_be_bytes()[1..]);
+    }
+    packet
+}
+
+// Returns the sections and locations of the violations found in a stream
+fn violations(
+    record: &[u8],
+    packets: &[&[u8]],
+) -> Vec<(&'static str, Location)> {
+    let mut checker = Checker::new(record, WIDTH, HEIGHT);
+    for packet in packets {
+        checker.check_frame(packet);
+    }
+    checker
+        .violations()
+        .iter()
+        .map(|violation| (violation.section, violation.location))
+        .collect()
+}
+
+#[test]
+fn test_conforming() {
+    let (record, packet) = encode(0);
+    let mut checker = Checker::new(&record, WIDTH, HEIGHT);
+    assert!(checker.check_frame(&packet).is_empty());
+    assert!(checker.check_frame(&packet).is_empty());
+    assert!(checker.is_conforming());
+}
+
+#[test]
+fn test_conforming_without_ec() {
+    let (record, packet) = encode_with(EncoderConfig {
+        width: WIDTH,
+        height: HEIGHT,
+        ec: false,
+        ..Default::default()
+    });
+    let mut checker = Checker::new(&record, WIDTH, HEIGHT);
+    assert!(checker.check_frame(&packet).is_empty());
+    assert!(checker.check_frame(&packet).is_empty());
+
+    // The last slice_size of the packet, claiming more than all of it.
+    let mut oversized = packet.clone();
+    let len = oversized.len();
+    oversized[len - 3] = 0xff;
+    assert_eq!(
+        violations(&record, &[&oversized]),
+        [("4.8. Slice Footer", Location::Frame(0))]
+    );
+}
+
+#[test]
+fn test_record_violations() {
+    let (record, packet) = encode(0);
+    let record = rewrite_record(&record, |record| {
+        record.ec = 2;
+        record.intra = 3;
+    });
+    assert_eq!(
+        violations(&record, &[&packet]),
+        [
+            ("4.1.16. ec", Location::ConfigRecord),
+            ("4.1.17. intra", Location::ConfigRecord),
+        ]
+    );
+
+    // Frames cannot be checked without a record.
+    let mut checker = Checker::new(&record[1..], WIDTH, HEIGHT);
+    assert_eq!(checker.violations().len(), 1);
+    assert_eq!(checker.violations()[0].section, "4.2. Configuration Record");
+    assert!(checker.check_frame(&packet).is_empty());
+}
+
+#[test]
+fn test_slice_violations() {
+    let (record, packet) = encode(1);
+
+    // Slices coded with the second quantization table set, which is gone,
+    // for both their luma and chroma planes.
+    let single_set = rewrite_record(&record, |record| {
+        record.quant_table_set_count = 1;
+        record.initial_state_delta.truncate(1);
+    });
+    let found = violations(&single_set, &[&packet]);
+    assert_eq!(found.len(), 4 * 2);
+    for (slice, violations) in found.chunks(2).enumerate() {
+        for &violation in violations {
+            assert_eq!(
+                violation,
+                ("4.5.6. quant_table_set_index", Location::Slice(0, slice))
+            );
+        }
+    }
+
+    // Slices coded for a 2x2 grid, in a 1x4 grid: the second column is
+    // out of it, and half of it is left uncovered.
+    let narrow = rewrite_record(&record, |record| {
+        record.num_h_slices_minus1 = 0;
+        record.num_v_slices_minus1 = 3;
+    });
+    assert_eq!(
+        violations(&narrow, &[&packet]),
+        [
+            ("4.5.1. slice_x", Location::Slice(0, 1)),
+            ("4.5.1. slice_x", Location::Slice(0, 3)),
+            ("4.5. Slice Header", Location::Frame(0)),
+        ]
+    );
+
+    // Slices out of the grid are left out of the coverage check, but
+    // still count when numbering the ones that overlap.
+    let (without_ec, _) = encode_with(EncoderConfig {
+        width: WIDTH,
+        height: HEIGHT,
+        ec: false,
+        ..Default::default()
+    });
+    let overlapping =
+        v3_frame(&[(0, 0), (5, 0), (1, 0), (1, 0), (0, 1), (1, 1)]);
+    assert_eq!(
+        violations(&without_ec, &[&overlapping]),
+        [
+            ("4.5.1. slice_x", Location::Slice(0, 1)),
+            ("4.5. Slice Header", Location::Slice(0, 3)),
+        ]
+    );
+
+    // The decoder reads slice headers alike, and stops at the first
+    // violation, which it locates.
+    let mut decoder = Decoder::new(&narrow, WIDTH, HEIGHT).unwrap();
+    decoder.set_threads(1).unwrap();
+    for frame in 0..2 {
+        let err = decoder.decode_frame(&packet).unwrap_err().to_string();
+        let expected = format!("frame {}, slice 1: 4.5.1. slice_x", frame);
+        assert!(err.contains(&expected), "{}", err);
+    }
+}
+
+// Slices are decoded straight into the frame, so overlapping ones fail
+// whether they are decoded in parallel or not.
+#[test]
+fn test_decode_overlapping_slices() {
+    let (without_ec, _) = encode_with(EncoderConfig {
+        width: WIDTH,
+        height: HEIGHT,
+        ec: false,
+        ..Default::default()
+    });
+    let overlapping = v3_frame(&[(0, 0), (1, 0), (1, 0), (0, 1)]);
+    for threads in 1..3 {
+        let mut decoder = Decoder::new(&without_ec, WIDTH, HEIGHT).unwrap();
+        decoder.set_threads(threads).unwrap();
+        let err = decoder.decode_frame(&overlapping).unwrap_err().to_string();
+        assert!(
+            err.contains("slice 2 failed")
+                && err.ends_with("slice overlaps slice 1"),
+            "{} threads: {}",
+            threads,
+            err
+        );
+    }
+}
+
+#[test]
+fn test_frame_violations() {
+    let (record, packet) = encode(0);
+
+    let mut damaged = packet.clone();
+    let len = damaged.len();
+    damaged[len - 9] ^= 0xff;
+    assert_eq!(
+        violations(&record, &[&packet, &damaged, &packet]),
+        [("4.8.3. slice_crc_parity", Location::Slice(1, 3))]
+    );
+
+    // A footer claiming more than the whole packet.
+    let mut oversized = packet.clone();
+    oversized[len - 8] = 0xff;
+    assert!(violations(&record, &[&oversized])
+        .contains(&("4.8. Slice Footer", Location::Frame(0))));
+
+    assert_eq!(
+        violations(&record, &[&[]]),
+        [("4.3. Frame", Location::Frame(0))]
+    );
+}
+
+#[test]
+fn test_keyframe_header_violations() {
+    let (record, _) = encode(0);
+    let keyframe = v1_frame(&record, true);
+    let inter_frame = v1_frame(&record, false);
+
+    // Versions 0 and 1 have no configuration record.
+    let mut checker = Checker::new(&[], WIDTH, HEIGHT);
+    assert!(checker.check_frame(&keyframe).is_empty());
+    assert!(checker.check_frame(&inter_frame).is_empty());
+    assert!(checker.is_conforming());
+
+    assert_eq!(
+        violations(&[], &[&inter_frame, &keyframe]),
+        [("4.3. Frame", Location::Frame(0))]
+    );
+
+    // The parameters of each keyframe are checked like a record.
+    let record = rewrite_record(&record, |record| {
+        record.coder_type = 2;
+        record.state_transition_delta[1] = -100;
+    });
+    let damaged = v1_frame(&record, true);
+    assert_eq!(
+        violations(&[], &[&keyframe, &damaged]),
+        [("4.1.4. state_transition_delta", Location::Frame(1))]
+    );
+
+    // A keyframe header claiming version 2, whose parameters belong in a
+    // record, leaves the following frames unchecked.
+    let mut coder = RangeEncoder::new();
+    coder.put_bool(&mut [128; 32], true);
+    coder.put_ur(&mut [128; 32], 2);
+    let version2 = coder.finish();
+    assert_eq!(
+        violations(&[], &[&version2, &inter_frame]),
+        [("4.1. Parameters", Location::Frame(0))]
+    );
+}
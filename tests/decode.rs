@@ -1,13 +1,17 @@
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::Read;
+use std::sync::{Arc, Mutex};
 
-use av_data::params::MediaKind;
-use av_format::buffer::AccReader;
-use av_format::demuxer::{Context, Event};
-
-use matroska::demuxer::MkvDemuxer;
-
+use ffv1::conformance::Checker;
 use ffv1::decoder::Decoder;
+use ffv1::encoder::{Encoder, EncoderConfig};
+use ffv1::frame::{
+    Concealed, Concealment, FrameLayout, PictureStructure, PixelFormat, Plane,
+    PlaneMut, Samples, SliceDamage,
+};
+use ffv1::mkv::MkvReader;
+use ffv1::record::ConfigRecord;
+use ffv1::trace::{Element, Location, Trace};
 
 // ffv1 decoder parameters
 #[derive(Default)]
@@ -19,73 +23,41 @@ struct DecParams {
 
 // Decodes a single ffv1 frame
 fn decode_single_frame(
-    demuxer: &mut Context,
+    reader: &mut MkvReader<File>,
     decoder: &mut Decoder,
 ) -> Result<ffv1::decoder::Frame, String> {
-    // The demuxer reads which event has occurred
-    match demuxer.read_event() {
+    match reader.next_packet() {
         // If a new packet has been found, decode it
-        Ok(event) => match event {
-            Event::NewPacket(pkt) => {
-                // Reads a ffv1 frame
-                decoder
-                    .decode_frame(&pkt.data)
-                    .map_err(|_e| "Decoding failure".to_owned())
-            }
-            // When the EOF is reached, the decoding process is stopped
-            Event::Eof => {
-                println!("EOF reached.");
-                Err("EOF reached".to_owned())
-            }
-            _ => {
-                // If an unsupported event occurs,
-                // the decoding process is stopped
-                println!("Unsupported event {:?}", event);
-                Err("Unsupported event".to_owned())
-            }
-        },
-        Err(err) => {
-            // If there are no more events, the decoding process is stopped
-            println!("No more events {:?}", err);
-            Err("No more events".to_owned())
-        }
+        Ok(Some(pkt)) => decoder
+            .decode_frame(&pkt.data)
+            .map_err(|_e| "Decoding failure".to_owned()),
+        // When the EOF is reached, the decoding process is stopped
+        Ok(None) => Err("EOF reached".to_owned()),
+        Err(err) => Err(format!("Cannot read a packet: {}", err)),
     }
 }
 
 fn decode(input: &str) -> ffv1::decoder::Frame {
-    let reader = File::open(input).unwrap();
+    decode_with_threads(input, 0)
+}
 
-    // Create a buffer of size 4096KiB to contain matroska data
-    let ar = AccReader::with_capacity(4 * 1024, reader);
+// Opens a matroska file and reads the ffv1 decoder parameters
+fn open(input: &str) -> (MkvReader<File>, DecParams) {
+    let reader = MkvReader::new(File::open(input).unwrap())
+        .expect("Cannot parse the format headers");
 
-    // Set the type of demuxer, in this case, a matroska demuxer
-    let mut demuxer = Context::new(Box::new(MkvDemuxer::new()), Box::new(ar));
+    let track = &reader.tracks()[0];
+    let decoder_params = DecParams {
+        width: track.width,
+        height: track.height,
+        extradata: track.config_record.clone(),
+    };
 
-    // Read matroska headers
-    demuxer
-        .read_headers()
-        .expect("Cannot parse the format headers");
+    (reader, decoder_params)
+}
 
-    // Save decoder params for ffv1 decoder
-    let mut decoder_params: DecParams = Default::default();
-
-    // Iterate over the streams contained in a matroska file
-    for stream in &demuxer.info.streams {
-        // Considers only video streams and analyze the type of codec inside.
-        if let Some(MediaKind::Video(info)) = &stream.params.kind {
-            let extradata =
-                stream.params.extradata.as_ref().unwrap_or_else(|| {
-                    eprintln!("No extradata detected. Aborting");
-                    std::process::exit(1);
-                });
-            if String::from_utf8_lossy(&extradata).contains("FFV1") {
-                decoder_params.width = info.width as u32;
-                decoder_params.height = info.height as u32;
-                // As per Matroska spec for VFW CodecPrivate
-                decoder_params.extradata = extradata[40..].to_owned();
-            }
-        }
-    }
+fn decode_with_threads(input: &str, threads: usize) -> ffv1::decoder::Frame {
+    let (mut reader, decoder_params) = open(input);
 
     // Create a new ffv1 decoder
     let mut ffv1_decoder = Decoder::new(
@@ -94,39 +66,37 @@ fn decode(input: &str) -> ffv1::decoder::Frame {
         decoder_params.height,
     )
     .unwrap();
+    ffv1_decoder.set_threads(threads).unwrap();
 
-    decode_single_frame(&mut demuxer, &mut ffv1_decoder).unwrap()
+    decode_single_frame(&mut reader, &mut ffv1_decoder).unwrap()
 }
 
-#[test]
-fn test_yuv420() {
-    let input = "data/ffv1_v3_yuv420p.mkv";
-    let reference = "data/ffv1_v3_yuv420p.ref";
-    let f = File::open(reference).unwrap();
+fn check_yuv420(input: &str) {
+    let reference = std::fs::read("data/ffv1_v3_yuv420p.ref").unwrap();
     let frame = decode(input);
 
-    let yplane = frame.buf[0].iter();
-    let uplane = frame.buf[1].iter();
-    let vplane = frame.buf[2].iter();
+    assert_eq!(frame.pixel_format().to_string(), "yuv420p");
+    let yplane = frame.plane_u8(0).unwrap().data.iter();
+    let uplane = frame.plane_u8(1).unwrap().data.iter();
+    let vplane = frame.plane_u8(2).unwrap().data.iter();
 
     let pixels = yplane.chain(uplane).chain(vplane);
 
-    for (i, (&p, r)) in pixels.zip(f.bytes()).enumerate() {
-        assert_eq!(p, r.unwrap(), "pixel {}", i);
+    for (i, (&p, &r)) in pixels.zip(reference.iter()).enumerate() {
+        assert_eq!(p, r, "pixel {}", i);
     }
 }
 
-#[test]
-fn test_bgr0() {
-    let input = "data/ffv1_v3_bgr0.mkv";
+fn check_bgr0(input: &str) {
     let reference = "data/ffv1_v3_bgr0.ref";
 
     let mut f = File::open(reference).unwrap();
     let frame = decode(input);
 
-    let gplane = frame.buf[0].iter();
-    let bplane = frame.buf[1].iter();
-    let rplane = frame.buf[2].iter();
+    assert_eq!(frame.pixel_format().to_string(), "gbrp");
+    let gplane = frame.plane_u8(0).unwrap().data.iter();
+    let bplane = frame.plane_u8(1).unwrap().data.iter();
+    let rplane = frame.plane_u8(2).unwrap().data.iter();
 
     let pixels = gplane.zip(bplane).zip(rplane);
 
@@ -141,6 +111,93 @@ fn test_bgr0() {
     }
 }
 
+// Reads the decoder parameters and the first packet of a matroska file,
+// so they can be damaged before decoding
+fn read_first_packet(input: &str) -> (DecParams, Vec<u8>) {
+    let (mut reader, decoder_params) = open(input);
+
+    match reader.next_packet() {
+        Ok(Some(pkt)) => (decoder_params, pkt.data),
+        _ => panic!("No packet in {}", input),
+    }
+}
+
+#[test]
+fn test_yuv420() {
+    check_yuv420("data/ffv1_v3_yuv420p.mkv");
+}
+
+#[test]
+fn test_bgr0() {
+    check_bgr0("data/ffv1_v3_bgr0.mkv");
+}
+
+#[test]
+fn test_v2_yuv420() {
+    check_yuv420("data/ffv1_v2_yuv420p.mkv");
+}
+
+#[test]
+fn test_v2_bgr0() {
+    check_bgr0("data/ffv1_v2_bgr0.mkv");
+}
+
+#[test]
+fn test_v4_yuv420() {
+    // The bottom right slice is coded in PCM mode.
+    check_yuv420("data/ffv1_v4_yuv420p.mkv");
+}
+
+#[test]
+fn test_v4_bgr0() {
+    // The bottom right slice is coded in PCM mode.
+    check_bgr0("data/ffv1_v4_bgr0.mkv");
+}
+
+// Made by data/make_samples.py, which codes data/ffv1_v3_yuv420p.ref as
+// version 0, for lack of FFmpeg.
+#[test]
+fn test_v0_yuv420() {
+    check_yuv420("data/ffv1_v0_yuv420p.mkv");
+}
+
+// Made by data/make_samples.py, see test_v0_yuv420.
+#[test]
+fn test_v1_yuv420() {
+    check_yuv420("data/ffv1_v1_yuv420p.mkv");
+}
+
+// Made by data/make_samples.py, which codes data/ffv1_v3_yuv420p.ref
+// cropped to 639x357, as version 3 with 3x2 slices.
+//
+// The second column of slices starts on column 213, so its chroma planes
+// start on column 106, which the first column of slices codes as well.
+// With an odd height, the rows of slices would split on an odd row too,
+// but then the last chroma row would be left uncoded, as FFmpeg leaves it.
+#[test]
+fn test_odd_size_yuv420() {
+    let reference = fs::read("data/ffv1_v3_yuv420p.ref").unwrap();
+    let (luma, chroma) = reference.split_at(640 * 360);
+    let crop = |plane: &[u8], width: usize, height: usize| -> Vec<u8> {
+        plane
+            .chunks(width)
+            .take(height)
+            .flat_map(|row| &row[..width.min(639)])
+            .copied()
+            .collect()
+    };
+    let (cb, cr) = chroma.split_at(320 * 180);
+    let planes =
+        [crop(luma, 640, 357), crop(cb, 320, 179), crop(cr, 320, 179)];
+
+    let frame = decode("data/ffv1_v3_yuv420p_odd.mkv");
+    assert_eq!((frame.width(), frame.height()), (639, 357));
+    assert_eq!(frame.slices().len(), 6);
+    for (p, plane) in planes.iter().enumerate() {
+        assert!(frame.plane_u8(p).unwrap().data == *plane, "plane {}", p);
+    }
+}
+
 #[test]
 fn test_gbrp16le() {
     use byteorder::{LittleEndian, ReadBytesExt};
@@ -150,9 +207,10 @@ fn test_gbrp16le() {
     let mut f = File::open(reference).unwrap();
     let frame = decode(input);
 
-    let gplane = frame.buf16[0].iter();
-    let bplane = frame.buf16[1].iter();
-    let rplane = frame.buf16[2].iter();
+    assert_eq!(frame.pixel_format().to_string(), "gbrp16");
+    let gplane = frame.plane_u16(0).unwrap().data.iter();
+    let bplane = frame.plane_u16(1).unwrap().data.iter();
+    let rplane = frame.plane_u16(2).unwrap().data.iter();
 
     let pixels = gplane.chain(bplane).chain(rplane);
 
@@ -161,3 +219,707 @@ fn test_gbrp16le() {
         assert_eq!(p, r, "pixel {}", i);
     }
 }
+
+#[test]
+fn test_threads() {
+    for input in &[
+        "data/ffv1_v3_yuv420p.mkv",
+        "data/ffv1_v3_bgr0.mkv",
+        "data/ffv1_v3_gbrp16le.mkv",
+    ] {
+        let sequential = decode_with_threads(input, 1);
+
+        for threads in 2..5 {
+            let frame = decode_with_threads(input, threads);
+            assert_eq!(
+                frame.samples(),
+                sequential.samples(),
+                "{}: {} threads",
+                input,
+                threads
+            );
+        }
+    }
+}
+
+// Without error correction, slice footers only hold the size of their
+// slice, so the last byte of the packet is part of a size.
+#[test]
+fn test_without_ec() {
+    let reference = fs::read("data/ffv1_v3_yuv420p.ref").unwrap();
+    let (luma, chroma) = reference.split_at(640 * 360);
+    let (cb, cr) = chroma.split_at(320 * 180);
+
+    let encoder = Encoder::new(EncoderConfig {
+        width: 640,
+        height: 360,
+        num_h_slices: 4,
+        num_v_slices: 3,
+        ec: false,
+        ..Default::default()
+    })
+    .unwrap();
+    let packet = encoder.encode_frame(&[luma, cb, cr]).unwrap();
+
+    for threads in 1..3 {
+        let mut decoder =
+            Decoder::new(encoder.config_record(), 640, 360).unwrap();
+        decoder.set_threads(threads).unwrap();
+        let frame = decoder.decode_frame(&packet).unwrap();
+        assert_eq!(frame.slices().len(), 12);
+        for (p, plane) in [luma, cb, cr].iter().enumerate() {
+            assert!(frame.plane_u8(p).unwrap().data == *plane, "plane {}", p);
+        }
+    }
+}
+
+#[test]
+fn test_frame_planes() {
+    let frame = decode("data/ffv1_v3_yuv420p.mkv");
+    assert_eq!(
+        frame.pixel_format(),
+        PixelFormat::Yuv {
+            bit_depth: 8,
+            log2_h_chroma_subsample: 1,
+            log2_v_chroma_subsample: 1,
+            alpha: false,
+        }
+    );
+    assert_eq!(frame.num_planes(), 3);
+    assert_eq!(frame.picture_structure(), PictureStructure::Progressive);
+    assert!(frame.plane_u8(3).is_none());
+    assert!(frame.plane_u16(0).is_none());
+
+    let chroma = frame.plane_u8(1).unwrap();
+    assert_eq!((chroma.width, chroma.height), (320, 180));
+    assert_eq!(chroma.row(179).len(), 320);
+
+    let frame = decode("data/ffv1_v2_yuv420p.mkv");
+    assert_eq!(frame.picture_structure(), PictureStructure::Unknown);
+
+    let frame = decode("data/ffv1_v3_gbrp16le.mkv");
+    assert!(frame.plane_u8(0).is_none());
+    match frame.samples() {
+        Samples::U16(planes) => assert_eq!(planes.len(), 3),
+        Samples::U8(_) => panic!("16 bit samples expected"),
+    }
+}
+
+// Decodes the first packet of a matroska file into planes whose rows are
+// padded, and checks them against the frame decode_frame returns
+fn check_decode_into(input: &str) {
+    let (params, packet) = read_first_packet(input);
+    let mut decoder =
+        Decoder::new(&params.extradata, params.width, params.height).unwrap();
+    let frame = decoder.decode_frame(&packet).unwrap();
+    let pixel_format = decoder.pixel_format().unwrap();
+    assert_eq!(pixel_format, frame.pixel_format());
+
+    let dimensions: Vec<_> = (0..pixel_format.num_planes())
+        .map(|p| pixel_format.plane_dimensions(p, params.width, params.height))
+        .collect();
+    let stride = |width: u32| width as usize + 13;
+
+    match frame.samples() {
+        Samples::U8(expected) => {
+            let mut bufs: Vec<Vec<u8>> = dimensions
+                .iter()
+                .map(|&(width, height)| {
+                    vec![0xAA; stride(width) * height as usize]
+                })
+                .collect();
+            // Twice, so the second frame reuses what the first one left.
+            for _ in 0..2 {
+                let mut planes: Vec<_> = bufs
+                    .iter_mut()
+                    .zip(&dimensions)
+                    .map(|(data, &(width, _))| PlaneMut {
+                        data,
+                        stride: stride(width),
+                    })
+                    .collect();
+                decoder.decode_frame_into_u8(&packet, &mut planes).unwrap();
+            }
+            for (p, (buf, &(width, height))) in
+                bufs.iter().zip(&dimensions).enumerate()
+            {
+                for (y, row) in buf.chunks(stride(width)).enumerate() {
+                    let (row, padding) = row.split_at(width as usize);
+                    let pos = y * width as usize;
+                    assert!(row == &expected[p][pos..pos + width as usize]);
+                    assert!(padding.iter().all(|&v| v == 0xAA));
+                }
+                assert_eq!(buf.len() / stride(width), height as usize);
+            }
+        }
+        Samples::U16(expected) => {
+            let mut bufs: Vec<Vec<u16>> = dimensions
+                .iter()
+                .map(|&(width, height)| {
+                    vec![0xAAAA; stride(width) * height as usize]
+                })
+                .collect();
+            for _ in 0..2 {
+                let mut planes: Vec<_> = bufs
+                    .iter_mut()
+                    .zip(&dimensions)
+                    .map(|(data, &(width, _))| PlaneMut {
+                        data,
+                        stride: stride(width),
+                    })
+                    .collect();
+                decoder.decode_frame_into_u16(&packet, &mut planes).unwrap();
+            }
+            for (p, (buf, &(width, _))) in
+                bufs.iter().zip(&dimensions).enumerate()
+            {
+                for (y, row) in buf.chunks(stride(width)).enumerate() {
+                    let (row, padding) = row.split_at(width as usize);
+                    let pos = y * width as usize;
+                    assert!(row == &expected[p][pos..pos + width as usize]);
+                    assert!(padding.iter().all(|&v| v == 0xAAAA));
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_decode_into() {
+    for input in CRC_PROTECTED.iter().chain(UNPROTECTED.iter()) {
+        check_decode_into(input);
+    }
+    check_decode_into("data/ffv1_v4_yuv420p.mkv");
+    check_decode_into("data/ffv1_v4_bgr0.mkv");
+}
+
+#[test]
+fn test_decode_into_invalid() {
+    let (params, packet) = read_first_packet("data/ffv1_v3_yuv420p.mkv");
+    let mut decoder =
+        Decoder::new(&params.extradata, params.width, params.height).unwrap();
+
+    let mut luma = vec![0u8; 640 * 360];
+    let mut cb = vec![0u8; 320 * 180];
+    let mut cr = vec![0u8; 320 * 180];
+    let mut wide = vec![0u16; 640 * 360];
+
+    // Too few planes
+    let mut planes = [PlaneMut {
+        data: &mut luma,
+        stride: 640,
+    }];
+    assert!(decoder.decode_frame_into_u8(&packet, &mut planes).is_err());
+
+    // Wrong sample type
+    let mut planes = [PlaneMut {
+        data: &mut wide,
+        stride: 640,
+    }];
+    assert!(decoder.decode_frame_into_u16(&packet, &mut planes).is_err());
+
+    // Stride too small for the plane
+    let mut planes = [
+        PlaneMut {
+            data: &mut luma,
+            stride: 640,
+        },
+        PlaneMut {
+            data: &mut cb,
+            stride: 300,
+        },
+        PlaneMut {
+            data: &mut cr,
+            stride: 320,
+        },
+    ];
+    assert!(decoder.decode_frame_into_u8(&packet, &mut planes).is_err());
+
+    // Buffer too small for its stride
+    planes[1].stride = 321;
+    assert!(decoder.decode_frame_into_u8(&packet, &mut planes).is_err());
+
+    planes[1].stride = 320;
+    decoder.decode_frame_into_u8(&packet, &mut planes).unwrap();
+}
+
+// Samples whose slices are protected by a CRC, so damaging them must
+// always be reported
+const CRC_PROTECTED: [&str; 3] = [
+    "data/ffv1_v3_yuv420p.mkv",
+    "data/ffv1_v3_bgr0.mkv",
+    "data/ffv1_v3_gbrp16le.mkv",
+];
+
+// Samples without any CRC, where damage may go unnoticed
+const UNPROTECTED: [&str; 2] =
+    ["data/ffv1_v2_yuv420p.mkv", "data/ffv1_v2_bgr0.mkv"];
+
+#[test]
+fn test_truncated_packets() {
+    for input in CRC_PROTECTED.iter().chain(UNPROTECTED.iter()) {
+        let (params, packet) = read_first_packet(input);
+        let mut decoder =
+            Decoder::new(&params.extradata, params.width, params.height)
+                .unwrap();
+
+        for len in (0..packet.len()).step_by(packet.len() / 16) {
+            let result = decoder.decode_frame(&packet[..len]);
+            if CRC_PROTECTED.contains(input) {
+                assert!(result.is_err(), "{} truncated to {}", input, len);
+            }
+        }
+
+        // The decoder is still usable afterwards.
+        decoder.decode_frame(&packet).unwrap();
+    }
+}
+
+#[test]
+fn test_corrupted_packets() {
+    for input in CRC_PROTECTED.iter().chain(UNPROTECTED.iter()) {
+        let (params, packet) = read_first_packet(input);
+        let mut decoder =
+            Decoder::new(&params.extradata, params.width, params.height)
+                .unwrap();
+
+        for pos in (0..packet.len()).step_by(packet.len() / 16) {
+            let mut corrupted = packet.clone();
+            corrupted[pos] ^= 0x55;
+            let result = decoder.decode_frame(&corrupted);
+            if CRC_PROTECTED.contains(input) {
+                assert!(result.is_err(), "{} at {}", input, pos);
+            }
+        }
+
+        decoder.decode_frame(&packet).unwrap();
+    }
+}
+
+#[test]
+fn test_truncated_records() {
+    for input in CRC_PROTECTED.iter().chain(UNPROTECTED.iter()) {
+        let (params, _) = read_first_packet(input);
+        let record = &params.extradata;
+
+        // Cutting into the CRC may leave a valid record behind.
+        for len in 1..record.len() {
+            let result =
+                Decoder::new(&record[..len], params.width, params.height);
+            if CRC_PROTECTED.contains(input) && len < record.len() - 4 {
+                assert!(result.is_err(), "{} truncated to {}", input, len);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_corrupted_records() {
+    for input in CRC_PROTECTED.iter().chain(UNPROTECTED.iter()) {
+        let (params, packet) = read_first_packet(input);
+
+        for pos in 0..params.extradata.len() {
+            let mut record = params.extradata.clone();
+            record[pos] ^= 0x55;
+            let result = Decoder::new(&record, params.width, params.height);
+            if let Ok(mut decoder) = result {
+                assert!(
+                    !CRC_PROTECTED.contains(input),
+                    "{} at {}",
+                    input,
+                    pos
+                );
+                let _ = decoder.decode_frame(&packet);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_inter_frame_first() {
+    let (params, mut packet) = read_first_packet("data/ffv1_v3_yuv420p.mkv");
+    let mut decoder =
+        Decoder::new(&params.extradata, params.width, params.height).unwrap();
+
+    // Clearing the top bit of the first byte turns the keyframe bit off.
+    packet[0] &= 0x7f;
+    assert!(decoder.decode_frame(&packet).is_err());
+}
+
+// Checks that a plane has aligned rows and the same samples as 'expected'
+fn check_aligned_plane<T: PartialEq>(
+    plane: Plane<'_, T>,
+    expected: Plane<'_, T>,
+    alignment: usize,
+) {
+    assert_eq!(
+        (plane.width, plane.height),
+        (expected.width, expected.height)
+    );
+    assert_eq!(plane.stride * std::mem::size_of::<T>() % alignment, 0);
+    for y in 0..plane.height {
+        assert_eq!(plane.row(y).as_ptr() as usize % alignment, 0);
+        assert!(plane.row(y) == expected.row(y));
+    }
+}
+
+// Decodes the first packet of a matroska file into frames with 64 byte
+// aligned rows, and checks them against the default layout
+fn check_frame_layout(input: &str) {
+    let (params, packet) = read_first_packet(input);
+    let mut decoder =
+        Decoder::new(&params.extradata, params.width, params.height).unwrap();
+    let expected = decoder.decode_frame(&packet).unwrap();
+
+    decoder
+        .set_frame_layout(FrameLayout {
+            row_alignment: 64,
+            strides: vec![0, 1000],
+        })
+        .unwrap();
+    let frame = decoder.decode_frame(&packet).unwrap();
+
+    for p in 0..frame.num_planes() {
+        if let Some(plane) = frame.plane_u8(p) {
+            check_aligned_plane(plane, expected.plane_u8(p).unwrap(), 64);
+        } else {
+            let plane = frame.plane_u16(p).unwrap();
+            if p == 1 {
+                assert_eq!(plane.stride, 1024);
+            }
+            check_aligned_plane(plane, expected.plane_u16(p).unwrap(), 64);
+        }
+    }
+    if let Some(plane) = frame.plane_u8(1) {
+        assert_eq!(plane.stride, 1024);
+    }
+}
+
+#[test]
+fn test_frame_layout() {
+    check_frame_layout("data/ffv1_v3_yuv420p.mkv");
+    check_frame_layout("data/ffv1_v3_bgr0.mkv");
+    check_frame_layout("data/ffv1_v3_gbrp16le.mkv");
+}
+
+#[test]
+fn test_frame_layout_invalid() {
+    let (params, packet) = read_first_packet("data/ffv1_v3_yuv420p.mkv");
+    let mut decoder =
+        Decoder::new(&params.extradata, params.width, params.height).unwrap();
+
+    assert!(decoder
+        .set_frame_layout(FrameLayout {
+            row_alignment: 48,
+            strides: Vec::new(),
+        })
+        .is_err());
+
+    // Stride smaller than the plane width
+    decoder
+        .set_frame_layout(FrameLayout {
+            row_alignment: 0,
+            strides: vec![640, 300],
+        })
+        .unwrap();
+    assert!(decoder.decode_frame(&packet).is_err());
+
+    // Planes too large to allocate
+    for layout in [
+        FrameLayout {
+            row_alignment: 1 << (usize::BITS - 1),
+            strides: Vec::new(),
+        },
+        FrameLayout {
+            row_alignment: 0,
+            strides: vec![usize::MAX],
+        },
+    ] {
+        decoder.set_frame_layout(layout).unwrap();
+        assert!(decoder.decode_frame(&packet).is_err());
+    }
+}
+
+#[test]
+fn test_slice_metadata() {
+    for &(input, sar) in &[
+        ("data/ffv1_v2_yuv420p.mkv", None),
+        ("data/ffv1_v3_yuv420p.mkv", Some((1, 1))),
+    ] {
+        let frame = decode(input);
+        let slices = frame.slices();
+        assert!(slices.len() > 1, "{}", input);
+
+        // Slices tile the frame
+        let area: u32 = slices.iter().map(|s| s.width * s.height).sum();
+        assert_eq!(area, frame.width() * frame.height(), "{}", input);
+        for slice in slices {
+            assert!(slice.x + slice.width <= frame.width());
+            assert!(slice.y + slice.height <= frame.height());
+            assert_eq!(slice.quant_table_set_index.len(), 2);
+            assert_eq!(slice.picture_structure, frame.picture_structure());
+            assert_eq!(slice.error_status, 0);
+        }
+        assert_eq!(frame.sample_aspect_ratio(), sar, "{}", input);
+        assert!(!frame.sample_aspect_ratio_mismatch());
+    }
+}
+
+// Damages the middle of the last slice of a packet, leaving its header and
+// its footer intact
+fn damage_last_slice(packet: &mut [u8]) {
+    let len = packet.len();
+    let size = u32::from_be_bytes([
+        0,
+        packet[len - 8],
+        packet[len - 7],
+        packet[len - 6],
+    ]) as usize;
+    packet[len - 8 - size / 2] ^= 0xff;
+}
+
+#[test]
+fn test_concealment() {
+    let (params, packet) = read_first_packet("data/ffv1_v3_yuv420p.mkv");
+    let mut damaged = packet.clone();
+    damage_last_slice(&mut damaged);
+    let new_decoder = || {
+        Decoder::new(&params.extradata, params.width, params.height).unwrap()
+    };
+
+    let good = new_decoder().decode_frame(&packet).unwrap();
+    assert!(new_decoder().decode_frame(&damaged).is_err());
+
+    for &(concealment, previous, method) in &[
+        (Concealment::MidGray, false, Concealment::MidGray),
+        (Concealment::Interpolate, false, Concealment::Interpolate),
+        (Concealment::PreviousFrame, true, Concealment::PreviousFrame),
+        // Without a previous frame, mid-gray is used instead.
+        (Concealment::PreviousFrame, false, Concealment::MidGray),
+    ] {
+        // Slices are concealed alike whether they are decoded in
+        // parallel or not.
+        for threads in 1..3 {
+            let mut decoder = new_decoder();
+            decoder.set_threads(threads).unwrap();
+            decoder.set_concealment(concealment);
+            if previous {
+                decoder.decode_frame(&packet).unwrap();
+            }
+            let frame = decoder.decode_frame(&damaged).unwrap();
+
+            let (last, slices) = frame.slices().split_last().unwrap();
+            assert!(slices.iter().all(|slice| slice.concealed.is_none()));
+            assert_eq!(
+                last.concealed,
+                Some(Concealed {
+                    damage: SliceDamage::CrcMismatch,
+                    method,
+                })
+            );
+
+            for p in 0..3 {
+                let shift = if p == 0 { 0 } else { 1 };
+                let ceil = |v: u32| (v + shift) >> shift;
+                let (x0, y0) = (ceil(last.x), ceil(last.y));
+                let (x1, y1) =
+                    (ceil(last.x + last.width), ceil(last.y + last.height));
+                let plane = frame.plane_u8(p).unwrap();
+                let expected = good.plane_u8(p).unwrap();
+                for y in 0..plane.height {
+                    for x in 0..plane.width {
+                        let sample = plane.row(y)[x as usize];
+                        let inside =
+                            (x0..x1).contains(&x) && (y0..y1).contains(&y);
+                        let expected = match method {
+                            // The last slice is interpolated from the row above
+                            // it only.
+                            Concealment::Interpolate if inside => {
+                                plane.row(y0 - 1)[x as usize]
+                            }
+                            Concealment::MidGray if inside => 128,
+                            _ => expected.row(y)[x as usize],
+                        };
+                        assert_eq!(
+                            sample, expected,
+                            "{:?} {} {} {}",
+                            method, p, x, y
+                        );
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_verify_frame() {
+    let (params, packet) = read_first_packet("data/ffv1_v3_yuv420p.mkv");
+    let mut decoder =
+        Decoder::new(&params.extradata, params.width, params.height).unwrap();
+
+    let integrity = decoder.verify_frame(&packet).unwrap();
+    assert!(integrity.keyframe);
+    assert!(integrity.is_intact());
+
+    // The slices and their 8 byte footers tile the packet.
+    let mut pos = 0;
+    for slice in &integrity.slices {
+        assert_eq!(slice.pos, pos);
+        assert_eq!(slice.error_status, 0);
+        pos += slice.size + 8;
+    }
+    assert_eq!(pos, packet.len());
+
+    // They are the slices the decoder finds.
+    let frame = decoder.decode_frame(&packet).unwrap();
+    assert_eq!(integrity.slices.len(), frame.slices().len());
+
+    let mut damaged = packet.clone();
+    damage_last_slice(&mut damaged);
+    let integrity = decoder.verify_frame(&damaged).unwrap();
+    let (last, slices) = integrity.slices.split_last().unwrap();
+    assert!(!integrity.is_intact());
+    assert!(!last.crc_valid);
+    assert!(slices.iter().all(|slice| slice.is_intact()));
+
+    assert!(decoder.verify_frame(&[]).is_err());
+    let truncated = decoder.verify_frame(&packet[..packet.len() - 1]);
+    assert!(!truncated.is_ok_and(|integrity| integrity.is_intact()));
+
+    // Version 2 has no CRCs.
+    let (params, packet) = read_first_packet("data/ffv1_v2_yuv420p.mkv");
+    let decoder =
+        Decoder::new(&params.extradata, params.width, params.height).unwrap();
+    assert!(decoder.verify_frame(&packet).is_err());
+}
+
+#[test]
+fn test_conformance() {
+    for input in CRC_PROTECTED
+        .iter()
+        .chain(&UNPROTECTED)
+        .chain(&["data/ffv1_v4_yuv420p.mkv", "data/ffv1_v4_bgr0.mkv"])
+    {
+        let (params, packet) = read_first_packet(input);
+        let mut checker =
+            Checker::new(&params.extradata, params.width, params.height);
+        checker.check_frame(&packet);
+        assert_eq!(checker.violations(), [], "{}", input);
+    }
+}
+
+// Trace whose elements can still be looked at once given to a decoder
+#[derive(Clone, Default)]
+struct SharedTrace(Arc<Mutex<Vec<Element>>>);
+
+impl Trace for SharedTrace {
+    fn element(&mut self, element: &Element) {
+        self.0.lock().unwrap().push(element.clone());
+    }
+}
+
+// Decodes the first packet of a matroska file, and returns the syntax
+// elements read from it
+fn trace_first_packet(input: &str, threads: usize) -> Vec<Element> {
+    let (params, packet) = read_first_packet(input);
+    let mut decoder =
+        Decoder::new(&params.extradata, params.width, params.height).unwrap();
+    decoder.set_threads(threads).unwrap();
+    let trace = SharedTrace::default();
+    decoder.set_trace(Some(Box::new(trace.clone())));
+    decoder.decode_frame(&packet).unwrap();
+
+    let elements = trace.0.lock().unwrap().clone();
+    elements
+}
+
+#[test]
+fn test_trace_record() {
+    let (params, _) = read_first_packet("data/ffv1_v3_yuv420p.mkv");
+    let mut trace = Vec::new();
+    ConfigRecord::parse_config_record_with_trace(
+        &params.extradata,
+        &mut trace,
+    )
+    .unwrap();
+
+    assert_eq!(trace[0].section(), "4.1.1");
+    assert_eq!(trace[0].name(), "version");
+    assert_eq!(trace[0].value, 3);
+    // The range coder reads its first two bytes ahead.
+    assert_eq!(trace[0].pos, 2);
+    let parity = trace.last().unwrap();
+    assert_eq!(parity.name(), "configuration_record_crc_parity");
+    assert_eq!(parity.pos, params.extradata.len() - 4);
+    for element in &trace {
+        assert_eq!(element.location, Location::ConfigRecord);
+        assert!(element.pos < params.extradata.len());
+    }
+}
+
+#[test]
+fn test_trace_frame() {
+    let input = "data/ffv1_v3_yuv420p.mkv";
+    let frame = decode(input);
+    let (_, packet) = read_first_packet(input);
+    let trace = trace_first_packet(input, 0);
+
+    // Slices are traced in order, even when decoded in parallel.
+    assert_eq!(trace, trace_first_packet(input, 1));
+
+    assert_eq!(trace[0].location, Location::Frame(0));
+    assert_eq!(trace[0].name(), "keyframe");
+    assert_eq!(trace[0].value, 1);
+    assert_eq!(
+        trace[0].to_string(),
+        r#"{"frame":0,"section":"4.3","name":"keyframe","value":1,"pos":2}"#
+    );
+
+    for (i, slice) in frame.slices().iter().enumerate() {
+        let elements: Vec<&Element> = trace
+            .iter()
+            .filter(|element| element.location == Location::Slice(0, i))
+            .collect();
+        let value = |name: &str| {
+            elements
+                .iter()
+                .find(|element| element.name() == name)
+                .unwrap()
+                .value
+        };
+        assert_eq!(value("error_status"), 0);
+        assert_eq!(value("sar_num"), slice.sar_num as i64);
+        assert_eq!(value("sar_den"), slice.sar_den as i64);
+        // Progressive is coded as 3.
+        assert_eq!(slice.picture_structure, PictureStructure::Progressive);
+        assert_eq!(value("picture_structure"), 3);
+        let indexes: Vec<i64> = elements
+            .iter()
+            .filter(|element| element.name() == "quant_table_set_index")
+            .map(|element| element.value)
+            .collect();
+        assert_eq!(indexes.len(), slice.quant_table_set_index.len());
+        for element in elements {
+            assert!(element.pos < packet.len());
+        }
+    }
+}
+
+#[test]
+fn test_trace_slice_layout() {
+    // Version 2 codes its slices in the keyframe header.
+    let trace = trace_first_packet("data/ffv1_v2_yuv420p.mkv", 0);
+    let slice_count = trace
+        .iter()
+        .find(|element| element.name() == "slice_count")
+        .unwrap();
+    assert_eq!(slice_count.location, Location::Frame(0));
+    assert_eq!(
+        trace
+            .iter()
+            .filter(|element| element.name() == "slice_x")
+            .count() as i64,
+        slice_count.value
+    );
+}
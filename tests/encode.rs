@@ -0,0 +1,317 @@
+use std::fs;
+
+use ffv1::decoder::Decoder;
+use ffv1::encoder::{Encoder, EncoderConfig};
+use ffv1::frame::PictureStructure;
+use ffv1::record::ConfigRecord;
+
+// Builds a plane holding gradients and some noise, so that every context
+// and large differences get coded
+fn make_plane(width: usize, height: usize, seed: u32) -> Vec<u8> {
+    let mut state = seed;
+    (0..width * height)
+        .map(|i| {
+            state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+            let (x, y) = (i % width, i / width);
+            let noise = (state >> 16) as usize % 16;
+            if (state >> 8) & 63 == 0 {
+                (state >> 24) as u8
+            } else {
+                ((x + 2 * y) / 3 + noise) as u8
+            }
+        })
+        .collect()
+}
+
+// Encodes a frame, then checks that decoding it gives the frame back
+fn check_round_trip(config: EncoderConfig, planes: &[Vec<u8>]) {
+    let (width, height) = (config.width, config.height);
+    let encoder = Encoder::new(config).unwrap();
+    let planes: Vec<&[u8]> = planes.iter().map(|p| p.as_slice()).collect();
+    let packet = encoder.encode_frame(&planes).unwrap();
+
+    let mut decoder =
+        Decoder::new(encoder.config_record(), width, height).unwrap();
+    let frame = decoder.decode_frame(&packet).unwrap();
+
+    assert_eq!(frame.num_planes(), planes.len());
+    for (p, plane) in planes.iter().enumerate() {
+        assert!(frame.plane_u8(p).unwrap().data == *plane, "plane {}", p);
+    }
+}
+
+#[test]
+fn test_encode_yuv420() {
+    // Taken from the decoded sample, so it codes natural content
+    let reference = fs::read("data/ffv1_v3_yuv420p.ref").unwrap();
+    let (luma, chroma) = reference.split_at(640 * 360);
+    let (cb, cr) = chroma.split_at(320 * 180);
+
+    let config = EncoderConfig {
+        width: 640,
+        height: 360,
+        ..Default::default()
+    };
+    check_round_trip(config, &[luma.to_vec(), cb.to_vec(), cr.to_vec()]);
+}
+
+#[test]
+fn test_encode_yuv444_alpha() {
+    let config = EncoderConfig {
+        width: 96,
+        height: 64,
+        log2_h_chroma_subsample: 0,
+        log2_v_chroma_subsample: 0,
+        extra_plane: true,
+        num_h_slices: 3,
+        num_v_slices: 1,
+        ..Default::default()
+    };
+    let planes: Vec<_> = (0..4).map(|p| make_plane(96, 64, p)).collect();
+    check_round_trip(config, &planes);
+}
+
+#[test]
+fn test_encode_yuv410_large_contexts() {
+    let config = EncoderConfig {
+        width: 128,
+        height: 64,
+        log2_h_chroma_subsample: 2,
+        log2_v_chroma_subsample: 2,
+        num_h_slices: 1,
+        num_v_slices: 4,
+        context_model: 1,
+        ..Default::default()
+    };
+    let planes = vec![
+        make_plane(128, 64, 0),
+        make_plane(32, 16, 1),
+        make_plane(32, 16, 2),
+    ];
+    check_round_trip(config, &planes);
+}
+
+#[test]
+fn test_encode_gray() {
+    let config = EncoderConfig {
+        width: 50,
+        height: 30,
+        chroma_planes: false,
+        num_h_slices: 1,
+        num_v_slices: 1,
+        ..Default::default()
+    };
+    check_round_trip(config, &[make_plane(50, 30, 0)]);
+}
+
+// Odd dimensions, with slices starting on odd luma positions, so chroma
+// planes have a partial last column and row, and slices code the chroma
+// samples they share again
+#[test]
+fn test_encode_odd_dimensions() {
+    for &(width, height, log2_h, log2_v, num_h_slices, num_v_slices) in &[
+        (721, 481, 1, 1, 7, 7),
+        (723, 481, 1, 0, 3, 7),
+        (723, 483, 2, 2, 5, 3),
+        (15, 9, 1, 1, 3, 3),
+    ] {
+        let config = EncoderConfig {
+            width,
+            height,
+            log2_h_chroma_subsample: log2_h,
+            log2_v_chroma_subsample: log2_v,
+            num_h_slices,
+            num_v_slices,
+            ..Default::default()
+        };
+        let chroma_width = (width as usize + (1 << log2_h) - 1) >> log2_h;
+        let chroma_height = (height as usize + (1 << log2_v) - 1) >> log2_v;
+        let planes = vec![
+            make_plane(width as usize, height as usize, 0),
+            make_plane(chroma_width, chroma_height, 1),
+            make_plane(chroma_width, chroma_height, 2),
+        ];
+        check_round_trip(config, &planes);
+    }
+}
+
+// The last of 3 slices across 17 columns starts on column 11, so its
+// chroma plane starts on column 5 and holds 3 samples: the ninth chroma
+// column would be left uncoded.
+#[test]
+fn test_encode_uncoded_chroma() {
+    let config = EncoderConfig {
+        width: 17,
+        height: 9,
+        num_h_slices: 3,
+        num_v_slices: 3,
+        ..Default::default()
+    };
+    assert!(Encoder::new(config).is_err());
+}
+
+#[test]
+fn test_encode_gray_alpha_odd() {
+    let config = EncoderConfig {
+        width: 33,
+        height: 21,
+        chroma_planes: false,
+        extra_plane: true,
+        num_h_slices: 3,
+        num_v_slices: 2,
+        ..Default::default()
+    };
+    check_round_trip(config, &[make_plane(33, 21, 0), make_plane(33, 21, 1)]);
+}
+
+#[test]
+fn test_encode_interlaced() {
+    for &(picture_structure, height) in &[
+        (PictureStructure::TopFieldFirst, 45),
+        (PictureStructure::BottomFieldFirst, 46),
+    ] {
+        let config = EncoderConfig {
+            width: 40,
+            height,
+            picture_structure,
+            ..Default::default()
+        };
+        let chroma_height = (height - height / 2) as usize;
+        let planes = [
+            make_plane(40, height as usize, 0),
+            make_plane(20, chroma_height, 1),
+            make_plane(20, chroma_height, 2),
+        ];
+        let encoder = Encoder::new(config).unwrap();
+        let packet = encoder
+            .encode_frame(&[&planes[0], &planes[1], &planes[2]])
+            .unwrap();
+
+        let mut decoder =
+            Decoder::new(encoder.config_record(), 40, height).unwrap();
+        let frame = decoder.decode_frame(&packet).unwrap();
+        assert_eq!(frame.picture_structure(), picture_structure);
+        assert_eq!(decoder.picture_structure(), picture_structure);
+
+        let (top, bottom) = frame.split_fields();
+        assert_eq!(top.height(), height - height / 2);
+        assert_eq!(bottom.height(), height / 2);
+        for (parity, field) in [top, bottom].iter().enumerate() {
+            assert_eq!(field.picture_structure(), picture_structure);
+            for (p, plane) in planes.iter().enumerate() {
+                let field_plane = field.plane_u8(p).unwrap();
+                let width = field_plane.width as usize;
+                let rows = plane.len() / width;
+                for y in 0..field_plane.height as usize {
+                    // Chroma rows missing from a field repeat its last one.
+                    let mut src = 2 * y + parity;
+                    if src >= rows {
+                        src -= 2;
+                    }
+                    assert!(
+                        field_plane.row(y as u32)
+                            == &plane[src * width..(src + 1) * width]
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn test_encode_sample_aspect_ratio() {
+    let config = EncoderConfig {
+        width: 64,
+        height: 48,
+        sample_aspect_ratio: Some((16, 15)),
+        ..Default::default()
+    };
+    let planes = [
+        make_plane(64, 48, 0),
+        make_plane(32, 24, 1),
+        make_plane(32, 24, 2),
+    ];
+    let encoder = Encoder::new(config).unwrap();
+    let packet = encoder
+        .encode_frame(&[&planes[0], &planes[1], &planes[2]])
+        .unwrap();
+
+    let mut decoder = Decoder::new(encoder.config_record(), 64, 48).unwrap();
+    let frame = decoder.decode_frame(&packet).unwrap();
+    assert_eq!(frame.sample_aspect_ratio(), Some((16, 15)));
+    assert!(!frame.sample_aspect_ratio_mismatch());
+    assert_eq!(frame.slices().len(), 4);
+    assert!(decoder.slices() == frame.slices());
+}
+
+#[test]
+fn test_encode_without_ec() {
+    let planes = [
+        make_plane(64, 48, 0),
+        make_plane(32, 24, 1),
+        make_plane(32, 24, 2),
+    ];
+    let planes: Vec<&[u8]> = planes.iter().map(|p| p.as_slice()).collect();
+    let encode = |ec| {
+        let encoder = Encoder::new(EncoderConfig {
+            width: 64,
+            height: 48,
+            ec,
+            ..Default::default()
+        })
+        .unwrap();
+        let packet = encoder.encode_frame(&planes).unwrap();
+        (encoder.config_record().to_owned(), packet)
+    };
+
+    let (record, packet) = encode(false);
+    let (_, packet_ec) = encode(true);
+    assert_eq!(ConfigRecord::parse_config_record(&record).unwrap().ec, 0);
+    // Each of the 4 slice footers goes without error_status and its CRC.
+    assert_eq!(packet.len() + 4 * 5, packet_ec.len());
+
+    let mut decoder = Decoder::new(&record, 64, 48).unwrap();
+    let frame = decoder.decode_frame(&packet).unwrap();
+    for (p, plane) in planes.iter().enumerate() {
+        assert!(frame.plane_u8(p).unwrap().data == *plane, "plane {}", p);
+    }
+    assert!(decoder.verify_frame(&packet).is_err());
+}
+
+#[test]
+fn test_encode_threads() {
+    let config = EncoderConfig {
+        width: 64,
+        height: 64,
+        num_h_slices: 4,
+        num_v_slices: 4,
+        ..Default::default()
+    };
+    let planes = [
+        make_plane(64, 64, 0),
+        make_plane(32, 32, 1),
+        make_plane(32, 32, 2),
+    ];
+    let planes: Vec<&[u8]> = planes.iter().map(|p| p.as_slice()).collect();
+
+    let mut encoder = Encoder::new(config).unwrap();
+    encoder.set_threads(1).unwrap();
+    let sequential = encoder.encode_frame(&planes).unwrap();
+    encoder.set_threads(4).unwrap();
+    assert!(encoder.encode_frame(&planes).unwrap() == sequential);
+}
+
+#[test]
+fn test_encode_invalid_planes() {
+    let config = EncoderConfig {
+        width: 64,
+        height: 64,
+        ..Default::default()
+    };
+    let encoder = Encoder::new(config).unwrap();
+    let luma = make_plane(64, 64, 0);
+    let chroma = make_plane(32, 32, 1);
+
+    assert!(encoder.encode_frame(&[&luma]).is_err());
+    assert!(encoder.encode_frame(&[&luma, &chroma, &luma]).is_err());
+}
@@ -0,0 +1,125 @@
+use ffv1::golomb::{Coder, Encoder, State};
+
+// Number of contexts symbols are spread over, context 0 being the one
+// starting runs
+const CONTEXTS: usize = 8;
+
+// A plane of symbols, each with the context it is coded in
+struct Plane {
+    width: usize,
+    lines: Vec<Vec<(i32, i32)>>,
+}
+
+// Builds planes mixing runs of zeros of any length, small differences
+// and differences large enough to be escaped
+fn make_planes(bits: usize, seed: u32) -> Vec<Plane> {
+    let mut state = seed;
+    let mut next = move || {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        state >> 8
+    };
+
+    let min = -(1 << (bits - 1));
+    let max = (1 << (bits - 1)) - 1;
+    [1, 7, 64, 1000]
+        .iter()
+        .map(|&width| {
+            let lines = (0..6)
+                .map(|_| {
+                    let mut line = Vec::with_capacity(width);
+                    while line.len() < width {
+                        let len =
+                            (next() as usize % 300).min(width - line.len());
+                        match next() % 4 {
+                            // A run, which may end the line
+                            0 => line.extend((0..len).map(|_| (0, 0))),
+                            // Zeros coded outside of a run
+                            1 => line.extend((0..len).map(|_| (1, 0))),
+                            _ => line.extend((0..len).map(|_| {
+                                let context =
+                                    (next() % CONTEXTS as u32) as i32;
+                                let v = match next() % 8 {
+                                    0 => min,
+                                    1 => max,
+                                    2 => (next() as i32 % (max + 1)) - max / 2,
+                                    _ => (next() % 7) as i32 - 3,
+                                };
+                                (context, v)
+                            })),
+                        }
+                    }
+                    line
+                })
+                .collect();
+            Plane { width, lines }
+        })
+        .collect()
+}
+
+fn check_round_trip(bits: usize, seed: u32) {
+    let planes = make_planes(bits, seed);
+
+    let mut encoder = Encoder::new();
+    let mut states = vec![State::new(); CONTEXTS];
+    for plane in &planes {
+        encoder.new_plane();
+        for line in &plane.lines {
+            encoder.new_line();
+            for &(context, v) in line {
+                encoder.put_sg(
+                    context,
+                    &mut states[context as usize],
+                    v,
+                    bits,
+                );
+            }
+        }
+    }
+    let buf = encoder.finish();
+
+    let mut coder = Coder::new(&buf);
+    let mut states = vec![State::new(); CONTEXTS];
+    for (p, plane) in planes.iter().enumerate() {
+        coder.new_plane(plane.width as u32);
+        for (y, line) in plane.lines.iter().enumerate() {
+            coder.new_line();
+            for (x, &(context, v)) in line.iter().enumerate() {
+                let read = coder
+                    .sg(context, &mut states[context as usize], bits)
+                    .unwrap();
+                assert_eq!(read, v, "plane {} line {} sample {}", p, y, x);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_golomb_round_trip() {
+    for seed in 0..8 {
+        check_round_trip(8, seed);
+    }
+}
+
+#[test]
+fn test_golomb_round_trip_rgb() {
+    // JPEG2000-RCT planes have an extra bit.
+    for seed in 0..8 {
+        check_round_trip(9, seed);
+    }
+}
+
+#[test]
+fn test_golomb_vlc_symbols() {
+    let mut encoder = Encoder::new();
+    let mut state = State::new();
+    for v in -128..128 {
+        encoder.put_vlc_symbol(&mut state, v, 8);
+    }
+    let buf = encoder.finish();
+
+    let mut coder = Coder::new(&buf);
+    let mut state = State::new();
+    for v in -128..128 {
+        assert_eq!(coder.get_vlc_symbol(&mut state, 8).unwrap(), v);
+    }
+}
@@ -0,0 +1,220 @@
+use std::fs::File;
+
+use ffv1::decoder::Decoder;
+use ffv1::encoder::{Encoder, EncoderConfig};
+use ffv1::mkv::MkvReader;
+use ffv1::record::ConfigRecord;
+
+const SAMPLES: [&str; 7] = [
+    "data/ffv1_v2_bgr0.mkv",
+    "data/ffv1_v2_yuv420p.mkv",
+    "data/ffv1_v3_bgr0.mkv",
+    "data/ffv1_v3_gbrp16le.mkv",
+    "data/ffv1_v3_yuv420p.mkv",
+    "data/ffv1_v4_bgr0.mkv",
+    "data/ffv1_v4_yuv420p.mkv",
+];
+
+const WIDTH: u32 = 32;
+const HEIGHT: u32 = 16;
+
+// Unknown size of an element
+const UNKNOWN: [u8; 8] = [0x01, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff];
+
+// Encodes an element of known size
+fn element(id: u32, payload: &[u8]) -> Vec<u8> {
+    let mut buf: Vec<u8> = id
+        .to_be_bytes()
+        .iter()
+        .copied()
+        .skip_while(|&byte| byte == 0)
+        .collect();
+    buf.push(0x01);
+    buf.extend_from_slice(&(payload.len() as u64).to_be_bytes()[1..]);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+// Encodes an element of unknown size, whose content follows it
+fn unknown_size(id: u32) -> Vec<u8> {
+    let mut buf = id.to_be_bytes().to_vec();
+    buf.extend_from_slice(&UNKNOWN);
+    buf
+}
+
+fn uint(id: u32, value: u64) -> Vec<u8> {
+    element(id, &value.to_be_bytes())
+}
+
+fn block(track: u8, timestamp: i16, flags: u8, data: &[u8]) -> Vec<u8> {
+    let mut buf = vec![0x80 | track];
+    buf.extend_from_slice(&timestamp.to_be_bytes());
+    buf.push(flags);
+    buf.extend_from_slice(data);
+    buf
+}
+
+// Builds the headers of a WebM file, with an audio track numbered 1, and
+// a V_FFV1 track numbered 2 if there is a record
+fn headers(record: Option<&[u8]>) -> Vec<u8> {
+    let mut file = element(0x1A45_DFA3, &element(0x4282, b"webm"));
+    file.extend(unknown_size(0x1853_8067));
+    file.extend(element(0x1549_A966, &uint(0x2A_D7B1, 100_000)));
+
+    let mut tracks = element(
+        0xAE,
+        &[uint(0xD7, 1), uint(0x83, 2), element(0x86, b"A_OPUS")].concat(),
+    );
+    if let Some(record) = record {
+        let video =
+            [uint(0xB0, WIDTH as u64), uint(0xBA, HEIGHT as u64)].concat();
+        tracks.extend(element(
+            0xAE,
+            &[
+                uint(0xD7, 2),
+                uint(0x83, 1),
+                element(0x86, b"V_FFV1"),
+                element(0x63A2, record),
+                element(0xE0, &video),
+            ]
+            .concat(),
+        ));
+    }
+    file.extend(element(0x1654_AE6B, &tracks));
+
+    // Void
+    file.extend(element(0xEC, &[0; 5]));
+    file
+}
+
+// Encodes a 4:2:0 frame whose luma samples all are 'luma'
+fn encode(encoder: &Encoder, luma: u8) -> Vec<u8> {
+    let luma = vec![luma; (WIDTH * HEIGHT) as usize];
+    let chroma = vec![128; (WIDTH * HEIGHT / 4) as usize];
+    encoder.encode_frame(&[&luma, &chroma, &chroma]).unwrap()
+}
+
+#[test]
+fn test_samples() {
+    for input in SAMPLES.iter() {
+        let mut reader = MkvReader::new(File::open(input).unwrap()).unwrap();
+        assert_eq!(reader.tracks().len(), 1, "{}", input);
+        let track = reader.tracks()[0].clone();
+        assert_eq!(track.codec_id, "V_MS/VFW/FOURCC", "{}", input);
+
+        // The record is the whole codec private data past the header,
+        // without the padding of the muxer.
+        let record =
+            ConfigRecord::parse_config_record(&track.config_record).unwrap();
+        assert!(
+            record.write_config_record().unwrap() == track.config_record,
+            "{}",
+            input
+        );
+
+        let mut decoder =
+            Decoder::new(&track.config_record, track.width, track.height)
+                .unwrap();
+        let mut last_timestamp = -1;
+        let mut count = 0;
+        for packet in &mut reader {
+            let packet = packet.unwrap();
+            assert_eq!(packet.track, track.number, "{}", input);
+            assert!(packet.timestamp > last_timestamp, "{}", input);
+            last_timestamp = packet.timestamp;
+            if count == 0 {
+                assert_eq!(packet.timestamp, 0, "{}", input);
+                decoder.decode_frame(&packet.data).unwrap();
+            }
+            count += 1;
+        }
+        assert!(count > 0, "{}", input);
+    }
+}
+
+#[test]
+fn test_ffv1_track() {
+    let encoder = Encoder::new(EncoderConfig {
+        width: WIDTH,
+        height: HEIGHT,
+        ..Default::default()
+    })
+    .unwrap();
+    let frames: Vec<_> = (0..3).map(|i| encode(&encoder, i * 50)).collect();
+
+    let mut file = headers(Some(encoder.config_record()));
+    file.extend(unknown_size(0x1F43_B675));
+    file.extend(uint(0xE7, 10));
+    file.extend(element(0xA3, &block(1, 0, 0x80, b"opus")));
+    file.extend(element(0xA3, &block(2, 0, 0x80, &frames[0])));
+    file.extend(element(0xA0, &element(0xA1, &block(2, 5, 0, &frames[1]))));
+    let cluster = [
+        uint(0xE7, 20),
+        element(0xA3, &block(2, -3, 0x80, &frames[2])),
+    ]
+    .concat();
+    file.extend(element(0x1F43_B675, &cluster));
+
+    let reader = MkvReader::new(&file[..]).unwrap();
+    assert_eq!(reader.tracks().len(), 1);
+    let track = reader.tracks()[0].clone();
+    assert_eq!(track.number, 2);
+    assert_eq!(track.codec_id, "V_FFV1");
+    assert_eq!((track.width, track.height), (WIDTH, HEIGHT));
+    assert_eq!(track.config_record, encoder.config_record());
+
+    let packets: Vec<_> = reader.map(|packet| packet.unwrap()).collect();
+    let timestamps: Vec<_> = packets.iter().map(|p| p.timestamp).collect();
+    assert_eq!(timestamps, [1_000_000, 1_500_000, 1_700_000]);
+
+    let mut decoder =
+        Decoder::new(&track.config_record, WIDTH, HEIGHT).unwrap();
+    for (i, packet) in packets.iter().enumerate() {
+        assert_eq!(packet.track, 2);
+        assert!(packet.data == frames[i]);
+        let frame = decoder.decode_frame(&packet.data).unwrap();
+        let luma = frame.plane_u8(0).unwrap().data;
+        assert!(luma.iter().all(|&sample| sample == i as u8 * 50));
+    }
+}
+
+#[test]
+fn test_invalid_files() {
+    // Not EBML
+    assert!(MkvReader::new(&b"RIFF\0\0\0\0AVI LIST"[..]).is_err());
+
+    // No FFV1 track
+    let mut file = headers(None);
+    file.extend(unknown_size(0x1F43_B675));
+    assert!(MkvReader::new(&file[..]).is_err());
+
+    let encoder = Encoder::new(EncoderConfig {
+        width: WIDTH,
+        height: HEIGHT,
+        ..Default::default()
+    })
+    .unwrap();
+    let frame = encode(&encoder, 0);
+    let mut file = headers(Some(encoder.config_record()));
+    file.extend(unknown_size(0x1F43_B675));
+
+    // Xiph lacing
+    let mut laced = file.clone();
+    laced.extend(element(0xA3, &block(2, 0, 0x82, &frame)));
+    let mut reader = MkvReader::new(&laced[..]).unwrap();
+    assert!(reader.next_packet().is_err());
+
+    // Block timestamp past the largest cluster timestamp
+    let mut overflow = file.clone();
+    overflow.extend(uint(0xE7, i64::MAX as u64));
+    overflow.extend(element(0xA3, &block(2, 1, 0x80, &frame)));
+    let mut reader = MkvReader::new(&overflow[..]).unwrap();
+    assert!(reader.next_packet().is_err());
+
+    // Truncated block
+    let mut truncated = file;
+    truncated.extend(element(0xA3, &block(2, 0, 0x80, &frame)));
+    truncated.truncate(truncated.len() - 1);
+    let mut reader = MkvReader::new(&truncated[..]).unwrap();
+    assert!(reader.next_packet().is_err());
+}
@@ -0,0 +1,276 @@
+use std::io::Cursor;
+
+use ffv1::decoder::Decoder;
+use ffv1::encoder::{Encoder, EncoderConfig};
+use ffv1::mp4::Mp4Reader;
+
+const WIDTH: u32 = 32;
+const HEIGHT: u32 = 16;
+
+fn boxed(box_type: &[u8; 4], payload: &[u8]) -> Vec<u8> {
+    let mut buf = (8 + payload.len() as u32).to_be_bytes().to_vec();
+    buf.extend_from_slice(box_type);
+    buf.extend_from_slice(payload);
+    buf
+}
+
+fn full_box(box_type: &[u8; 4], version: u8, payload: &[u8]) -> Vec<u8> {
+    boxed(box_type, &[&[version, 0, 0, 0][..], payload].concat())
+}
+
+// Writes a table box of u32 or u64 entries
+fn table(box_type: &[u8; 4], entries: &[&[u64]], wide: bool) -> Vec<u8> {
+    let mut payload = (entries.len() as u32).to_be_bytes().to_vec();
+    for entry in entries {
+        for &field in entry.iter() {
+            if wide {
+                payload.extend_from_slice(&field.to_be_bytes());
+            } else {
+                payload.extend_from_slice(&(field as u32).to_be_bytes());
+            }
+        }
+    }
+    full_box(box_type, 0, &payload)
+}
+
+// Sample table of a track, its samples being 'sizes' long and stored in
+// chunks at 'chunk_offsets', in runs given by 'stsc' entries
+struct SampleTable<'a> {
+    sizes: &'a [u32],
+    chunk_offsets: &'a [u64],
+    stsc: &'a [&'a [u64]],
+    stts: &'a [&'a [u64]],
+    stss: Option<&'a [&'a [u64]]>,
+    co64: bool,
+}
+
+// Writes a 'trak' box, with a version 1 'mdhd' box if 'mdhd_v1'
+fn trak(
+    id: u32,
+    entry_type: &[u8; 4],
+    record: &[u8],
+    mdhd_v1: bool,
+    samples: &SampleTable,
+) -> Vec<u8> {
+    let mut tkhd = vec![0; 8];
+    tkhd.extend_from_slice(&id.to_be_bytes());
+    tkhd.resize(80, 0);
+
+    // Times, then timescale, for 25 frames per second
+    let mut mdhd = vec![0; if mdhd_v1 { 16 } else { 8 }];
+    mdhd.extend_from_slice(&25u32.to_be_bytes());
+    mdhd.resize(mdhd.len() + 12, 0);
+
+    let mut entry = vec![0; 24];
+    entry.extend_from_slice(&(WIDTH as u16).to_be_bytes());
+    entry.extend_from_slice(&(HEIGHT as u16).to_be_bytes());
+    entry.resize(78, 0);
+    entry.extend(boxed(b"fiel", &[1, 0]));
+    entry.extend(boxed(b"glbl", record));
+    let stsd = full_box(
+        b"stsd",
+        0,
+        &[&1u32.to_be_bytes()[..], &boxed(entry_type, &entry)].concat(),
+    );
+
+    let mut stsz = 0u32.to_be_bytes().to_vec();
+    stsz.extend_from_slice(&(samples.sizes.len() as u32).to_be_bytes());
+    for size in samples.sizes {
+        stsz.extend_from_slice(&size.to_be_bytes());
+    }
+    let offsets: Vec<&[u64]> = samples
+        .chunk_offsets
+        .iter()
+        .map(std::slice::from_ref)
+        .collect();
+    let mut stbl = [
+        stsd,
+        table(b"stts", samples.stts, false),
+        table(b"stsc", samples.stsc, false),
+        full_box(b"stsz", 0, &stsz),
+        if samples.co64 {
+            table(b"co64", &offsets, true)
+        } else {
+            table(b"stco", &offsets, false)
+        },
+    ]
+    .concat();
+    if let Some(stss) = samples.stss {
+        stbl.extend(table(b"stss", stss, false));
+    }
+
+    let minf = boxed(b"stbl", &stbl);
+    let mdia = [
+        full_box(b"mdhd", mdhd_v1 as u8, &mdhd),
+        boxed(b"minf", &minf),
+    ]
+    .concat();
+    boxed(
+        b"trak",
+        &[full_box(b"tkhd", 0, &tkhd), boxed(b"mdia", &mdia)].concat(),
+    )
+}
+
+fn encode() -> (Vec<u8>, Vec<Vec<u8>>) {
+    let encoder = Encoder::new(EncoderConfig {
+        width: WIDTH,
+        height: HEIGHT,
+        ..Default::default()
+    })
+    .unwrap();
+    let chroma = vec![128; (WIDTH * HEIGHT / 4) as usize];
+    let frames = (0..3)
+        .map(|i| {
+            let luma = vec![i * 50; (WIDTH * HEIGHT) as usize];
+            encoder.encode_frame(&[&luma, &chroma, &chroma]).unwrap()
+        })
+        .collect();
+
+    (encoder.config_record().to_owned(), frames)
+}
+
+// Writes an MP4 file with the 'moov' box last, whose FFV1 track stores
+// two frames in a chunk, then a frame in another chunk, after a sample of
+// an AVC track
+fn write_mp4(record: &[u8], frames: &[Vec<u8>]) -> Vec<u8> {
+    let mut file = boxed(b"ftyp", b"isom\0\0\0\0isom");
+    let mdat = [&frames[0][..], &frames[1], b"avc", &frames[2]].concat();
+    let first = file.len() as u64 + 8;
+    let avc = first + (frames[0].len() + frames[1].len()) as u64;
+    file.extend(boxed(b"mdat", &mdat));
+
+    let sizes: Vec<_> = frames.iter().map(|f| f.len() as u32).collect();
+    let ffv1 = SampleTable {
+        sizes: &sizes,
+        chunk_offsets: &[first, avc + 3],
+        stsc: &[&[1, 2, 1], &[2, 1, 1]],
+        stts: &[&[1, 1], &[2, 2]],
+        stss: Some(&[&[1], &[3]]),
+        co64: false,
+    };
+    let h264 = SampleTable {
+        sizes: &[3],
+        chunk_offsets: &[avc],
+        stsc: &[&[1, 1, 1]],
+        stts: &[&[1, 1]],
+        stss: None,
+        co64: false,
+    };
+    let moov = [
+        full_box(b"mvhd", 0, &[0; 96]),
+        trak(1, b"avc1", &[], false, &h264),
+        trak(2, b"FFV1", record, false, &ffv1),
+    ]
+    .concat();
+    file.extend(boxed(b"moov", &moov));
+
+    file
+}
+
+// Writes a MOV file with the 'moov' box first, and an 'mdat' box
+// extending to the end of the file
+fn write_mov(record: &[u8], frames: &[Vec<u8>]) -> Vec<u8> {
+    let sizes: Vec<_> = frames.iter().map(|f| f.len() as u32).collect();
+    let moov = |first: u64| {
+        let samples = SampleTable {
+            sizes: &sizes,
+            chunk_offsets: &[first],
+            stsc: &[&[1, 3, 1]],
+            stts: &[&[3, 1]],
+            stss: None,
+            co64: true,
+        };
+        boxed(b"moov", &trak(1, b"FFV1", record, true, &samples))
+    };
+
+    let mut file = boxed(b"ftyp", b"qt  \0\0\0\0qt  ");
+    file.extend(boxed(b"wide", &[]));
+    let first = (file.len() + moov(0).len() + 8) as u64;
+    file.extend(moov(first));
+    file.extend_from_slice(&[0, 0, 0, 0]);
+    file.extend_from_slice(b"mdat");
+    for frame in frames {
+        file.extend_from_slice(frame);
+    }
+
+    file
+}
+
+// Reads the frames of a file, and checks them against 'frames'
+fn check_frames(file: &[u8], record: &[u8], frames: &[Vec<u8>]) -> Vec<u32> {
+    let mut reader = Mp4Reader::new(Cursor::new(file)).unwrap();
+    assert_eq!(reader.tracks().len(), 1);
+    let track = reader.tracks()[0].clone();
+    assert_eq!((track.width, track.height), (WIDTH, HEIGHT));
+    assert_eq!(track.timescale, 25);
+    assert_eq!(track.sample_count, frames.len());
+    assert!(track.config_record == record);
+
+    let mut decoder =
+        Decoder::new(&track.config_record, track.width, track.height).unwrap();
+    let mut timestamps = Vec::new();
+    for (i, packet) in (&mut reader).enumerate() {
+        let packet = packet.unwrap();
+        assert_eq!(packet.track, track.id);
+        assert_eq!(packet.sample, i);
+        assert!(packet.data == frames[i]);
+        let frame = decoder.decode_frame(&packet.data).unwrap();
+        let luma = frame.plane_u8(0).unwrap().data;
+        assert!(luma.iter().all(|&sample| sample == i as u8 * 50));
+        timestamps.push((packet.timestamp / 40_000_000) as u32);
+    }
+    assert_eq!(timestamps.len(), frames.len());
+
+    timestamps
+}
+
+#[test]
+fn test_mp4() {
+    let (record, frames) = encode();
+    let file = write_mp4(&record, &frames);
+    assert_eq!(check_frames(&file, &record, &frames), [0, 1, 3]);
+
+    let reader = Mp4Reader::new(Cursor::new(&file)).unwrap();
+    assert_eq!(reader.tracks()[0].id, 2);
+    let keyframes: Vec<_> = reader.map(|p| p.unwrap().keyframe).collect();
+    assert_eq!(keyframes, [true, false, true]);
+}
+
+#[test]
+fn test_mov() {
+    let (record, frames) = encode();
+    let file = write_mov(&record, &frames);
+    assert_eq!(check_frames(&file, &record, &frames), [0, 1, 2]);
+
+    let mut reader = Mp4Reader::new(Cursor::new(&file)).unwrap();
+    assert!(reader.all(|packet| packet.unwrap().keyframe));
+}
+
+#[test]
+fn test_invalid_files() {
+    let (record, frames) = encode();
+
+    // No moov box
+    let file = boxed(b"ftyp", b"isom\0\0\0\0isom");
+    assert!(Mp4Reader::new(Cursor::new(&file)).is_err());
+
+    // No FFV1 track
+    let mut file = write_mp4(&record, &frames);
+    let entry = file.windows(4).rposition(|w| w == b"FFV1").unwrap();
+    file[entry..entry + 4].copy_from_slice(b"avc1");
+    assert!(Mp4Reader::new(Cursor::new(&file)).is_err());
+
+    // More samples than the sizes table holds
+    let mut file = write_mp4(&record, &frames);
+    let stsz = file.windows(4).rposition(|w| w == b"stsz").unwrap();
+    file[stsz + 15] = 4;
+    assert!(Mp4Reader::new(Cursor::new(&file)).is_err());
+
+    // Last sample past the end of the file
+    let mut file = write_mov(&record, &frames);
+    file.pop();
+    let mut reader = Mp4Reader::new(Cursor::new(&file)).unwrap();
+    assert!(reader.next_packet().unwrap().is_some());
+    assert!(reader.next_packet().unwrap().is_some());
+    assert!(reader.next_packet().is_err());
+}
@@ -0,0 +1,155 @@
+use ffv1::range::{RangeCoder, RangeEncoder};
+use ffv1::rangecoder::tables::DEFAULT_STATE_TRANSITION;
+
+// A symbol as written to and read from a range coder
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Symbol {
+    Unsigned(u32),
+    Signed(i32),
+    Bool(bool),
+}
+
+// Builds a sequence of symbols, small ones most of the time like the ones
+// coded in a slice, along with the extremes of each kind
+fn make_symbols(count: usize, seed: u32) -> Vec<(usize, Symbol)> {
+    let mut symbols = vec![
+        (0, Symbol::Unsigned(0)),
+        (0, Symbol::Unsigned(u32::MAX)),
+        (1, Symbol::Signed(i32::MIN)),
+        (1, Symbol::Signed(i32::MAX)),
+        (2, Symbol::Bool(true)),
+    ];
+
+    let mut state = seed;
+    for _ in 0..count {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        let bits = (state >> 16) % 33;
+        let value = if bits == 0 {
+            0
+        } else {
+            (state.rotate_left(7) ^ 0x5bd1_e995) >> (32 - bits)
+        };
+        let context = (state >> 8) as usize % 4;
+        let symbol = match (state >> 4) % 3 {
+            0 => Symbol::Unsigned(value),
+            1 => Symbol::Signed(value as i32 >> (bits / 2)),
+            _ => Symbol::Bool(value & 1 == 1),
+        };
+        symbols.push((context, symbol));
+    }
+
+    symbols
+}
+
+fn write_symbols(encoder: &mut RangeEncoder, symbols: &[(usize, Symbol)]) {
+    let mut states = [[128; 32]; 4];
+    for &(context, symbol) in symbols {
+        let state = &mut states[context];
+        match symbol {
+            Symbol::Unsigned(v) => encoder.put_ur(state, v),
+            Symbol::Signed(v) => encoder.put_sr(state, v),
+            Symbol::Bool(v) => encoder.put_bool(state, v),
+        }
+    }
+}
+
+fn read_symbols(coder: &mut RangeCoder, symbols: &[(usize, Symbol)]) {
+    let mut states = [[128; 32]; 4];
+    for (i, &(context, symbol)) in symbols.iter().enumerate() {
+        let state = &mut states[context];
+        let read = match symbol {
+            Symbol::Unsigned(_) => Symbol::Unsigned(coder.ur(state).unwrap()),
+            Symbol::Signed(_) => Symbol::Signed(coder.sr(state).unwrap()),
+            Symbol::Bool(_) => Symbol::Bool(coder.br(state)),
+        };
+        assert_eq!(read, symbol, "symbol {}", i);
+    }
+}
+
+// A state transition table adapting faster than the default one
+fn custom_table() -> [u8; 256] {
+    let mut table = [0; 256];
+    for (i, state) in table.iter_mut().enumerate().skip(1) {
+        *state = (i + (255 - i) / 4) as u8;
+    }
+    table
+}
+
+#[test]
+fn test_range_round_trip() {
+    for seed in 0..8 {
+        let symbols = make_symbols(10000, seed);
+
+        let mut encoder = RangeEncoder::new();
+        write_symbols(&mut encoder, &symbols);
+        let buf = encoder.finish();
+
+        read_symbols(&mut RangeCoder::new(&buf).unwrap(), &symbols);
+    }
+}
+
+#[test]
+fn test_range_put_symbol() {
+    let mut encoder = RangeEncoder::new();
+    let mut state = [128; 32];
+    for v in -300..300 {
+        encoder.put_symbol(&mut state, v, true);
+        encoder.put_symbol(&mut state, v.abs(), false);
+    }
+    let buf = encoder.finish();
+
+    let mut coder = RangeCoder::new(&buf).unwrap();
+    let mut state = [128; 32];
+    for v in -300..300 {
+        assert_eq!(coder.symbol(&mut state, true).unwrap(), v);
+        assert_eq!(coder.symbol(&mut state, false).unwrap(), v.abs());
+    }
+}
+
+#[test]
+fn test_range_custom_table() {
+    let table = custom_table();
+    assert_ne!(table, DEFAULT_STATE_TRANSITION);
+    let symbols = make_symbols(10000, 42);
+
+    let mut encoder = RangeEncoder::new();
+    encoder.set_table(&table);
+    write_symbols(&mut encoder, &symbols);
+    let buf = encoder.finish();
+
+    let mut coder = RangeCoder::new(&buf).unwrap();
+    coder.set_table(&table);
+    read_symbols(&mut coder, &symbols);
+}
+
+#[test]
+fn test_range_sentinal_end() {
+    for seed in 0..64 {
+        let symbols = make_symbols(seed as usize * 7, seed);
+
+        let mut encoder = RangeEncoder::new();
+        write_symbols(&mut encoder, &symbols);
+        encoder.sentinal_end();
+        let mut buf = encoder.finish();
+        let len = buf.len();
+
+        // Whatever follows the range coded bytes must not matter, and
+        // its position must be found.
+        buf.extend_from_slice(&[0xFF, 0x00, 0xAA, 0x55]);
+
+        let mut coder = RangeCoder::new(&buf).unwrap();
+        read_symbols(&mut coder, &symbols);
+        coder.sentinal_end();
+        assert_eq!(coder.get_pos() - 1, len as isize, "seed {}", seed);
+    }
+}
+
+#[test]
+fn test_range_empty() {
+    // Even with nothing coded, the decoder gets the two bytes it needs.
+    assert!(RangeCoder::new(&RangeEncoder::new().finish()).is_ok());
+
+    let mut encoder = RangeEncoder::new();
+    encoder.sentinal_end();
+    assert!(RangeCoder::new(&encoder.finish()).is_ok());
+}
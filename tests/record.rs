@@ -0,0 +1,84 @@
+use std::fs::File;
+
+use ffv1::mkv::MkvReader;
+use ffv1::range::{RangeCoder, RangeEncoder};
+use ffv1::record::ConfigRecord;
+
+const SAMPLES: [&str; 7] = [
+    "data/ffv1_v2_bgr0.mkv",
+    "data/ffv1_v2_yuv420p.mkv",
+    "data/ffv1_v3_bgr0.mkv",
+    "data/ffv1_v3_gbrp16le.mkv",
+    "data/ffv1_v3_yuv420p.mkv",
+    "data/ffv1_v4_bgr0.mkv",
+    "data/ffv1_v4_yuv420p.mkv",
+];
+
+// Reads the configuration record of a matroska file
+fn read_record(input: &str) -> Vec<u8> {
+    let reader = MkvReader::new(File::open(input).unwrap())
+        .expect("Cannot parse the format headers");
+
+    reader.tracks()[0].config_record.clone()
+}
+
+#[test]
+fn test_round_trip() {
+    for input in SAMPLES.iter() {
+        let buf = read_record(input);
+        let record = ConfigRecord::parse_config_record(&buf).unwrap();
+        let written = record.write_config_record().unwrap();
+
+        assert!(written == buf, "{}", input);
+    }
+}
+
+#[test]
+fn test_states_coded() {
+    let buf = read_record("data/ffv1_v3_yuv420p.mkv");
+    let mut record = ConfigRecord::parse_config_record(&buf).unwrap();
+    for (j, deltas) in record.initial_state_delta[1].iter_mut().enumerate() {
+        deltas[j % 32] = (j % 7) as i16 - 3;
+    }
+
+    let written = record.write_config_record().unwrap();
+    assert!(written.len() > buf.len());
+
+    let parsed = ConfigRecord::parse_config_record(&written).unwrap();
+    assert_eq!(parsed.initial_state_delta, record.initial_state_delta);
+    assert!(parsed.write_config_record().unwrap() == written);
+}
+
+#[test]
+fn test_keyframe_header() {
+    let buf = read_record("data/ffv1_v3_yuv420p.mkv");
+    let mut record = ConfigRecord::parse_config_record(&buf).unwrap();
+    assert!(record
+        .write_keyframe_header(&mut RangeEncoder::new())
+        .is_err());
+
+    record.version = 1;
+    let mut coder = RangeEncoder::new();
+    record.write_keyframe_header(&mut coder).unwrap();
+    let written = coder.finish();
+
+    let mut coder = RangeCoder::new(&written).unwrap();
+    let parsed = ConfigRecord::parse_keyframe_header(&mut coder).unwrap();
+    assert_eq!(parsed.version, 1);
+    assert_eq!(parsed.quant_table_set_count, 1);
+    assert_eq!(parsed.context_count[0], record.context_count[0]);
+    assert!(parsed.quant_tables[0] == record.quant_tables[0]);
+}
+
+#[test]
+fn test_invalid() {
+    let buf = read_record("data/ffv1_v3_yuv420p.mkv");
+    let mut record = ConfigRecord::parse_config_record(&buf).unwrap();
+
+    assert!(record.set_quant_table_set(0, &[[128]; 4]).is_err());
+    assert!(record.set_quant_table_set(0, &[[127]; 5]).is_err());
+    assert!(record.set_quant_table_set(0, &[[0, 128]; 5]).is_err());
+
+    record.version = 5;
+    assert!(record.write_config_record().is_err());
+}
@@ -0,0 +1,103 @@
+use ffv1::decoder::Decoder;
+use ffv1::encoder::{Encoder, EncoderConfig};
+use ffv1::vfw::BitmapInfoHeader;
+
+const WIDTH: u32 = 32;
+const HEIGHT: u32 = 16;
+
+// Writes a BITMAPINFOHEADER followed by 'record', with biSize set to
+// 'size'
+fn bitmap_info_header(
+    size: u32,
+    height: i32,
+    fourcc: &[u8; 4],
+    record: &[u8],
+) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&size.to_le_bytes());
+    buf.extend_from_slice(&(WIDTH as i32).to_le_bytes());
+    buf.extend_from_slice(&height.to_le_bytes());
+    // biPlanes, biBitCount
+    buf.extend_from_slice(&1u16.to_le_bytes());
+    buf.extend_from_slice(&24u16.to_le_bytes());
+    buf.extend_from_slice(fourcc);
+    // biSizeImage to biClrImportant
+    buf.extend_from_slice(&[0; 20]);
+    buf.extend_from_slice(record);
+    buf
+}
+
+fn encoder() -> Encoder {
+    Encoder::new(EncoderConfig {
+        width: WIDTH,
+        height: HEIGHT,
+        ..Default::default()
+    })
+    .unwrap()
+}
+
+#[test]
+fn test_parse() {
+    let encoder = encoder();
+    let record = encoder.config_record();
+    let size = 40 + record.len() as u32;
+
+    // Padded by the muxer
+    let mut buf = bitmap_info_header(size, HEIGHT as i32, b"FFV1", record);
+    buf.push(0);
+    let header = BitmapInfoHeader::parse_bitmap_info_header(&buf).unwrap();
+    assert_eq!(header.size, size);
+    assert_eq!((header.width, header.height), (WIDTH as i32, HEIGHT as i32));
+    assert_eq!(header.bit_count, 24);
+    assert_eq!(&header.fourcc, b"FFV1");
+    assert!(header.config_record == record);
+
+    // biSize not counting the record, and frames stored top-down
+    let buf = bitmap_info_header(40, -(HEIGHT as i32), b"FFV1", record);
+    let header = BitmapInfoHeader::parse_bitmap_info_header(&buf).unwrap();
+    assert!(header.config_record == record);
+    assert_eq!(header.dimensions(), (WIDTH, HEIGHT));
+
+    // FFV1 versions 0 and 1
+    let buf = bitmap_info_header(40, HEIGHT as i32, b"FFV1", &[]);
+    let header = BitmapInfoHeader::parse_bitmap_info_header(&buf).unwrap();
+    assert!(header.config_record.is_empty());
+}
+
+#[test]
+fn test_malformed() {
+    let record = encoder().config_record().to_owned();
+    let size = 40 + record.len() as u32;
+    let parse = |buf: &[u8]| BitmapInfoHeader::parse_bitmap_info_header(buf);
+
+    let buf = bitmap_info_header(size, HEIGHT as i32, b"FFV1", &record);
+    assert!(parse(&buf[..39]).is_err());
+    assert!(parse(&buf[..buf.len() - 1]).is_err());
+
+    let buf = bitmap_info_header(size, HEIGHT as i32, b"H264", &record);
+    assert!(parse(&buf).is_err());
+
+    let buf = bitmap_info_header(12, HEIGHT as i32, b"FFV1", &record);
+    assert!(parse(&buf).is_err());
+
+    let buf = bitmap_info_header(size, 0, b"FFV1", &record);
+    assert!(parse(&buf).is_err());
+}
+
+#[test]
+fn test_decoder_from_vfw() {
+    let encoder = encoder();
+    let luma: Vec<u8> = (0..WIDTH * HEIGHT).map(|i| i as u8).collect();
+    let chroma = vec![128; (WIDTH * HEIGHT / 4) as usize];
+    let packet = encoder.encode_frame(&[&luma, &chroma, &chroma]).unwrap();
+
+    let record = encoder.config_record();
+    let buf =
+        bitmap_info_header(40 + record.len() as u32, 16, b"FFV1", record);
+    let mut decoder = Decoder::from_vfw(&buf).unwrap();
+    let frame = decoder.decode_frame(&packet).unwrap();
+    assert_eq!((frame.width(), frame.height()), (WIDTH, HEIGHT));
+    assert!(frame.plane_u8(0).unwrap().data == &luma[..]);
+
+    assert!(Decoder::from_vfw(&buf[..40]).is_err());
+}